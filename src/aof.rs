@@ -0,0 +1,1108 @@
+use crate::commands;
+use crate::db::RedisValue;
+use crate::rdb::RedisData;
+use crate::rdb_writer::{self, DbSnapshot};
+use crate::redis_config::RedisConfig;
+use crate::{ClientState, RedisCommand};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+// appendfsync: when written commands are forced to disk
+#[derive(Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    // Before the reply goes out, nothing acknowledged is ever lost
+    Always,
+    // Once per second on a background thread, at most a second of writes is lost
+    EverySec,
+    // Whenever the OS flushes its buffers
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(Self::Always),
+            "everysec" => Some(Self::EverySec),
+            "no" => Some(Self::No),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AofFile {
+    name: String,
+    seq: u64,
+}
+
+// <appendfilename>.manifest in appenddirname: the files that make up the AOF, in replay
+// order. Same format as Redis 7, one "file <name> seq <n> type <b|h|i>" line per file:
+// the base file holds a snapshot, the incr files every write made after it.
+#[derive(Clone, Default)]
+pub struct Manifest {
+    base: Option<AofFile>,
+    incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    fn path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.manifest", name))
+    }
+
+    // None when there is no manifest yet
+    fn read(dir: &Path, name: &str) -> Result<Option<Self>, String> {
+        let path = Self::path(dir, name);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        let mut manifest = Self::default();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let invalid = || format!("Invalid AOF manifest file format in {}", path.display());
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            if fields.len() % 2 == 1 {
+                return Err(invalid());
+            }
+            let field = |key: &str| {
+                fields
+                    .chunks(2)
+                    .find(|pair| pair[0] == key)
+                    .map(|pair| pair[1])
+            };
+            let (Some(file_name), Some(seq), Some(file_type)) =
+                (field("file"), field("seq"), field("type"))
+            else {
+                return Err(invalid());
+            };
+            let file = AofFile {
+                name: file_name.to_string(),
+                seq: seq.parse().map_err(|_| invalid())?,
+            };
+            match file_type {
+                "b" if manifest.base.is_none() => manifest.base = Some(file),
+                "i" => manifest.incrs.push(file),
+                // History files are left over from a rewrite and no longer needed
+                "h" => {
+                    let _ = fs::remove_file(dir.join(&file.name));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Some(manifest))
+    }
+
+    fn persist(&self, dir: &Path, name: &str) -> io::Result<()> {
+        let mut content = String::new();
+        if let Some(base) = &self.base {
+            content += &format!("file {} seq {} type b\n", base.name, base.seq);
+        }
+        for incr in &self.incrs {
+            content += &format!("file {} seq {} type i\n", incr.name, incr.seq);
+        }
+        rdb_writer::write_file(&Self::path(dir, name), content.as_bytes())
+    }
+
+    fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+
+    fn next_incr(&self, name: &str) -> AofFile {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        AofFile {
+            name: format!("{}.{}.incr.aof", name, seq),
+            seq,
+        }
+    }
+}
+
+// A BGREWRITEAOF running on its own thread
+struct Rewrite {
+    base: AofFile,
+    // Incr files from this index on were opened after the rewrite started and stay
+    keep_from: usize,
+    // Filled in by the thread once the base file is written
+    result: Arc<Mutex<Option<io::Result<()>>>>,
+}
+
+// The append only file being written: every command that changed the dataset, in the
+// order it ran, as the same RESP a client would send
+pub struct Aof {
+    dir: PathBuf,
+    // appendfilename, the prefix of every file in dir
+    name: String,
+    manifest: Manifest,
+    // Incr file new commands are appended to
+    file: File,
+    file_path: PathBuf,
+    // Set when AOF was turned on at runtime: the incr file is not in the manifest yet and
+    // only joins it once the first rewrite has written a base file next to it
+    waiting_rewrite: bool,
+    pub fsync: AppendFsync,
+    // Database the commands written so far apply to, a SELECT is added when it changes
+    selected_db: Option<usize>,
+    // Encoded commands the file has not taken yet, kept when a write fails so it can be
+    // retried instead of leaving a hole in the file
+    buf: Vec<u8>,
+    pub last_write_error: Option<String>,
+    // Written since the last fsync, for everysec
+    unsynced: bool,
+    last_fsync: Instant,
+    fsync_in_progress: Arc<AtomicBool>,
+    // Replication offset of the last command written, and of the last one an everysec
+    // fsync made durable, for WAITAOF
+    pub reploff: u64,
+    synced_reploff: Arc<AtomicU64>,
+    rewrite: Option<Rewrite>,
+    pub last_rewrite_ok: bool,
+    // Size of all the files, and what it was right after the last rewrite. Their ratio
+    // triggers auto-aof-rewrite-percentage.
+    pub current_size: u64,
+    pub base_size: u64,
+}
+
+impl Aof {
+    // Opens the AOF load() just replayed, appending to its last incr file
+    pub fn open(config: &RedisConfig, manifest: Manifest) -> io::Result<Self> {
+        let dir = config.get_aof_dir();
+        let name = config.get_appendfilename();
+        fs::create_dir_all(&dir)?;
+
+        let mut manifest = manifest;
+        if manifest.incrs.is_empty() {
+            manifest.incrs.push(manifest.next_incr(&name));
+            manifest.persist(&dir, &name)?;
+        }
+        let file_path = dir.join(&manifest.incrs[manifest.incrs.len() - 1].name);
+        let size = manifest
+            .files()
+            .map(|file| fs::metadata(dir.join(&file.name)).map_or(0, |meta| meta.len()))
+            .sum();
+
+        Self::new(config, dir, name, manifest, file_path, false, size)
+    }
+
+    // CONFIG SET appendonly yes: writes go to a temporary incr file until the rewrite the
+    // caller starts has captured the current dataset in a base file
+    pub fn start(config: &RedisConfig) -> Result<Self, String> {
+        let dir = config.get_aof_dir();
+        let name = config.get_appendfilename();
+        fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        let manifest = Manifest::read(&dir, &name)?.unwrap_or_default();
+
+        let file_path = dir.join(format!("temp-{}.incr", name));
+        let _ = fs::remove_file(&file_path);
+        Self::new(config, dir, name, manifest, file_path, true, 0)
+            .map_err(|e| format!("{}: {}", config.get_aof_dir().display(), e))
+    }
+
+    fn new(
+        config: &RedisConfig,
+        dir: PathBuf,
+        name: String,
+        manifest: Manifest,
+        file_path: PathBuf,
+        waiting_rewrite: bool,
+        size: u64,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file_path)?;
+        Ok(Self {
+            dir,
+            name,
+            manifest,
+            file,
+            file_path,
+            waiting_rewrite,
+            fsync: config.get_appendfsync(),
+            selected_db: None,
+            buf: Vec::new(),
+            last_write_error: None,
+            unsynced: false,
+            last_fsync: Instant::now(),
+            fsync_in_progress: Arc::new(AtomicBool::new(false)),
+            reploff: 0,
+            synced_reploff: Arc::new(AtomicU64::new(0)),
+            rewrite: None,
+            last_rewrite_ok: true,
+            current_size: size,
+            base_size: size,
+        })
+    }
+
+    pub fn has_base(&self) -> bool {
+        self.manifest.base.is_some()
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite.is_some()
+    }
+
+    // Replication offset up to which the file is durable. With appendfsync no that is
+    // the kernel's business, whatever was written counts.
+    pub fn fsynced_reploff(&self) -> u64 {
+        let synced = match self.fsync {
+            AppendFsync::Always | AppendFsync::No => true,
+            AppendFsync::EverySec => {
+                !self.unsynced && !self.fsync_in_progress.load(Ordering::Acquire)
+            }
+        };
+        if synced && self.buf.is_empty() {
+            self.reploff
+        } else {
+            self.synced_reploff.load(Ordering::Acquire)
+        }
+    }
+
+    // Appends the commands one call propagated
+    pub fn feed(&mut self, commands: &[(usize, Vec<String>)]) {
+        let encoded = encode_propagated(commands, &mut self.selected_db);
+        self.buf.extend_from_slice(&encoded);
+        self.write_buffer();
+    }
+
+    // Retries a failed write, starts the everysec fsync and completes a finished rewrite.
+    // Called from the server cron. An error means the AOF could not be turned on and has
+    // to be dropped.
+    pub fn cron(&mut self) -> Result<(), String> {
+        if !self.buf.is_empty() {
+            self.write_buffer();
+        }
+
+        if self.fsync == AppendFsync::EverySec
+            && self.unsynced
+            && self.last_fsync.elapsed() >= Duration::from_secs(1)
+            && !self.fsync_in_progress.load(Ordering::Acquire)
+        {
+            match self.file.try_clone() {
+                Ok(file) => {
+                    self.unsynced = false;
+                    self.last_fsync = Instant::now();
+                    self.fsync_in_progress.store(true, Ordering::Release);
+                    let in_progress = Arc::clone(&self.fsync_in_progress);
+                    let synced_reploff = Arc::clone(&self.synced_reploff);
+                    let reploff = self.reploff;
+                    let path = self.file_path.clone();
+                    thread::spawn(move || {
+                        match file.sync_data() {
+                            Ok(()) => synced_reploff.store(reploff, Ordering::Release),
+                            Err(e) => eprintln!("Failed to fsync {}: {}", path.display(), e),
+                        }
+                        in_progress.store(false, Ordering::Release);
+                    });
+                }
+                Err(e) => eprintln!("Failed to fsync {}: {}", self.file_path.display(), e),
+            }
+        }
+
+        let finished = self
+            .rewrite
+            .as_ref()
+            .and_then(|rewrite| rewrite.result.lock().unwrap().take());
+        match (finished, self.rewrite.take()) {
+            (Some(result), Some(rewrite)) => self.finish_rewrite(rewrite, result),
+            (_, rewrite) => {
+                self.rewrite = rewrite;
+                Ok(())
+            }
+        }
+    }
+
+    // BGREWRITEAOF: new writes go to a fresh incr file from now on, while a thread writes
+    // the snapshot as the next base file. The manifest only switches to the new base once
+    // it is complete, until then the old base and incr files still describe the dataset.
+    pub fn start_rewrite(
+        &mut self,
+        databases: Vec<DbSnapshot>,
+        rdb_preamble: bool,
+    ) -> Result<(), String> {
+        if self.rewrite.is_some() {
+            return Err(
+                "ERR Background append only file rewriting already in progress".to_string(),
+            );
+        }
+
+        let keep_from = if self.waiting_rewrite {
+            self.manifest.incrs.len()
+        } else {
+            self.open_next_incr()
+                .map_err(|e| format!("ERR Can't open a new incr AOF file: {}", e))?;
+            self.manifest.incrs.len() - 1
+        };
+
+        // Only strings have a command to recreate them with
+        let content = match rdb_preamble {
+            true => None,
+            false => encode_commands(&databases),
+        };
+        let seq = self.manifest.base.as_ref().map_or(1, |base| base.seq + 1);
+        let extension = if content.is_some() { "aof" } else { "rdb" };
+        let base = AofFile {
+            name: format!("{}.{}.base.{}", self.name, seq, extension),
+            seq,
+        };
+
+        let result = Arc::new(Mutex::new(None));
+        let thread_result = Arc::clone(&result);
+        let path = self.dir.join(&base.name);
+        thread::spawn(move || {
+            let content = content.unwrap_or_else(|| rdb_writer::encode(&databases));
+            let written = rdb_writer::write_file(&path, &content);
+            *thread_result.lock().unwrap() = Some(written);
+        });
+
+        self.rewrite = Some(Rewrite {
+            base,
+            keep_from,
+            result,
+        });
+        Ok(())
+    }
+
+    // Whether auto-aof-rewrite-percentage / auto-aof-rewrite-min-size call for a rewrite,
+    // along with the growth since the last one
+    pub fn rewrite_due(&self, percentage: u64, min_size: u64) -> Option<u64> {
+        if self.rewrite.is_some() || percentage == 0 || self.current_size <= min_size {
+            return None;
+        }
+        let growth = (self.current_size * 100 / self.base_size.max(1)).saturating_sub(100);
+        (growth >= percentage).then_some(growth)
+    }
+
+    fn open_next_incr(&mut self) -> io::Result<()> {
+        self.write_buffer();
+        if let Some(e) = &self.last_write_error {
+            return Err(io::Error::other(e.clone()));
+        }
+
+        let incr = self.manifest.next_incr(&self.name);
+        let path = self.dir.join(&incr.name);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.manifest.incrs.push(incr);
+        if let Err(e) = self.manifest.persist(&self.dir, &self.name) {
+            self.manifest.incrs.pop();
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+
+        self.file = file;
+        self.file_path = path;
+        // The new file is replayed from a fresh client, it has to select its own db
+        self.selected_db = None;
+        Ok(())
+    }
+
+    fn finish_rewrite(&mut self, rewrite: Rewrite, result: io::Result<()>) -> Result<(), String> {
+        let base_path = self.dir.join(&rewrite.base.name);
+        let failed = |aof: &mut Self, e: io::Error| {
+            eprintln!("Background AOF rewrite failed: {}", e);
+            let _ = fs::remove_file(&base_path);
+            aof.last_rewrite_ok = false;
+            if aof.waiting_rewrite {
+                let _ = fs::remove_file(&aof.file_path);
+                return Err("the first AOF rewrite failed, AOF is turned off".to_string());
+            }
+            Ok(())
+        };
+        if let Err(e) = result {
+            return failed(self, e);
+        }
+
+        let mut manifest = self.manifest.clone();
+        let mut incrs = manifest.incrs.split_off(rewrite.keep_from);
+        let mut file_path = self.file_path.clone();
+        if self.waiting_rewrite {
+            let incr = self.manifest.next_incr(&self.name);
+            file_path = self.dir.join(&incr.name);
+            if let Err(e) = fs::rename(&self.file_path, &file_path) {
+                return failed(self, e);
+            }
+            incrs.push(incr);
+        }
+        let history = manifest.files().cloned().collect::<Vec<AofFile>>();
+        let new_manifest = Manifest {
+            base: Some(rewrite.base),
+            incrs,
+        };
+        if let Err(e) = new_manifest.persist(&self.dir, &self.name) {
+            if self.waiting_rewrite {
+                let _ = fs::rename(&file_path, &self.file_path);
+            }
+            return failed(self, e);
+        }
+
+        for file in history {
+            let _ = fs::remove_file(self.dir.join(&file.name));
+        }
+        self.manifest = new_manifest;
+        self.file_path = file_path;
+        self.waiting_rewrite = false;
+        self.last_rewrite_ok = true;
+        self.current_size = self
+            .manifest
+            .files()
+            .map(|file| fs::metadata(self.dir.join(&file.name)).map_or(0, |meta| meta.len()))
+            .sum();
+        self.base_size = self.current_size;
+        println!("Background AOF rewrite finished successfully");
+        Ok(())
+    }
+
+    fn write_buffer(&mut self) {
+        while !self.buf.is_empty() {
+            match self.file.write(&self.buf) {
+                Ok(0) => {
+                    self.write_failed(io::Error::from(io::ErrorKind::WriteZero));
+                    return;
+                }
+                Ok(written) => {
+                    self.buf.drain(..written);
+                    self.current_size += written as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.write_failed(e);
+                    return;
+                }
+            }
+        }
+        if self.last_write_error.take().is_some() {
+            println!("AOF write error looks solved, accepting writes again");
+        }
+        self.unsynced = true;
+
+        if self.fsync == AppendFsync::Always {
+            // The reply would claim a durability the file does not have
+            if let Err(e) = self.file.sync_data() {
+                eprintln!(
+                    "Can't persist AOF for fsync error when the AOF fsync policy is 'always': {}. Exiting...",
+                    e
+                );
+                process::exit(1);
+            }
+            self.unsynced = false;
+            self.synced_reploff.store(self.reploff, Ordering::Release);
+        }
+    }
+
+    fn write_failed(&mut self, e: io::Error) {
+        if self.last_write_error.is_none() {
+            eprintln!(
+                "Error writing to the AOF file {}: {}",
+                self.file_path.display(),
+                e
+            );
+        }
+        self.last_write_error = Some(e.to_string());
+    }
+}
+
+// Commands one call propagated, as the AOF and the replication stream carry them: a
+// SELECT whenever the database changes, and MULTI / EXEC around several of them (EXEC, or
+// a write that also expired a key) so they are applied all or none
+pub fn encode_propagated(
+    commands: &[(usize, Vec<String>)],
+    selected_db: &mut Option<usize>,
+) -> Vec<u8> {
+    let mut out = String::new();
+    let mut select = |out: &mut String, index: usize| {
+        if *selected_db != Some(index) {
+            *out += &RedisCommand::encode(&["SELECT".to_string(), index.to_string()]);
+            *selected_db = Some(index);
+        }
+    };
+
+    let wrap = commands.len() > 1;
+    if let (true, Some((first_db, _))) = (wrap, commands.first()) {
+        select(&mut out, *first_db);
+        out += &RedisCommand::encode(&["MULTI".to_string()]);
+    }
+    for (index, argv) in commands {
+        select(&mut out, *index);
+        out += &RedisCommand::encode(argv);
+    }
+    if wrap {
+        out += &RedisCommand::encode(&["EXEC".to_string()]);
+    }
+    out.into_bytes()
+}
+
+// Base file in AOF format: a SELECT per database and a SET per key. None when a value
+// has no command that could recreate it, the base is then written as RDB.
+fn encode_commands(databases: &[DbSnapshot]) -> Option<Vec<u8>> {
+    let mut out = String::new();
+    for (index, db) in databases.iter().enumerate() {
+        if db.data.is_empty() {
+            continue;
+        }
+        out += &RedisCommand::encode(&["SELECT".to_string(), index.to_string()]);
+        for (key, value) in &db.data {
+            let RedisValue::String(string) = value else {
+                return None;
+            };
+            let mut argv = vec!["SET".to_string(), key.clone(), string.clone()];
+            if let Some(time) = db.expiry.get(key) {
+                let ms = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+                argv.extend(["PXAT".to_string(), ms.as_millis().to_string()]);
+            }
+            out += &RedisCommand::encode(&argv);
+        }
+    }
+    Some(out.into_bytes())
+}
+
+// Replays the base file and every incr file the manifest lists. An AOF from before the
+// multi part format, a single appendfilename in dir, is first moved into appenddirname
+// as the base file. Returns the manifest for Aof::open.
+pub fn load(db: &mut RedisData, config: &mut RedisConfig) -> Result<(Manifest, String), String> {
+    let dir = config.get_aof_dir();
+    let name = config.get_appendfilename();
+
+    let manifest = match Manifest::read(&dir, &name)? {
+        Some(manifest) => manifest,
+        None => {
+            let legacy = config.get_aof_path();
+            if !legacy.exists() {
+                return Ok((
+                    Manifest::default(),
+                    format!("No AOF manifest in {}", dir.display()),
+                ));
+            }
+            println!(
+                "Upgrading {} to the multi part AOF format",
+                legacy.display()
+            );
+            let manifest = Manifest {
+                base: Some(AofFile {
+                    name: name.clone(),
+                    seq: 1,
+                }),
+                incrs: Vec::new(),
+            };
+            fs::create_dir_all(&dir)
+                .and_then(|_| fs::rename(&legacy, dir.join(&name)))
+                .and_then(|_| manifest.persist(&dir, &name))
+                .map_err(|e| format!("Failed to upgrade {}: {}", legacy.display(), e))?;
+            manifest
+        }
+    };
+
+    let (mut client, _receiver) = ClientState::new();
+    // Like the master link on a replica, replay is not subject to READONLY, MASTERDOWN or
+    // MISCONF: the commands were already accepted once
+    client.is_master = true;
+    let files = manifest.files().count();
+    let mut replayed = 0;
+    for (i, file) in manifest.files().enumerate() {
+        replayed += replay_file(
+            db,
+            config,
+            &mut client,
+            &dir.join(&file.name),
+            i + 1 == files,
+        )?;
+    }
+
+    // The files already hold everything that was just replayed
+    db.dirty = 0;
+    let keys = db.databases.iter().map(|db| db.data.len()).sum::<usize>();
+    Ok((
+        manifest,
+        format!(
+            "Loaded {} keys from {} AOF files in {} ({} commands replayed)",
+            keys,
+            files,
+            dir.display(),
+            replayed
+        ),
+    ))
+}
+
+// Loads one file of the AOF: a base in RDB format, or commands replayed through the
+// regular command path. The last file may have been cut short by a crash in the middle
+// of a write, it is then truncated back to its last complete command when
+// aof-load-truncated is on, an incomplete MULTI going with it. Anything else that is not
+// valid RESP is refused, the file was damaged some other way.
+fn replay_file(
+    db: &mut RedisData,
+    config: &mut RedisConfig,
+    client: &mut ClientState,
+    path: &Path,
+    last: bool,
+) -> Result<usize, String> {
+    let content = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if content.starts_with(b"REDIS") {
+        db.load_rdb(&content)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Ok(0);
+    }
+
+    let mut cursor = 0;
+    // End of the last command applied, outside of any MULTI
+    let mut valid_len = 0;
+    let mut replayed = 0;
+
+    loop {
+        let (command, used) = match RedisCommand::parser_receive(&content[cursor..]) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => break,
+            Err(e) => {
+                return Err(format!(
+                    "Bad file format reading the append only file {} at byte {}: {}",
+                    path.display(),
+                    cursor,
+                    e
+                ))
+            }
+        };
+        cursor += used;
+        if command.str_cmd.is_empty() {
+            continue;
+        }
+
+        let spec = match commands::validate(&command) {
+            Ok(spec) => spec,
+            Err(e) => {
+                return Err(format!(
+                    "{} reading the append only file {} at byte {}",
+                    e.to_string().trim_start_matches('-').trim_end(),
+                    path.display(),
+                    cursor - used
+                ))
+            }
+        };
+
+        match spec.name {
+            "multi" => client.multi_queue = Some(Vec::new()),
+            "exec" => {
+                for queued in client.multi_queue.take().unwrap_or_default() {
+                    replay_command(db, config, client, &queued, path, cursor - used)?;
+                    replayed += 1;
+                }
+            }
+            _ => match client.multi_queue.as_mut() {
+                Some(queue) => queue.push(command),
+                None => {
+                    replay_command(db, config, client, &command, path, cursor - used)?;
+                    replayed += 1;
+                }
+            },
+        }
+        db.flush_propagation();
+
+        if client.multi_queue.is_none() {
+            valid_len = cursor;
+        }
+    }
+
+    if valid_len < content.len() {
+        if !last || !config.get_aof_load_truncated() {
+            return Err(format!(
+                "Unexpected end of file reading the append only file {} at byte {}. You can: 1) Make a backup of your AOF file, then use ./redis-check-aof --fix <filename>. 2) Alternatively you can set the 'aof-load-truncated' configuration option to yes and restart the server.",
+                path.display(),
+                valid_len
+            ));
+        }
+        eprintln!(
+            "!!! Warning: short read while loading the AOF file {}!!! Truncating it to {} bytes, dropping the {} bytes after the last complete command",
+            path.display(),
+            valid_len,
+            content.len() - valid_len
+        );
+        client.multi_queue = None;
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid_len as u64))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+
+    Ok(replayed)
+}
+
+// A command that fails on replay would leave the dataset different from the one that
+// wrote the file, so loading stops instead of going on without it
+fn replay_command(
+    db: &mut RedisData,
+    config: &mut RedisConfig,
+    client: &mut ClientState,
+    command: &RedisCommand,
+    path: &Path,
+    offset: usize,
+) -> Result<(), String> {
+    let response = commands::execute_command(db, config, client, command);
+    match response.strip_prefix('-') {
+        Some(e) => Err(format!(
+            "Error replaying '{}' from the append only file {} at byte {}: {}",
+            command.str_cmd[0],
+            path.display(),
+            offset,
+            e.trim_end()
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::PubSub;
+    use crate::rdb::ReplicationRole;
+
+    fn new_data() -> RedisData {
+        RedisData::init_db(
+            16,
+            Arc::new(Mutex::new(PubSub::new())),
+            ReplicationRole::Master,
+            None,
+            None,
+        )
+    }
+
+    // Removed again when the test is done with it
+    struct TestDir(PathBuf);
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl std::ops::Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    // An empty dir of its own for every test, with a config pointing at it
+    fn setup(test: &str, args: &[&str]) -> (TestDir, RedisConfig) {
+        let dir = TestDir(std::env::temp_dir().join(format!("aof-{}-{}", process::id(), test)));
+        let _ = fs::remove_dir_all(&*dir);
+        fs::create_dir_all(&*dir).unwrap();
+        let mut argv = vec!["redis-server", "--dir", dir.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let config = RedisConfig::parse_argument(argv.iter().map(|arg| arg.to_string()).collect());
+        (dir, config)
+    }
+
+    fn commands(commands: &[&[&str]]) -> Vec<u8> {
+        commands
+            .iter()
+            .map(|argv| {
+                RedisCommand::encode(
+                    &argv
+                        .iter()
+                        .map(|arg| arg.to_string())
+                        .collect::<Vec<String>>(),
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    // Writes the files and a manifest listing them, the first one as the base
+    fn write_aof(config: &RedisConfig, files: &[Vec<u8>]) -> Vec<PathBuf> {
+        let dir = config.get_aof_dir();
+        let name = config.get_appendfilename();
+        fs::create_dir_all(&dir).unwrap();
+        let mut manifest = Manifest::default();
+        let mut paths = vec![];
+        for (i, content) in files.iter().enumerate() {
+            let file = match i {
+                0 => AofFile {
+                    name: format!("{}.1.base.aof", name),
+                    seq: 1,
+                },
+                _ => manifest.next_incr(&name),
+            };
+            fs::write(dir.join(&file.name), content).unwrap();
+            paths.push(dir.join(&file.name));
+            match i {
+                0 => manifest.base = Some(file),
+                _ => manifest.incrs.push(file),
+            }
+        }
+        manifest.persist(&dir, &name).unwrap();
+        paths
+    }
+
+    fn load_error(config: &mut RedisConfig) -> String {
+        load(&mut new_data(), config).err().expect("the AOF loaded")
+    }
+
+    fn string<'a>(data: &'a RedisData, key: &str) -> Option<&'a str> {
+        match data.databases[0].data.get(key) {
+            Some(RedisValue::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let (dir, _) = setup("manifest", &[]);
+        let manifest = Manifest {
+            base: Some(AofFile {
+                name: "appendonly.aof.3.base.rdb".to_string(),
+                seq: 3,
+            }),
+            incrs: vec![
+                AofFile {
+                    name: "appendonly.aof.5.incr.aof".to_string(),
+                    seq: 5,
+                },
+                AofFile {
+                    name: "appendonly.aof.6.incr.aof".to_string(),
+                    seq: 6,
+                },
+            ],
+        };
+        manifest.persist(&dir, "appendonly.aof").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("appendonly.aof.manifest")).unwrap(),
+            "file appendonly.aof.3.base.rdb seq 3 type b\n\
+             file appendonly.aof.5.incr.aof seq 5 type i\n\
+             file appendonly.aof.6.incr.aof seq 6 type i\n"
+        );
+
+        let read = Manifest::read(&dir, "appendonly.aof").unwrap().unwrap();
+        let files = read
+            .files()
+            .map(|file| (file.name.as_str(), file.seq))
+            .collect::<Vec<(&str, u64)>>();
+        assert_eq!(
+            files,
+            [
+                ("appendonly.aof.3.base.rdb", 3),
+                ("appendonly.aof.5.incr.aof", 5),
+                ("appendonly.aof.6.incr.aof", 6)
+            ]
+        );
+        assert_eq!(
+            read.next_incr("appendonly.aof").name,
+            "appendonly.aof.7.incr.aof"
+        );
+        assert_eq!(Manifest::default().next_incr("x").name, "x.1.incr.aof");
+        assert!(Manifest::read(&dir, "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn manifest_parsing() {
+        let (dir, _) = setup("manifest-parsing", &[]);
+        let read = |content: &str| {
+            fs::write(dir.join("a.manifest"), content).unwrap();
+            Manifest::read(&dir, "a")
+        };
+
+        // Fields in any order, blank lines skipped, history files deleted
+        fs::write(dir.join("a.1.base.aof"), "").unwrap();
+        let manifest = read(
+            "type i seq 2 file a.2.incr.aof\n\n\
+             file a.1.base.aof seq 1 type h\n\
+             file a.2.base.rdb seq 2 type b\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(manifest.base.unwrap().name, "a.2.base.rdb");
+        assert_eq!(manifest.incrs.len(), 1);
+        assert_eq!(manifest.incrs[0].seq, 2);
+        assert!(!dir.join("a.1.base.aof").exists());
+
+        for bad in [
+            "file a.1.incr.aof seq 1 type\n",
+            "file a.1.incr.aof seq 1\n",
+            "file a.1.incr.aof seq one type i\n",
+            "file a.1.incr.aof seq -1 type i\n",
+            "file a.1.incr.aof seq 1 type x\n",
+            "file a.1.base.aof seq 1 type b\nfile a.2.base.aof seq 2 type b\n",
+        ] {
+            let e = read(bad).err().expect(bad);
+            assert!(e.starts_with("Invalid AOF manifest file format"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn replays_the_base_and_every_incr_file() {
+        let (_dir, mut config) = setup("replay", &[]);
+        write_aof(
+            &config,
+            &[
+                commands(&[&["SET", "a", "1"], &["SET", "b", "base"]]),
+                commands(&[
+                    &["SET", "a", "2"],
+                    &["SELECT", "1"],
+                    &["SET", "c", "db1"],
+                    &["DEL", "c"],
+                    &["SET", "d", "db1"],
+                ]),
+                commands(&[
+                    &["SELECT", "0"],
+                    &["MULTI"],
+                    &["SET", "a", "3"],
+                    &["DEL", "b"],
+                    &["EXEC"],
+                ]),
+            ],
+        );
+
+        let mut data = new_data();
+        let (manifest, message) = load(&mut data, &mut config).unwrap();
+        assert_eq!(manifest.files().count(), 3);
+        assert!(message.contains("(10 commands replayed)"), "{}", message);
+        assert_eq!(string(&data, "a"), Some("3"));
+        assert_eq!(string(&data, "b"), None);
+        assert!(!data.databases[1].data.contains_key("c"));
+        assert!(data.databases[1].data.contains_key("d"));
+        assert_eq!(data.dirty, 0);
+    }
+
+    #[test]
+    fn a_base_can_be_an_rdb_file() {
+        let (_dir, mut config) = setup("rdb-base", &[]);
+        let mut snapshot = (0..16)
+            .map(|_| DbSnapshot {
+                data: Default::default(),
+                expiry: Default::default(),
+            })
+            .collect::<Vec<DbSnapshot>>();
+        snapshot[0]
+            .data
+            .insert("a".to_string(), RedisValue::List(["x".to_string()].into()));
+        write_aof(
+            &config,
+            &[
+                rdb_writer::encode(&snapshot),
+                commands(&[&["SET", "b", "y"]]),
+            ],
+        );
+
+        let mut data = new_data();
+        load(&mut data, &mut config).unwrap();
+        assert_eq!(
+            data.databases[0].data["a"],
+            RedisValue::List(["x".to_string()].into())
+        );
+        assert_eq!(string(&data, "b"), Some("y"));
+    }
+
+    #[test]
+    fn a_truncated_tail_is_dropped_with_aof_load_truncated() {
+        let (_dir, mut config) = setup("truncated-yes", &[]);
+        let complete = commands(&[&["SET", "a", "1"]]);
+        // A whole MULTI that never got its EXEC goes with the cut off command
+        let mut incr = complete.clone();
+        incr.extend(commands(&[&["MULTI"], &["SET", "b", "2"]]));
+        incr.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nc");
+        let paths = write_aof(&config, &[commands(&[]), incr]);
+
+        let mut data = new_data();
+        load(&mut data, &mut config).unwrap();
+        assert_eq!(string(&data, "a"), Some("1"));
+        assert_eq!(string(&data, "b"), None);
+        assert_eq!(fs::read(&paths[1]).unwrap(), complete);
+
+        // The file is whole again, the next load has nothing to cut
+        let mut data = new_data();
+        load(&mut data, &mut config).unwrap();
+        assert_eq!(string(&data, "a"), Some("1"));
+    }
+
+    #[test]
+    fn a_truncated_tail_fails_without_aof_load_truncated() {
+        let (_dir, mut config) = setup("truncated-no", &["--aof-load-truncated", "no"]);
+        let mut incr = commands(&[&["SET", "a", "1"]]);
+        let valid_len = incr.len();
+        incr.extend_from_slice(b"*2\r\n$3\r\nGET");
+        let paths = write_aof(&config, &[commands(&[]), incr.clone()]);
+
+        let e = load_error(&mut config);
+        assert!(e.starts_with("Unexpected end of file"), "{}", e);
+        assert!(e.contains(&format!("at byte {}.", valid_len)), "{}", e);
+        // Left as it was for redis-check-aof
+        assert_eq!(fs::read(&paths[1]).unwrap(), incr);
+    }
+
+    #[test]
+    fn only_the_last_file_may_be_truncated() {
+        let (_dir, mut config) = setup("truncated-middle", &[]);
+        let mut base = commands(&[&["SET", "a", "1"]]);
+        base.extend_from_slice(b"*2\r\n$3\r\nGET");
+        write_aof(&config, &[base, commands(&[&["SET", "b", "2"]])]);
+
+        let e = load_error(&mut config);
+        assert!(e.starts_with("Unexpected end of file"), "{}", e);
+        assert!(e.contains(".1.base.aof at byte 27."), "{}", e);
+    }
+
+    #[test]
+    fn stops_at_the_first_bad_command() {
+        let (_dir, mut config) = setup("bad-command", &[]);
+        let set = commands(&[&["SET", "a", "x"]]);
+        let mut incr = set.clone();
+        incr.extend(commands(&[&["SELECT", "99"], &["SET", "b", "never"]]));
+        write_aof(&config, &[commands(&[]), incr]);
+
+        let mut data = new_data();
+        let e = load(&mut data, &mut config).err().unwrap();
+        assert!(e.starts_with("Error replaying 'SELECT'"), "{}", e);
+        assert!(
+            e.contains(&format!(".1.incr.aof at byte {}: ERR", set.len())),
+            "{}",
+            e
+        );
+        assert!(!data.databases[0].data.contains_key("b"));
+
+        // A command inside a MULTI is reported at its EXEC
+        let (_dir, mut config) = setup("bad-command-multi", &[]);
+        let mut incr = commands(&[&["SET", "a", "x"], &["MULTI"], &["SELECT", "99"]]);
+        let exec = incr.len();
+        incr.extend(commands(&[&["EXEC"]]));
+        write_aof(&config, &[commands(&[]), incr]);
+        let e = load_error(&mut config);
+        assert!(e.contains(&format!("at byte {}: ERR", exec)), "{}", e);
+
+        let (_dir, mut config) = setup("unknown-command", &[]);
+        write_aof(&config, &[commands(&[&["NOSUCHCOMMAND", "a"]])]);
+        let e = load_error(&mut config);
+        assert!(e.contains("reading the append only file"), "{}", e);
+        assert!(e.ends_with(".1.base.aof at byte 0"), "{}", e);
+
+        let (_dir, mut config) = setup("bad-format", &[]);
+        let mut base = commands(&[&["SET", "a", "1"]]);
+        base.extend_from_slice(b"*1\r\n+PING\r\n");
+        write_aof(&config, &[base]);
+        let e = load_error(&mut config);
+        assert!(e.starts_with("Bad file format"), "{}", e);
+        assert!(e.contains("at byte 27:"), "{}", e);
+    }
+
+    #[test]
+    fn upgrades_a_single_file_aof() {
+        let (dir, mut config) = setup("legacy", &[]);
+        let mut data = new_data();
+        let (manifest, message) = load(&mut data, &mut config).unwrap();
+        assert_eq!(manifest.files().count(), 0);
+        assert!(message.starts_with("No AOF manifest"), "{}", message);
+
+        fs::write(dir.join("appendonly.aof"), commands(&[&["SET", "a", "1"]])).unwrap();
+        let (manifest, _) = load(&mut data, &mut config).unwrap();
+        assert_eq!(string(&data, "a"), Some("1"));
+        assert_eq!(manifest.base.unwrap().name, "appendonly.aof");
+        assert!(!dir.join("appendonly.aof").exists());
+        assert!(dir.join("appendonlydir/appendonly.aof").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("appendonlydir/appendonly.aof.manifest")).unwrap(),
+            "file appendonly.aof seq 1 type b\n"
+        );
+    }
+}
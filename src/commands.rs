@@ -0,0 +1,1037 @@
+use crate::notify;
+use crate::pubsub::PubSub;
+use crate::rdb::{RedisData, RedisResponse, ReplicationRole};
+use crate::rdb_writer::REDIS_VERSION;
+use crate::redis_config::RedisConfig;
+use crate::replication;
+use crate::scan::{self, ScanOptions};
+use crate::tracking::TrackingOptions;
+use crate::{ClientState, RedisCommand};
+use std::collections::HashMap;
+use std::time::UNIX_EPOCH;
+
+// Allowed while a RESP2 connection is in subscribed mode
+pub const PUBSUB_CONTEXT: u32 = 1 << 0;
+// Rejected inside MULTI, the transaction is then aborted
+pub const NO_MULTI: u32 = 1 << 1;
+// Only reads its key (always the first argument), so CLIENT TRACKING remembers it
+pub const READONLY: u32 = 1 << 2;
+// May modify the keyspace, refused while writes are denied
+pub const WRITE: u32 = 1 << 3;
+// Served by a replica whose master link is down even with replica-serve-stale-data no
+pub const STALE: u32 = 1 << 4;
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Same convention as Redis: N means exactly N arguments (command name included),
+    // -N means at least N
+    pub arity: i32,
+    pub flags: u32,
+}
+
+impl CommandSpec {
+    const fn new(name: &'static str, arity: i32, flags: u32) -> Self {
+        Self { name, arity, flags }
+    }
+}
+
+const COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec::new("echo", 2, 0),
+    CommandSpec::new("ping", -1, PUBSUB_CONTEXT),
+    CommandSpec::new("set", -3, WRITE),
+    CommandSpec::new("get", 2, READONLY),
+    CommandSpec::new("del", -2, WRITE),
+    CommandSpec::new("config", -2, STALE),
+    CommandSpec::new("keys", 2, 0),
+    CommandSpec::new("scan", -2, 0),
+    CommandSpec::new("sscan", -3, READONLY),
+    CommandSpec::new("hscan", -3, READONLY),
+    CommandSpec::new("zscan", -3, READONLY),
+    CommandSpec::new("select", 2, STALE),
+    CommandSpec::new("swapdb", 3, WRITE),
+    CommandSpec::new("flushdb", -1, WRITE),
+    CommandSpec::new("flushall", -1, WRITE),
+    CommandSpec::new("info", -1, STALE),
+    CommandSpec::new("multi", 1, STALE),
+    CommandSpec::new("exec", 1, STALE),
+    CommandSpec::new("discard", 1, STALE),
+    CommandSpec::new("watch", -2, STALE),
+    CommandSpec::new("unwatch", 1, STALE),
+    CommandSpec::new("subscribe", -2, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("unsubscribe", -1, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("psubscribe", -2, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("punsubscribe", -1, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("ssubscribe", -2, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("sunsubscribe", -1, PUBSUB_CONTEXT | NO_MULTI | STALE),
+    CommandSpec::new("publish", 3, STALE),
+    CommandSpec::new("spublish", 3, STALE),
+    CommandSpec::new("pubsub", -2, STALE),
+    CommandSpec::new("save", 1, 0),
+    CommandSpec::new("bgsave", 1, 0),
+    CommandSpec::new("lastsave", 1, STALE),
+    CommandSpec::new("bgrewriteaof", 1, 0),
+    CommandSpec::new("hello", -1, NO_MULTI | STALE),
+    CommandSpec::new("client", -2, STALE),
+    CommandSpec::new("replconf", -1, STALE),
+    CommandSpec::new("psync", 3, NO_MULTI | STALE),
+    CommandSpec::new("wait", 3, 0),
+    CommandSpec::new("replicaof", 3, NO_MULTI | STALE),
+    CommandSpec::new("slaveof", 3, NO_MULTI | STALE),
+    CommandSpec::new("waitaof", 4, 0),
+    CommandSpec::new("quit", -1, PUBSUB_CONTEXT | STALE),
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+// Checks that a command exists and has a valid number of arguments. These are the errors
+// MULTI reports at queue time, which later make EXEC abort the whole transaction.
+pub fn validate(command: &RedisCommand) -> Result<&'static CommandSpec, RedisResponse> {
+    let name = &command.str_cmd[0];
+    let spec = match lookup(name) {
+        Some(spec) => spec,
+        None => {
+            let args = command.str_cmd[1..]
+                .iter()
+                .map(|arg| format!("'{}' ", arg))
+                .collect::<String>();
+            return Err(RedisResponse::Error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                name, args
+            )));
+        }
+    };
+
+    let argc = command.str_cmd.len() as i32;
+    if (spec.arity > 0 && argc != spec.arity) || (spec.arity < 0 && argc < -spec.arity) {
+        return Err(wrong_arity(spec.name));
+    }
+
+    Ok(spec)
+}
+
+pub fn wrong_arity(name: &str) -> RedisResponse {
+    RedisResponse::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+// Runs one already validated command against the keyspace and returns the encoded reply.
+// Callers hold the RedisData write lock, so a batch of calls (EXEC) runs atomically.
+pub fn execute_command(
+    db: &mut RedisData,
+    config: &mut RedisConfig,
+    client: &mut ClientState,
+    command: &RedisCommand,
+) -> String {
+    let spec = lookup(&command.str_cmd[0]);
+    if let (ReplicationRole::Slave, false) = (db.get_role(), client.is_master) {
+        if !db.replication.master_link_up
+            && !config.get_replica_serve_stale_data()
+            && spec.is_some_and(|spec| spec.flags & STALE == 0)
+        {
+            return RedisResponse::Error(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                    .to_string(),
+            )
+            .to_string();
+        }
+        if config.get_replica_read_only() && spec.is_some_and(|spec| spec.flags & WRITE != 0) {
+            return RedisResponse::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            )
+            .to_string();
+        }
+    }
+
+    let may_write = spec.is_some_and(|spec| spec.flags & WRITE != 0 || spec.name == "ping");
+    if may_write && !client.is_master {
+        if let Some(e) = db.writes_denied(
+            config.get_stop_writes_on_bgsave_error(),
+            !config.get_save_points().is_empty(),
+        ) {
+            return e.to_string();
+        }
+    }
+
+    db.current_client = Some(client.id);
+    let response = run_command(db, config, client, command);
+    db.current_client = None;
+
+    if spec.is_some_and(|spec| spec.flags & READONLY != 0) {
+        track_read_key(db, client, &command.str_cmd[1]);
+    }
+    response
+}
+
+// Default mode tracking: remember that the client may now cache this key. OPTIN clients
+// only want the reads right after CLIENT CACHING YES, OPTOUT clients all but the ones
+// after CLIENT CACHING NO.
+fn track_read_key(db: &mut RedisData, client: &ClientState, key: &str) {
+    let track = match db.tracking.options(client.id) {
+        Some(options) if options.bcast => false,
+        Some(options) if options.optin => client.caching == Some(true),
+        Some(options) if options.optout => client.caching != Some(false),
+        Some(_) => true,
+        None => false,
+    };
+    if track {
+        db.tracking.remember_keys(client.id, &[key.to_string()]);
+    }
+}
+
+fn run_command(
+    db: &mut RedisData,
+    config: &mut RedisConfig,
+    client: &mut ClientState,
+    command: &RedisCommand,
+) -> String {
+    let selected_db = client.selected_db;
+
+    match command.str_cmd[0].to_lowercase().as_str() {
+        "echo" => command.format_response_code(None),
+
+        // In RESP2 subscribed mode PING answers with a pong message instead of a status reply
+        "ping" if !client.resp3 && client.subscription_count() > 0 => {
+            let message = command.str_cmd.get(1).cloned().unwrap_or_default();
+            format!("*2\r\n$4\r\npong\r\n${}\r\n{}\r\n", message.len(), message)
+        }
+
+        "ping" => match command.str_cmd.get(1) {
+            Some(message) => command.format_response_code(Some(message.clone())),
+            None => "+PONG\r\n".to_string(),
+        },
+
+        "set" => {
+            let key = &command.str_cmd[1];
+            db.expire_if_needed(selected_db, key);
+            let is_new = !db.db(selected_db).data.contains_key(key);
+            let response = db.db_mut(selected_db).set_value(command);
+            if let RedisResponse::Error(_) = response {
+                return response.to_string();
+            }
+
+            db.signal_modified_key(selected_db, key);
+            if is_new {
+                db.notify_keyspace_event(notify::NEW, "new", key, selected_db);
+            }
+            db.notify_keyspace_event(notify::STRING, "set", key, selected_db);
+
+            // EX / PX / EXAT are logged as PXAT so a replay later does not extend the TTL
+            let mut argv = command.str_cmd[..3].to_vec();
+            if let Some(expire_at) = db.db(selected_db).expiry.get(key) {
+                let ms = expire_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                argv.extend(["PXAT".to_string(), ms.to_string()]);
+                db.notify_keyspace_event(notify::GENERIC, "expire", key, selected_db);
+            }
+            db.propagate(selected_db, argv);
+            response.to_string()
+        }
+
+        "get" => {
+            let key = &command.str_cmd[1];
+            db.expire_if_needed(selected_db, key);
+            match db.db_mut(selected_db).get_value(command) {
+                Ok(Some(string_return)) => command.format_response_code(Some(string_return)),
+                Ok(None) => {
+                    db.notify_keyspace_event(notify::KEY_MISS, "keymiss", key, selected_db);
+                    "$-1\r\n".to_string()
+                }
+                Err(e) => e.to_string(),
+            }
+        }
+
+        "del" => {
+            let mut deleted = 0;
+            for key in &command.str_cmd[1..] {
+                db.expire_if_needed(selected_db, key);
+                if db.db_mut(selected_db).remove_key(key).is_some() {
+                    deleted += 1;
+                    db.signal_modified_key(selected_db, key);
+                    db.notify_keyspace_event(notify::GENERIC, "del", key, selected_db);
+                }
+            }
+            if deleted > 0 {
+                db.propagate(selected_db, command.str_cmd.clone());
+            }
+            format!(":{}\r\n", deleted)
+        }
+
+        "config" => match command.str_cmd[1].to_lowercase().as_str() {
+            "get" if command.str_cmd.len() > 2 => config.get_config(command),
+            "get" => wrong_arity("config|get").to_string(),
+            "set" => {
+                let response = config.set_config(command);
+                db.notify_keyspace_events = config.get_notify_keyspace_events();
+                if let Err(e) = db.set_appendonly(config) {
+                    eprintln!("Unable to turn on AOF: {}", e);
+                    config
+                        .config
+                        .insert("--appendonly".to_string(), "no".to_string());
+                    return RedisResponse::Error(
+                        "ERR CONFIG SET failed (possibly related to argument 'appendonly') - Unable to turn on AOF. Check server logs.".to_string(),
+                    )
+                    .to_string();
+                }
+                if let Some(aof) = db.aof.as_mut() {
+                    aof.fsync = config.get_appendfsync();
+                }
+                db.replication
+                    .resize_backlog(config.get_repl_backlog_size());
+                response.to_string()
+            }
+            subcommand => RedisResponse::Error(format!(
+                "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+                subcommand
+            ))
+            .to_string(),
+        },
+
+        "keys" => {
+            let lst_of_keys = db.db(selected_db).get_keys(&command.str_cmd[1]);
+            let base_str = format!("*{}\r\n", lst_of_keys.len());
+
+            let formatted_item = lst_of_keys
+                .iter()
+                .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+                .collect::<String>();
+
+            base_str + &formatted_item
+        }
+
+        "scan" => scan::parse_cursor(&command.str_cmd[1])
+            .and_then(|cursor| {
+                let opts = ScanOptions::parse(&command.str_cmd[2..], true, false)?;
+                Ok((cursor, opts))
+            })
+            .map(|(cursor, opts)| {
+                let (next_cursor, keys) = db.db(selected_db).scan(cursor, &opts);
+                scan::format_scan_response(next_cursor, &keys)
+            })
+            .unwrap_or_else(|e| e.to_string()),
+
+        cmd @ ("sscan" | "hscan" | "zscan") => {
+            let value_type = match cmd {
+                "sscan" => "set",
+                "hscan" => "hash",
+                _ => "zset",
+            };
+            scan::parse_cursor(&command.str_cmd[2])
+                .and_then(|cursor| {
+                    let opts =
+                        ScanOptions::parse(&command.str_cmd[3..], false, value_type == "hash")?;
+                    db.db(selected_db).scan_collection(
+                        &command.str_cmd[1],
+                        value_type,
+                        cursor,
+                        &opts,
+                    )
+                })
+                .map(|(next_cursor, items)| scan::format_scan_response(next_cursor, &items))
+                .unwrap_or_else(|e| e.to_string())
+        }
+
+        "select" => match db.parse_db_index(&command.str_cmd[1]) {
+            Ok(index) => {
+                client.selected_db = index;
+                RedisResponse::OK("OK".to_string()).to_string()
+            }
+            Err(e) => e.to_string(),
+        },
+
+        "swapdb" => match (
+            db.parse_db_index(&command.str_cmd[1]),
+            db.parse_db_index(&command.str_cmd[2]),
+        ) {
+            (Ok(first), Ok(second)) => {
+                db.swap_db(first, second);
+                db.propagate(selected_db, command.str_cmd.clone());
+                RedisResponse::OK("OK".to_string()).to_string()
+            }
+            (Err(_), _) | (_, Err(_)) => {
+                RedisResponse::Error("ERR invalid DB index".to_string()).to_string()
+            }
+        },
+
+        cmd @ ("flushdb" | "flushall") => {
+            let lazy = match command.str_cmd.get(1).map(|s| s.to_lowercase()) {
+                None => Some(false),
+                Some(mode) if mode == "sync" => Some(false),
+                Some(mode) if mode == "async" => Some(true),
+                Some(_) => None,
+            };
+            match lazy {
+                Some(lazy) if command.str_cmd.len() <= 2 => {
+                    if cmd == "flushdb" {
+                        db.flush_db(selected_db, lazy);
+                    } else {
+                        db.flush_all(lazy);
+                    }
+                    db.propagate(selected_db, command.str_cmd.clone());
+                    RedisResponse::OK("OK".to_string()).to_string()
+                }
+                _ => RedisResponse::Error("ERR syntax error".to_string()).to_string(),
+            }
+        }
+
+        "info" => info(db, config, command),
+
+        "publish" => {
+            let receivers = db
+                .pubsub
+                .lock()
+                .unwrap()
+                .publish(&command.str_cmd[1], &command.str_cmd[2]);
+            format!(":{}\r\n", receivers)
+        }
+
+        "spublish" => {
+            let receivers = db
+                .pubsub
+                .lock()
+                .unwrap()
+                .spublish(&command.str_cmd[1], &command.str_cmd[2]);
+            format!(":{}\r\n", receivers)
+        }
+
+        "pubsub" => pubsub_introspection(&db.pubsub.lock().unwrap(), command),
+
+        "save" => db.save(&config.get_rdb_path()).to_string(),
+
+        "bgsave" => db.bgsave(&config.get_rdb_path()).to_string(),
+
+        "bgrewriteaof" => db
+            .bgrewriteaof(config.get_aof_use_rdb_preamble())
+            .to_string(),
+
+        "lastsave" => format!(":{}\r\n", db.save_status.lock().unwrap().lastsave),
+
+        "hello" => hello(db, client, command),
+
+        "client" => client_command(db, client, command),
+
+        "replconf" => replconf(client, command),
+
+        "wait" | "waitaof" => replication::wait_now(db, command),
+
+        // Queued inside MULTI: EXEC has already dropped every watch by the time this runs
+        "unwatch" => RedisResponse::OK("OK".to_string()).to_string(),
+
+        // MULTI / EXEC / DISCARD / WATCH only make sense at the connection level, see event_handler
+        cmd => RedisResponse::Error(format!("ERR '{}' is not allowed here", cmd)).to_string(),
+    }
+}
+
+fn info(db: &RedisData, config: &RedisConfig, command: &RedisCommand) -> String {
+    let mut db_info: HashMap<String, Vec<String>> = HashMap::new();
+
+    let mut replication = vec![];
+    if let ReplicationRole::Slave = db.get_role() {
+        let link = &db.replication;
+        let (host, port) = db.get_host_port();
+        replication.push("role:slave".to_string());
+        replication.push(format!("master_host:{}", host.unwrap_or_default()));
+        replication.push(format!("master_port:{}", port.unwrap_or_default()));
+        replication.push(format!(
+            "master_link_status:{}",
+            if link.master_link_up { "up" } else { "down" }
+        ));
+        replication.push(format!(
+            "master_last_io_seconds_ago:{}",
+            if link.master_link_up {
+                link.master_last_io.elapsed().as_secs() as i64
+            } else {
+                -1
+            }
+        ));
+        replication.push(format!(
+            "master_sync_in_progress:{}",
+            link.sync_in_progress as u8
+        ));
+        replication.push(format!("slave_read_repl_offset:{}", link.offset));
+        replication.push(format!("slave_repl_offset:{}", link.offset));
+        if !link.master_link_up {
+            replication.push(format!(
+                "master_link_down_since_seconds:{}",
+                link.master_link_down_since
+                    .map_or(-1, |since| since.elapsed().as_secs() as i64)
+            ));
+        }
+        replication.push(format!(
+            "slave_read_only:{}",
+            config.get_replica_read_only() as u8
+        ));
+    } else {
+        replication.push("role:master".to_string());
+    }
+    replication.push(format!(
+        "connected_slaves:{}",
+        db.replication.replicas.len()
+    ));
+    let mut replicas = db.replication.replicas.iter().collect::<Vec<_>>();
+    replicas.sort_by_key(|(id, _)| **id);
+    for (i, (_, replica)) in replicas.into_iter().enumerate() {
+        replication.push(format!(
+            "slave{}:ip={},port={},state={},offset={},lag={}",
+            i,
+            replica.ip,
+            replica.port,
+            if replica.online {
+                "online"
+            } else {
+                "wait_bgsave"
+            },
+            replica.ack_offset,
+            replica.last_ack.elapsed().as_secs()
+        ));
+    }
+    replication.push(format!("master_replid:{}", db.replication.replid));
+    replication.push(format!("master_repl_offset:{}", db.replication.offset));
+    replication.push(format!("master_replid2:{}", db.replication.replid2));
+    replication.push(format!(
+        "second_repl_offset:{}",
+        db.replication
+            .second_repl_offset
+            .map_or("-1".to_string(), |offset| offset.to_string())
+    ));
+    match &db.replication.backlog {
+        Some(backlog) => {
+            replication.push("repl_backlog_active:1".to_string());
+            replication.push(format!("repl_backlog_size:{}", backlog.size()));
+            replication.push(format!(
+                "repl_backlog_first_byte_offset:{}",
+                db.replication.offset + 1 - backlog.histlen() as u64
+            ));
+            replication.push(format!("repl_backlog_histlen:{}", backlog.histlen()));
+        }
+        None => {
+            replication.push("repl_backlog_active:0".to_string());
+            replication.push(format!("repl_backlog_size:{}", db.replication.backlog_size));
+            replication.push("repl_backlog_first_byte_offset:0".to_string());
+            replication.push("repl_backlog_histlen:0".to_string());
+        }
+    }
+    db_info.insert("replication".to_string(), replication);
+
+    let persistence = {
+        let status = db.save_status.lock().unwrap();
+        let mut persistence = vec![
+            format!("rdb_bgsave_in_progress:{}", status.bgsave_in_progress as u8),
+            format!("rdb_changes_since_last_save:{}", db.dirty),
+            format!("rdb_last_save_time:{}", status.lastsave),
+            format!(
+                "rdb_last_bgsave_status:{}",
+                if status.last_bgsave_ok { "ok" } else { "err" }
+            ),
+            format!("aof_enabled:{}", db.aof.is_some() as u8),
+            format!(
+                "aof_rewrite_in_progress:{}",
+                db.aof.as_ref().is_some_and(|aof| aof.rewrite_in_progress()) as u8
+            ),
+            format!(
+                "aof_last_bgrewrite_status:{}",
+                match db.aof.as_ref().is_none_or(|aof| aof.last_rewrite_ok) {
+                    true => "ok",
+                    false => "err",
+                }
+            ),
+            format!(
+                "aof_last_write_status:{}",
+                match db
+                    .aof
+                    .as_ref()
+                    .and_then(|aof| aof.last_write_error.as_ref())
+                {
+                    Some(_) => "err",
+                    None => "ok",
+                }
+            ),
+        ];
+        if let Some(aof) = db.aof.as_ref() {
+            persistence.push(format!("aof_current_size:{}", aof.current_size));
+            persistence.push(format!("aof_base_size:{}", aof.base_size));
+        }
+        persistence
+    };
+    db_info.insert("persistence".to_string(), persistence);
+
+    let keyspace = db
+        .databases
+        .iter()
+        .enumerate()
+        .filter(|(_, database)| !database.data.is_empty())
+        .map(|(index, database)| {
+            format!(
+                "db{}:keys={},expires={},avg_ttl=0",
+                index,
+                database.data.len(),
+                database.expiry.len()
+            )
+        })
+        .collect::<Vec<String>>();
+    db_info.insert("keyspace".to_string(), keyspace);
+
+    let lst_info = match command.str_cmd.get(1).map(|s| s.to_lowercase()) {
+        Some(section) if db_info.contains_key(&section) => {
+            // Return only the requested section
+            db_info.get(&section).cloned().unwrap_or_default()
+        }
+        Some(_) => Vec::new(),
+        None => {
+            // Return all info
+            db_info.values().flatten().cloned().collect()
+        }
+    };
+
+    let total_response = lst_info
+        .iter()
+        .map(|item| format!("{}\r\n", item))
+        .collect::<String>();
+
+    format!("${}\r\n{}\r\n", total_response.len(), total_response)
+}
+
+// REPLCONF option value [option value ...], sent by a replica during the handshake
+fn replconf(client: &mut ClientState, command: &RedisCommand) -> String {
+    let args = &command.str_cmd[1..];
+    if args.len() % 2 == 1 {
+        return RedisResponse::Error("ERR syntax error".to_string()).to_string();
+    }
+
+    for pair in args.chunks(2) {
+        match pair[0].to_lowercase().as_str() {
+            "listening-port" => match pair[1].parse::<u16>() {
+                Ok(port) => client.replica_port = Some(port),
+                Err(_) => {
+                    return RedisResponse::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    )
+                    .to_string()
+                }
+            },
+            // Capabilities of the replica, psync2 is the only protocol spoken here
+            "capa" => {
+                if pair[1].eq_ignore_ascii_case("eof") {
+                    client.replica_capa_eof = true;
+                }
+            }
+            // Only meaningful on the replication link, other clients get no reply
+            "ack" | "getack" => return String::new(),
+            option => {
+                return RedisResponse::Error(format!(
+                    "ERR Unrecognized REPLCONF option: {}",
+                    option
+                ))
+                .to_string()
+            }
+        }
+    }
+    RedisResponse::OK("OK".to_string()).to_string()
+}
+
+// HELLO [protover], switches the connection between RESP2 and RESP3
+fn hello(db: &RedisData, client: &mut ClientState, command: &RedisCommand) -> String {
+    if let Some(protover) = command.str_cmd.get(1) {
+        match protover.parse::<i64>() {
+            Ok(2) => client.resp3 = false,
+            Ok(3) => client.resp3 = true,
+            Ok(_) => {
+                return RedisResponse::Error("NOPROTO unsupported protocol version".to_string())
+                    .to_string()
+            }
+            Err(_) => {
+                return RedisResponse::Error(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                )
+                .to_string()
+            }
+        }
+    }
+    if command.str_cmd.len() > 2 {
+        return RedisResponse::Error("ERR syntax error".to_string()).to_string();
+    }
+
+    let role = match db.get_role() {
+        ReplicationRole::Master => "master",
+        ReplicationRole::Slave => "replica",
+    };
+    let proto = if client.resp3 { 3 } else { 2 };
+    let fields = [
+        ("server", "$5\r\nredis\r\n".to_string()),
+        (
+            "version",
+            format!("${}\r\n{}\r\n", REDIS_VERSION.len(), REDIS_VERSION),
+        ),
+        ("proto", format!(":{}\r\n", proto)),
+        ("id", format!(":{}\r\n", client.id)),
+        ("mode", "$10\r\nstandalone\r\n".to_string()),
+        ("role", format!("${}\r\n{}\r\n", role.len(), role)),
+        ("modules", "*0\r\n".to_string()),
+    ];
+
+    // A map in RESP3, a flat list of field / value pairs in RESP2
+    let mut response = if client.resp3 {
+        format!("%{}\r\n", fields.len())
+    } else {
+        format!("*{}\r\n", fields.len() * 2)
+    };
+    for (field, value) in fields {
+        response += &format!("${}\r\n{}\r\n{}", field.len(), field, value);
+    }
+    response
+}
+
+// CLIENT ID | GETREDIR | TRACKING | CACHING
+fn client_command(db: &mut RedisData, client: &mut ClientState, command: &RedisCommand) -> String {
+    let args = &command.str_cmd[2..];
+    match command.str_cmd[1].to_lowercase().as_str() {
+        "id" if args.is_empty() => format!(":{}\r\n", client.id),
+
+        // -1 when tracking is off, 0 when it is on without redirection
+        "getredir" if args.is_empty() => {
+            let redirect = match db.tracking.options(client.id) {
+                Some(options) => options.redirect.map_or(0, |id| id as i64),
+                None => -1,
+            };
+            format!(":{}\r\n", redirect)
+        }
+
+        "tracking" if !args.is_empty() => client_tracking(db, client, args)
+            .map(|_| RedisResponse::OK("OK".to_string()))
+            .unwrap_or_else(|e| e)
+            .to_string(),
+
+        "caching" if args.len() == 1 => {
+            let options = match db.tracking.options(client.id) {
+                Some(options) if options.optin || options.optout => options,
+                _ => {
+                    return RedisResponse::Error(
+                        "ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".to_string(),
+                    )
+                    .to_string()
+                }
+            };
+            match args[0].to_lowercase().as_str() {
+                "yes" if options.optin => client.caching = Some(true),
+                "yes" => return RedisResponse::Error(
+                    "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."
+                        .to_string(),
+                )
+                .to_string(),
+                "no" if options.optout => client.caching = Some(false),
+                "no" => return RedisResponse::Error(
+                    "ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."
+                        .to_string(),
+                )
+                .to_string(),
+                _ => return RedisResponse::Error("ERR syntax error".to_string()).to_string(),
+            }
+            RedisResponse::OK("OK".to_string()).to_string()
+        }
+
+        subcommand @ ("id" | "getredir" | "tracking" | "caching") => {
+            wrong_arity(&format!("client|{}", subcommand)).to_string()
+        }
+
+        subcommand => RedisResponse::Error(format!(
+            "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+            subcommand
+        ))
+        .to_string(),
+    }
+}
+
+// CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]
+fn client_tracking(
+    db: &mut RedisData,
+    client: &mut ClientState,
+    args: &[String],
+) -> Result<(), RedisResponse> {
+    let syntax_error = || RedisResponse::Error("ERR syntax error".to_string());
+
+    let enable = match args[0].to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return Err(syntax_error()),
+    };
+
+    let mut options = TrackingOptions::default();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "redirect" if i + 1 < args.len() => {
+                if options.redirect.is_some() {
+                    return Err(RedisResponse::Error(
+                        "ERR A client can only redirect to a single other client".to_string(),
+                    ));
+                }
+                let id = args[i + 1].parse::<u64>().map_err(|_| {
+                    RedisResponse::Error("ERR value is not an integer or out of range".to_string())
+                })?;
+                // Redirecting to itself is the same as not redirecting
+                if id != client.id {
+                    if !db.clients.contains_key(&id) {
+                        return Err(RedisResponse::Error(
+                            "ERR The client ID you want redirect to does not exist".to_string(),
+                        ));
+                    }
+                    options.redirect = Some(id);
+                }
+                i += 1;
+            }
+            "prefix" if i + 1 < args.len() => {
+                options.prefixes.push(args[i + 1].clone());
+                i += 1;
+            }
+            "bcast" => options.bcast = true,
+            "optin" => options.optin = true,
+            "optout" => options.optout = true,
+            "noloop" => options.noloop = true,
+            _ => return Err(syntax_error()),
+        }
+        i += 1;
+    }
+
+    if !enable {
+        db.tracking.disable(client.id);
+        client.caching = None;
+        return Ok(());
+    }
+
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err(RedisResponse::Error(
+            "ERR PREFIX option requires BCAST mode to be enabled".to_string(),
+        ));
+    }
+    if options.optin && options.optout {
+        return Err(RedisResponse::Error(
+            "ERR You can't use OPTIN and OPTOUT at the same time".to_string(),
+        ));
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Err(RedisResponse::Error(
+            "ERR OPTIN and OPTOUT are not compatible with BCAST".to_string(),
+        ));
+    }
+    if let Some(current) = db.tracking.options(client.id) {
+        if current.bcast != options.bcast {
+            return Err(RedisResponse::Error(
+                "ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string(),
+            ));
+        }
+        if current.optin != options.optin || current.optout != options.optout {
+            return Err(RedisResponse::Error(
+                "ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".to_string(),
+            ));
+        }
+    }
+
+    db.tracking
+        .enable(client.id, options)
+        .map_err(RedisResponse::Error)
+}
+
+// SUBSCRIBE / UNSUBSCRIBE / PSUBSCRIBE / PUNSUBSCRIBE / SSUBSCRIBE / SUNSUBSCRIBE, one
+// confirmation per channel. Shard replies count shard subscriptions only, like Redis.
+pub fn pubsub_command(
+    pubsub: &mut PubSub,
+    client: &mut ClientState,
+    command: &RedisCommand,
+) -> String {
+    let kind = command.str_cmd[0].to_lowercase();
+    let args = &command.str_cmd[1..];
+    let mut response = String::new();
+
+    match kind.as_str() {
+        "subscribe" => {
+            for channel in args {
+                if !client.channels.contains(channel) {
+                    pubsub.subscribe(client.id, &client.sender, channel);
+                    client.channels.push(channel.clone());
+                }
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(channel),
+                    client.subscription_count(),
+                );
+            }
+        }
+        "psubscribe" => {
+            for pattern in args {
+                if !client.patterns.contains(pattern) {
+                    pubsub.psubscribe(client.id, &client.sender, pattern);
+                    client.patterns.push(pattern.clone());
+                }
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(pattern),
+                    client.subscription_count(),
+                );
+            }
+        }
+        "unsubscribe" => {
+            // Without arguments every channel is dropped
+            let channels = if args.is_empty() {
+                client.channels.clone()
+            } else {
+                args.to_vec()
+            };
+            for channel in &channels {
+                pubsub.unsubscribe(client.id, channel);
+                client.channels.retain(|c| c != channel);
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(channel),
+                    client.subscription_count(),
+                );
+            }
+            if channels.is_empty() {
+                response +=
+                    &subscription_reply(client.resp3, &kind, None, client.subscription_count());
+            }
+        }
+        "ssubscribe" => {
+            for channel in args {
+                if !client.shard_channels.contains(channel) {
+                    pubsub.ssubscribe(client.id, &client.sender, channel);
+                    client.shard_channels.push(channel.clone());
+                }
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(channel),
+                    client.shard_channels.len(),
+                );
+            }
+        }
+        "sunsubscribe" => {
+            let channels = if args.is_empty() {
+                client.shard_channels.clone()
+            } else {
+                args.to_vec()
+            };
+            for channel in &channels {
+                pubsub.sunsubscribe(client.id, channel);
+                client.shard_channels.retain(|c| c != channel);
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(channel),
+                    client.shard_channels.len(),
+                );
+            }
+            if channels.is_empty() {
+                response +=
+                    &subscription_reply(client.resp3, &kind, None, client.shard_channels.len());
+            }
+        }
+        _ => {
+            let patterns = if args.is_empty() {
+                client.patterns.clone()
+            } else {
+                args.to_vec()
+            };
+            for pattern in &patterns {
+                pubsub.punsubscribe(client.id, pattern);
+                client.patterns.retain(|p| p != pattern);
+                response += &subscription_reply(
+                    client.resp3,
+                    &kind,
+                    Some(pattern),
+                    client.subscription_count(),
+                );
+            }
+            if patterns.is_empty() {
+                response +=
+                    &subscription_reply(client.resp3, &kind, None, client.subscription_count());
+            }
+        }
+    }
+
+    response
+}
+
+// Drops every subscription of a client that is going away
+pub fn unsubscribe_all(pubsub: &mut PubSub, client: &mut ClientState) {
+    for channel in client.channels.drain(..) {
+        pubsub.unsubscribe(client.id, &channel);
+    }
+    for pattern in client.patterns.drain(..) {
+        pubsub.punsubscribe(client.id, &pattern);
+    }
+    for channel in client.shard_channels.drain(..) {
+        pubsub.sunsubscribe(client.id, &channel);
+    }
+}
+
+// *3 [kind, channel, number of subscriptions the client now has]
+fn subscription_reply(resp3: bool, kind: &str, channel: Option<&str>, count: usize) -> String {
+    let channel = match channel {
+        Some(channel) => format!("${}\r\n{}\r\n", channel.len(), channel),
+        None => "$-1\r\n".to_string(),
+    };
+    // Sent as push frames to RESP3 clients, like the messages that follow
+    format!(
+        "{}3\r\n${}\r\n{}\r\n{}:{}\r\n",
+        if resp3 { '>' } else { '*' },
+        kind.len(),
+        kind,
+        channel,
+        count
+    )
+}
+
+// PUBSUB CHANNELS [pattern] / NUMSUB [channel ...] / NUMPAT
+//        SHARDCHANNELS [pattern] / SHARDNUMSUB [channel ...]
+fn pubsub_introspection(pubsub: &PubSub, command: &RedisCommand) -> String {
+    let args = &command.str_cmd[2..];
+
+    match command.str_cmd[1].to_lowercase().as_str() {
+        "channels" if args.len() <= 1 => {
+            let channels = pubsub.active_channels(args.first().map(|s| s.as_str()));
+            let formatted_item = channels
+                .iter()
+                .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+                .collect::<String>();
+            format!("*{}\r\n{}", channels.len(), formatted_item)
+        }
+        "shardchannels" if args.len() <= 1 => {
+            let channels = pubsub.active_shard_channels(args.first().map(|s| s.as_str()));
+            let formatted_item = channels
+                .iter()
+                .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+                .collect::<String>();
+            format!("*{}\r\n{}", channels.len(), formatted_item)
+        }
+        subcommand @ ("numsub" | "shardnumsub") => {
+            let formatted_item = args
+                .iter()
+                .map(|channel| {
+                    let count = if subcommand == "numsub" {
+                        pubsub.num_subscribers(channel)
+                    } else {
+                        pubsub.num_shard_subscribers(channel)
+                    };
+                    format!("${}\r\n{}\r\n:{}\r\n", channel.len(), channel, count)
+                })
+                .collect::<String>();
+            format!("*{}\r\n{}", args.len() * 2, formatted_item)
+        }
+        "numpat" if args.is_empty() => format!(":{}\r\n", pubsub.num_patterns()),
+        subcommand => RedisResponse::Error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+            subcommand
+        ))
+        .to_string(),
+    }
+}
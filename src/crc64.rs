@@ -0,0 +1,52 @@
+// CRC-64/Jones, the checksum Redis appends to RDB files: polynomial 0xad93d23594c935a9 in
+// reflected form, zero initial value and no final xor
+const POLY: u64 = 0x95ac9329ac4bc9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// Continues a checksum, start with 0
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc64;
+
+    #[test]
+    fn check_value() {
+        // The check value of CRC-64/Jones, also what Redis' own crc64 test expects
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(0, b""), 0);
+    }
+
+    #[test]
+    fn continues_a_checksum() {
+        let data = b"This is a test of the emergency broadcast system.";
+        let (head, tail) = data.split_at(17);
+        assert_eq!(crc64(crc64(0, head), tail), crc64(0, data));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::glob;
+use crate::rdb::RedisResponse;
+use crate::scan::ScanOptions;
+use crate::RedisCommand;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::ops::Bound;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Only strings can be written by clients so far; the other types come from RDB files and
+// are what SCAN TYPE and the SSCAN/HSCAN/ZSCAN family operate on.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RedisValue {
+    String(String),
+    List(VecDeque<String>),
+    Set(ScanMap<()>),
+    ZSet(ScanMap<f64>),
+    Hash(ScanMap<String>),
+    Stream(Stream),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+// Metadata and consumer groups are kept as loaded so saving does not lose them
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<StreamGroup>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StreamGroup {
+    pub name: String,
+    pub last_id: StreamId,
+    pub entries_read: u64,
+    // Delivered but not acknowledged entries: (id, delivery time in ms, delivery count)
+    pub pending: Vec<(StreamId, u64, u64)>,
+    pub consumers: Vec<StreamConsumer>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StreamConsumer {
+    pub name: String,
+    pub seen_time: u64,
+    pub active_time: u64,
+    pub pending: Vec<StreamId>,
+}
+
+// Members of a set, hash or sorted set, kept in the order SSCAN / HSCAN / ZSCAN walk
+// them. A cursor is a position in the collection itself, so it stays valid however the
+// collection changes between calls and no separate index has to be kept in sync.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct ScanMap<V> {
+    entries: BTreeMap<(u64, String), V>,
+}
+
+// Member positions come from one hasher for the whole process: collections are built
+// while loading, before it is known which database they end up in
+fn member_position(member: &str) -> u64 {
+    static HASHER: OnceLock<RandomState> = OnceLock::new();
+    HASHER.get_or_init(RandomState::new).hash_one(member).max(1)
+}
+
+impl<V> ScanMap<V> {
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, member: String, value: V) -> Option<V> {
+        self.entries
+            .insert((member_position(&member), member), value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.entries
+            .iter()
+            .map(|((_, member), value)| (member, value))
+    }
+
+    // Members from the cursor position on, with their positions
+    fn scan_from(&self, cursor: u64) -> impl Iterator<Item = (u64, &String, &V)> {
+        self.entries
+            .range((cursor, String::new())..)
+            .map(|((position, member), value)| (*position, member, value))
+    }
+}
+
+impl<V> FromIterator<(String, V)> for ScanMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (member, value) in iter {
+            map.insert(member, value);
+        }
+        map
+    }
+}
+
+impl FromIterator<String> for ScanMap<()> {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        iter.into_iter().map(|member| (member, ())).collect()
+    }
+}
+
+impl RedisValue {
+    // Name reported by TYPE and matched by SCAN ... TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Set(_) => "set",
+            RedisValue::ZSet(_) => "zset",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Stream(_) => "stream",
+        }
+    }
+}
+
+// One logical database, selected per connection with SELECT
+pub struct Database {
+    pub data: HashMap<String, RedisValue>,
+    pub expiry: HashMap<String, SystemTime>,
+
+    // Every key ordered by scan_hash(). SCAN cursors are positions in this order, so they
+    // stay valid however the table grows or shrinks between calls.
+    scan_index: BTreeSet<(u64, String)>,
+    // Keys with a TTL in scan order, and where the active expire cycle resumes sampling
+    expire_index: BTreeSet<(u64, String)>,
+    expire_cursor: u64,
+    scan_hasher: RandomState,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+            expiry: HashMap::new(),
+            scan_index: BTreeSet::new(),
+            expire_index: BTreeSet::new(),
+            expire_cursor: 0,
+            scan_hasher: RandomState::new(),
+        }
+    }
+
+    // Position of an element in the scan order. Zero is reserved for the cursor that
+    // starts and ends an iteration.
+    pub fn scan_hash(&self, item: &str) -> u64 {
+        self.scan_hasher.hash_one(item).max(1)
+    }
+
+    pub fn insert_key(&mut self, key: String, value: RedisValue) {
+        if !self.data.contains_key(&key) {
+            self.scan_index.insert((self.scan_hash(&key), key.clone()));
+        }
+        self.data.insert(key, value);
+    }
+
+    pub fn set_expiry(&mut self, key: String, expire_at: SystemTime) {
+        self.expire_index
+            .insert((self.scan_hash(&key), key.clone()));
+        self.expiry.insert(key, expire_at);
+    }
+
+    pub fn remove_expiry(&mut self, key: &str) {
+        if self.expiry.remove(key).is_some() {
+            self.expire_index
+                .remove(&(self.scan_hash(key), key.to_string()));
+        }
+    }
+
+    // Next keys with a TTL for the active expire cycle, continuing where the previous call
+    // stopped and starting over once every key has been visited
+    pub fn sample_volatile(&mut self, count: usize) -> Vec<String> {
+        let mut range = self.expire_index.range((
+            Bound::Included((self.expire_cursor, String::new())),
+            Bound::Unbounded,
+        ));
+        let keys = range
+            .by_ref()
+            .take(count)
+            .map(|(_, key)| key.clone())
+            .collect();
+        self.expire_cursor = range.next().map(|(hash, _)| *hash).unwrap_or(0);
+        keys
+    }
+
+    pub fn remove_key(&mut self, key: &str) -> Option<RedisValue> {
+        self.remove_expiry(key);
+        let value = self.data.remove(key)?;
+        self.scan_index
+            .remove(&(self.scan_hash(key), key.to_string()));
+        Some(value)
+    }
+
+    // SET key value [EX seconds | PX milliseconds | EXAT unix-seconds | PXAT unix-milliseconds]
+    pub fn set_value(&mut self, command: &RedisCommand) -> RedisResponse {
+        let expire_at = match &command.str_cmd[3..] {
+            [] => None,
+            [option, value] => {
+                let amount = match value.parse::<i64>() {
+                    Ok(amount) if amount > 0 => amount as u64,
+                    Ok(_) => return invalid_expire_time(),
+                    Err(_) => {
+                        return RedisResponse::Error(
+                            "ERR value is not an integer or out of range".to_string(),
+                        )
+                    }
+                };
+                let expire_at = match option.to_lowercase().as_str() {
+                    "ex" => SystemTime::now().checked_add(Duration::from_secs(amount)),
+                    "px" => SystemTime::now().checked_add(Duration::from_millis(amount)),
+                    "exat" => UNIX_EPOCH.checked_add(Duration::from_secs(amount)),
+                    "pxat" => UNIX_EPOCH.checked_add(Duration::from_millis(amount)),
+                    _ => return RedisResponse::Error("ERR syntax error".to_string()),
+                };
+                match expire_at {
+                    Some(expire_at) => Some(expire_at),
+                    None => return invalid_expire_time(),
+                }
+            }
+            _ => return RedisResponse::Error("ERR syntax error".to_string()),
+        };
+
+        self.insert_key(
+            command.str_cmd[1].clone(),
+            RedisValue::String(command.str_cmd[2].clone()),
+        );
+        // A plain SET discards any TTL the key had
+        self.remove_expiry(&command.str_cmd[1]);
+        if let Some(expire_at) = expire_at {
+            self.set_expiry(command.str_cmd[1].clone(), expire_at);
+        }
+
+        RedisResponse::OK(String::from("OK"))
+    }
+
+    pub fn get_value(&mut self, command: &RedisCommand) -> Result<Option<String>, RedisResponse> {
+        let key = &command.str_cmd[1];
+
+        if self.is_expired(key, SystemTime::now()) {
+            return Ok(None);
+        }
+
+        match self.data.get(key) {
+            Some(RedisValue::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(wrong_type()),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_expired(&self, key: &str, now: SystemTime) -> bool {
+        match self.expiry.get(key) {
+            Some(&expiry_time) => now > expiry_time,
+            None => false,
+        }
+    }
+
+    // Keys matching a glob pattern, skipping the ones that are already logically expired
+    pub fn get_keys(&self, pattern: &str) -> Vec<String> {
+        let all_keys = pattern == "*";
+        let now = SystemTime::now();
+
+        self.data
+            .keys()
+            .filter(|key| !self.is_expired(key, now))
+            .filter(|key| all_keys || glob::string_match(pattern, key, false))
+            .cloned()
+            .collect()
+    }
+
+    // One SCAN step: visits up to opts.count keys from the cursor position and returns the
+    // cursor to continue from (0 once the whole keyspace has been visited) along with the
+    // visited keys that pass the MATCH and TYPE filters.
+    pub fn scan(&self, cursor: u64, opts: &ScanOptions) -> (u64, Vec<String>) {
+        let now = SystemTime::now();
+        let mut range = self
+            .scan_index
+            .range((Bound::Included((cursor, String::new())), Bound::Unbounded));
+
+        let mut keys = vec![];
+        for (_, key) in range.by_ref().take(opts.count) {
+            if self.is_expired(key, now) || !opts.matches(key) {
+                continue;
+            }
+            if let Some(value_type) = &opts.value_type {
+                match self.data.get(key) {
+                    Some(value) if value.type_name().eq_ignore_ascii_case(value_type) => {}
+                    _ => continue,
+                }
+            }
+            keys.push(key.clone());
+        }
+
+        let next_cursor = range.next().map(|(hash, _)| *hash).unwrap_or(0);
+        (next_cursor, keys)
+    }
+
+    // SSCAN / HSCAN / ZSCAN. Members are walked in the order of their ScanMap; hashes and
+    // sorted sets return flattened field/value (member/score) pairs.
+    pub fn scan_collection(
+        &self,
+        key: &str,
+        value_type: &str,
+        cursor: u64,
+        opts: &ScanOptions,
+    ) -> Result<(u64, Vec<String>), RedisResponse> {
+        if self.is_expired(key, SystemTime::now()) {
+            return Ok((0, vec![]));
+        }
+
+        let members: Box<dyn Iterator<Item = (u64, &String, Option<String>)>> =
+            match self.data.get(key) {
+                None => return Ok((0, vec![])),
+                Some(value) if value.type_name() != value_type => return Err(wrong_type()),
+                Some(RedisValue::Set(set)) => Box::new(
+                    set.scan_from(cursor)
+                        .map(|(pos, member, _)| (pos, member, None)),
+                ),
+                Some(RedisValue::Hash(hash)) => Box::new(
+                    hash.scan_from(cursor)
+                        .map(|(pos, field, value)| (pos, field, Some(value.clone()))),
+                ),
+                Some(RedisValue::ZSet(zset)) => Box::new(
+                    zset.scan_from(cursor)
+                        .map(|(pos, member, score)| (pos, member, Some(score.to_string()))),
+                ),
+                Some(_) => return Err(wrong_type()),
+            };
+
+        let mut members = members.peekable();
+        let mut items = vec![];
+        for (_, member, value) in members.by_ref().take(opts.count) {
+            if !opts.matches(member) {
+                continue;
+            }
+            items.push(member.clone());
+            if let Some(value) = value {
+                if !opts.novalues {
+                    items.push(value);
+                }
+            }
+        }
+
+        let next_cursor = members.peek().map(|(pos, _, _)| *pos).unwrap_or(0);
+        Ok((next_cursor, items))
+    }
+}
+
+fn invalid_expire_time() -> RedisResponse {
+    RedisResponse::Error("ERR invalid expire time in 'set' command".to_string())
+}
+
+pub fn wrong_type() -> RedisResponse {
+    RedisResponse::Error(
+        "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+    )
+}
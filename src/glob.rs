@@ -0,0 +1,234 @@
+// Glob-style pattern matching, following the semantics of Redis' stringmatchlen():
+//
+//   *        matches any sequence of characters (including none)
+//   ?        matches exactly one character
+//   [abc]    matches one character from the set
+//   [^abc]   matches one character not in the set
+//   [a-z]    matches one character in the range (bounds may be reversed)
+//   \x       matches x literally, inside or outside of a [] set
+
+// Patterns nesting deeper than this are rejected instead of recursing further.
+const MAX_NESTING: usize = 1000;
+
+pub fn string_match(pattern: &str, string: &str, nocase: bool) -> bool {
+    let mut skip_longer_matches = false;
+    match_impl(
+        pattern.as_bytes(),
+        string.as_bytes(),
+        nocase,
+        &mut skip_longer_matches,
+        0,
+    )
+}
+
+fn eq_byte(a: u8, b: u8, nocase: bool) -> bool {
+    if nocase {
+        a.eq_ignore_ascii_case(&b)
+    } else {
+        a == b
+    }
+}
+
+fn match_impl(
+    pattern: &[u8],
+    string: &[u8],
+    nocase: bool,
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                while s < string.len() {
+                    if match_impl(
+                        &pattern[p + 1..],
+                        &string[s..],
+                        nocase,
+                        skip_longer_matches,
+                        nesting + 1,
+                    ) {
+                        return true;
+                    }
+                    if *skip_longer_matches {
+                        return false;
+                    }
+                    s += 1;
+                }
+                // Nothing after this '*' matched anywhere in the rest of the string, so
+                // letting an earlier '*' swallow more characters cannot help either.
+                *skip_longer_matches = true;
+                return false;
+            }
+
+            b'?' => {
+                s += 1;
+            }
+
+            b'[' => {
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+
+                let c = string[s];
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        // Unterminated set: treat the end of the pattern as the closing ']'.
+                        p -= 1;
+                        break;
+                    } else if pattern[p] == b'\\' && pattern.len() - p >= 2 {
+                        p += 1;
+                        if pattern[p] == c {
+                            matched = true;
+                        }
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if pattern.len() - p >= 3 && pattern[p + 1] == b'-' {
+                        let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+                        if start > end {
+                            std::mem::swap(&mut start, &mut end);
+                        }
+                        let c = if nocase {
+                            start = start.to_ascii_lowercase();
+                            end = end.to_ascii_lowercase();
+                            c.to_ascii_lowercase()
+                        } else {
+                            c
+                        };
+                        p += 2;
+                        if c >= start && c <= end {
+                            matched = true;
+                        }
+                    } else if eq_byte(pattern[p], c, nocase) {
+                        matched = true;
+                    }
+                    p += 1;
+                }
+
+                if not {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                s += 1;
+            }
+
+            _ => {
+                if pattern[p] == b'\\' && pattern.len() - p >= 2 {
+                    p += 1;
+                }
+                if !eq_byte(pattern[p], string[s], nocase) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+
+        p += 1;
+        if s == string.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+            break;
+        }
+    }
+
+    p == pattern.len() && s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::string_match;
+
+    #[test]
+    fn literal_and_wildcards() {
+        assert!(string_match("hello", "hello", false));
+        assert!(!string_match("hello", "hell", false));
+        assert!(string_match("h?llo", "hallo", false));
+        assert!(!string_match("h?llo", "hllo", false));
+        // As in Redis, an empty string matches nothing, not even "*"
+        assert!(!string_match("*", "", false));
+        assert!(string_match("h*", "h", false));
+        assert!(string_match("h**o", "hello", false));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(string_match("h\\*llo", "h*llo", false));
+        assert!(!string_match("h\\*llo", "hello", false));
+        assert!(string_match("\\?", "?", false));
+        assert!(!string_match("\\?", "a", false));
+        assert!(string_match("[\\]]", "]", false));
+        assert!(string_match("[\\-]", "-", false));
+        // A trailing backslash is matched literally
+        assert!(string_match("a\\", "a\\", false));
+    }
+
+    #[test]
+    fn sets_and_ranges() {
+        assert!(string_match("h[ae]llo", "hallo", false));
+        assert!(!string_match("h[ae]llo", "hillo", false));
+        assert!(string_match("[a-c]", "b", false));
+        assert!(string_match("[c-a]", "b", false));
+        assert!(!string_match("[a-c]", "d", false));
+        assert!(string_match("[^a-c]", "d", false));
+        assert!(!string_match("[^a-c]", "b", false));
+        assert!(!string_match("[^a-c]", "", false));
+    }
+
+    #[test]
+    fn dash_before_closing_bracket_is_a_range_to_it() {
+        // As in Redis, "[a-]" is the range between 'a' and ']', not 'a' or '-'
+        assert!(string_match("[a-]", "a", false));
+        assert!(string_match("[a-]", "_", false));
+        assert!(!string_match("[a-]", "-", false));
+        assert!(!string_match("[a-]", "b", false));
+    }
+
+    #[test]
+    fn unterminated_set() {
+        assert!(string_match("[abc", "a", false));
+        assert!(!string_match("[abc", "d", false));
+        assert!(string_match("x[ab", "xb", false));
+        assert!(!string_match("[", "a", false));
+        assert!(string_match("[^", "a", false));
+    }
+
+    #[test]
+    fn star_backtracking() {
+        assert!(string_match("*a*b", "xaxxb", false));
+        assert!(string_match("a*b*c", "abbbc", false));
+        assert!(string_match("*.txt", "notes.old.txt", false));
+        assert!(!string_match("*a*b", "aaaa", false));
+        assert!(!string_match("a*b*c", "abbb", false));
+        // Would take exponential time without giving up on longer matches
+        let pattern = format!("{}b", "a*".repeat(30));
+        assert!(!string_match(&pattern, &"a".repeat(60), false));
+    }
+
+    #[test]
+    fn nocase() {
+        assert!(string_match("HeLLo", "hello", true));
+        assert!(!string_match("HeLLo", "hello", false));
+        assert!(string_match("[A-C]x", "bX", true));
+        assert!(!string_match("[A-C]x", "bX", false));
+        assert!(string_match("[^A-C]", "d", true));
+        assert!(!string_match("[^A-C]", "b", true));
+    }
+}
@@ -0,0 +1,85 @@
+// LZF decompression, the format Redis uses for compressed strings in RDB files.
+// Each chunk starts with a control byte:
+//   000LLLLL                     literal run of L + 1 bytes that follow
+//   LLLooooo oooooooo            back reference of L + 2 bytes, offset o + 1
+//   111ooooo LLLLLLLL oooooooo   same with a length of 7 + L + 2
+pub fn decompress(input: &[u8], expected_len: usize) -> Option<Vec<u8>> {
+    // The expected length comes from the file, it is only trusted as an upper bound
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 1 << 5 {
+            let run = input.get(i..i + ctrl + 1)?;
+            if output.len() + run.len() > expected_len {
+                return None;
+            }
+            output.extend_from_slice(run);
+            i += ctrl + 1;
+            continue;
+        }
+
+        let mut len = ctrl >> 5;
+        if len == 7 {
+            len += *input.get(i)? as usize;
+            i += 1;
+        }
+        len += 2;
+
+        let offset = ((ctrl & 0x1F) << 8) + *input.get(i)? as usize + 1;
+        i += 1;
+        if offset > output.len() || output.len() + len > expected_len {
+            return None;
+        }
+
+        // Byte by byte: the reference may overlap the bytes it is producing
+        let start = output.len() - offset;
+        for k in 0..len {
+            output.push(output[start + k]);
+        }
+    }
+
+    (output.len() == expected_len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+
+    #[test]
+    fn literal_runs() {
+        assert_eq!(decompress(b"\x02abc", 3), Some(b"abc".to_vec()));
+        assert_eq!(decompress(b"\x01ab\x00c", 3), Some(b"abc".to_vec()));
+        assert_eq!(decompress(b"", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn back_references() {
+        // "ab" then 3 bytes from 2 back
+        assert_eq!(decompress(b"\x01ab\x20\x01", 5), Some(b"ababa".to_vec()));
+        // Long form: 7 + 0 + 2 bytes from 3 back, overlapping what it writes
+        assert_eq!(
+            decompress(b"\x02abc\xe0\x00\x02", 12),
+            Some(b"abcabcabcabc".to_vec())
+        );
+        // 40 times 'a': one literal and a reference of 7 + 30 + 2 to the byte before
+        assert_eq!(decompress(b"\x00a\xe0\x1e\x00", 40), Some(vec![b'a'; 40]));
+    }
+
+    #[test]
+    fn rejects_corrupt_input() {
+        // Reference before the start of the output
+        assert_eq!(decompress(b"\x01ab\x20\x05", 5), None);
+        // Literal run or reference cut short
+        assert_eq!(decompress(b"\x05ab", 6), None);
+        assert_eq!(decompress(b"\x01ab\xe0", 12), None);
+        assert_eq!(decompress(b"\x01ab\x20", 5), None);
+        // Output longer or shorter than the length stored with it
+        assert_eq!(decompress(b"\x02abc", 2), None);
+        assert_eq!(decompress(b"\x02abc", 4), None);
+        assert_eq!(decompress(b"\x02abc\xe0\x00\x02", 11), None);
+    }
+}
@@ -12,68 +12,132 @@ use std::io::{Read, Write};
 //use std::net::{TcpListener, TcpStream};
 use rdb::ReplicationRole;
 use std::result::Result::Ok;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use std::u64;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tokio::sync::RwLock as AsyncRwLock;
 use tokio::task;
+mod aof;
+mod commands;
+mod crc64;
+mod db;
+mod glob;
+mod lzf;
+mod notify;
+mod pubsub;
 mod rdb;
+mod rdb_writer;
 mod redis_config;
+mod replication;
+mod scan;
+mod sentinel;
+mod tracking;
+use bytes::{Buf, BytesMut};
+use pubsub::{ClientSender, PubSub, PushMessage};
 use rdb::RedisData;
 use rdb::RedisResponse;
 use redis_config::RedisConfig;
 
 //#[derive(Debug)]
-struct RedisCommand {
-    cmd_len: i32,          // Number of commands (e.g., echo hey -> 2)
-    n_chars_len: Vec<i32>, // Length of each argument (e.g., [4, 3])
-    str_cmd: Vec<String>,  // Command arguments (e.g., ["ECHO", "hey"])
+pub struct RedisCommand {
+    pub cmd_len: i32, // Number of commands (e.g., echo hey -> 2)
+    #[allow(dead_code)]
+    pub n_chars_len: Vec<i32>, // Length of each argument (e.g., [4, 3])
+    pub str_cmd: Vec<String>, // Command arguments (e.g., ["ECHO", "hey"])
 }
 
 impl RedisCommand {
-    fn parser_receive(command_str: &str) -> Result<Self, String> {
-        let mut lines = command_str.split("\r\n").filter(|x| !x.is_empty());
+    // Parses one RESP array of bulk strings from the front of buf. Returns the command along
+    // with the number of bytes it took, or None while the buffer holds only part of it.
+    fn parser_receive(buf: &[u8]) -> Result<Option<(Self, usize)>, String> {
+        let mut cursor = 0;
+
+        let cmd_len = match Self::read_line(buf, &mut cursor) {
+            Some(line) => match line.strip_prefix(b"*") {
+                Some(len) => Self::parse_len(len)?,
+                None => {
+                    return Err(format!(
+                        "expected '*', got '{}'",
+                        String::from_utf8_lossy(&line[..1])
+                    ))
+                }
+            },
+            None => return Ok(None),
+        };
 
-        //let mut cmd_len = 0;
         let mut n_chars_len = Vec::new();
         let mut str_cmd = Vec::new();
-        let cmd_len = if let Some(line) = lines.next() {
-            if line.starts_with('*') {
-                line[1..]
-                    .parse::<i32>()
-                    .expect("Failed to parse command length")
-            } else {
-                return Err("Invalid command format: expected '*'".to_string());
+        for _ in 0..cmd_len.max(0) {
+            let len = match Self::read_line(buf, &mut cursor) {
+                Some(line) => match line.strip_prefix(b"$") {
+                    Some(len) if Self::parse_len(len)? >= 0 => Self::parse_len(len)?,
+                    Some(_) => return Err("invalid bulk length".to_string()),
+                    None => {
+                        return Err(format!(
+                            "expected '$', got '{}'",
+                            String::from_utf8_lossy(&line[..1])
+                        ))
+                    }
+                },
+                None => return Ok(None),
+            };
+
+            let end = cursor + len as usize;
+            if buf.len() < end + 2 {
+                return Ok(None);
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err("expected CRLF after bulk string".to_string());
             }
-        } else {
-            return Err("Command string is empty".to_string());
-        };
 
-        while let Some(line) = lines.next() {
-            match line.chars().next() {
-                Some('$') => {
-                    n_chars_len.push(
-                        line.trim_start_matches('$')
-                            .parse::<i32>()
-                            .expect("Failed to parse character length"),
-                    );
-                }
+            // Arguments keep their case; only the command name is matched case-insensitively
+            str_cmd.push(String::from_utf8_lossy(&buf[cursor..end]).to_string());
+            n_chars_len.push(len);
+            cursor = end + 2;
+        }
 
-                _ => str_cmd.push(line.to_lowercase()),
-            }
+        Ok(Some((
+            Self {
+                cmd_len,
+                n_chars_len,
+                str_cmd,
+            },
+            cursor,
+        )))
+    }
+
+    fn read_line<'a>(buf: &'a [u8], cursor: &mut usize) -> Option<&'a [u8]> {
+        let start = *cursor;
+        let len = buf[start..].windows(2).position(|w| w == b"\r\n")?;
+        *cursor = start + len + 2;
+        if len == 0 {
+            // An empty line cannot be a header, let the prefix checks reject it
+            return Some(b" ");
         }
+        Some(&buf[start..start + len])
+    }
 
-        Ok(Self {
-            cmd_len,
-            n_chars_len,
-            str_cmd,
-        })
+    fn parse_len(bytes: &[u8]) -> Result<i32, String> {
+        str::from_utf8(bytes)
+            .ok()
+            .and_then(|len| len.parse::<i32>().ok())
+            .ok_or_else(|| "invalid length".to_string())
     }
 
-    fn format_response_code(&self, input: Option<String>) -> String {
+    // RESP array of bulk strings, the form commands are written to the AOF in
+    pub fn encode(args: &[String]) -> String {
+        let formatted_item = args
+            .iter()
+            .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+            .collect::<String>();
+        format!("*{}\r\n{}", args.len(), formatted_item)
+    }
+
+    pub fn format_response_code(&self, input: Option<String>) -> String {
         let str_cmd_to_use = match input {
             Some(s) => {
                 vec![s]
@@ -88,11 +152,91 @@ impl RedisCommand {
     }
 }
 
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Per-connection state, lives as long as the client stays connected
+pub struct ClientState {
+    pub id: u64,
+    // Database picked with SELECT, every connection starts on db 0
+    pub selected_db: usize,
+    // Commands queued between MULTI and EXEC
+    pub multi_queue: Option<Vec<RedisCommand>>,
+    // Set when a command could not be queued, EXEC then discards the transaction
+    pub multi_error: bool,
+    // WATCHed keys as (db index, key, already expired when watched)
+    pub watched_keys: Vec<(usize, String, bool)>,
+    // Pub/sub messages for this connection are sent here and written out by read_commands
+    pub sender: ClientSender,
+    pub channels: Vec<String>,
+    pub patterns: Vec<String>,
+    pub shard_channels: Vec<String>,
+    // Protocol picked with HELLO, RESP3 gets out-of-band data as push frames
+    pub resp3: bool,
+    // CLIENT CACHING YES/NO, applies to the next command only
+    pub caching: Option<bool>,
+    // Link to our master: its commands are the replication stream
+    pub is_master: bool,
+    // REPLCONF listening-port of a replica about to send PSYNC
+    pub replica_port: Option<u16>,
+    // REPLCONF capa eof: it can read a diskless RDB transfer
+    pub replica_capa_eof: bool,
+}
+
+impl ClientState {
+    fn new() -> (Self, UnboundedReceiver<PushMessage>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let client = Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            selected_db: 0,
+            multi_queue: None,
+            multi_error: false,
+            watched_keys: Vec::new(),
+            sender,
+            channels: Vec::new(),
+            patterns: Vec::new(),
+            shard_channels: Vec::new(),
+            resp3: false,
+            caching: None,
+            is_master: false,
+            replica_port: None,
+            replica_capa_eof: false,
+        };
+        (client, receiver)
+    }
+
+    // RESP2 has no push frames: invalidations only reach a connection subscribed to
+    // __redis__:invalidate, typically the REDIRECT target of a tracking client
+    fn accepts_push(&self, message: &PushMessage) -> bool {
+        match message {
+            PushMessage::Invalidate { .. } => {
+                self.resp3 || self.channels.iter().any(|c| c == "__redis__:invalidate")
+            }
+            _ => true,
+        }
+    }
+
+    // A RESP2 connection with at least one subscription only accepts pub/sub commands
+    pub fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    fn unwatch_all_keys(&mut self, db: &mut RedisData) {
+        for (index, key, _) in self.watched_keys.drain(..) {
+            db.unwatch_key(self.id, index, &key);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     //env::set_var("RUST_BACKTRACE", "full");
     let args: Vec<String> = env::args().collect();
-    let config_struct = RedisConfig::parse_argument(args);
+    let mut config_struct = RedisConfig::parse_argument(args);
+
+    if let Some(monitor) = config_struct.get_sentinel_monitor() {
+        sentinel::run(config_struct, monitor).await;
+        return;
+    }
 
     let (host, port, role) = match config_struct.get_replicaof() {
         Some(value) => {
@@ -105,26 +249,57 @@ async fn main() {
         None => (None, None, ReplicationRole::Master),
     };
 
-    let mut redis_data = RedisData::init_db(role, host, port);
+    let pubsub = Arc::new(Mutex::new(PubSub::new()));
+    let mut redis_data = RedisData::init_db(
+        config_struct.get_databases(),
+        Arc::clone(&pubsub),
+        role,
+        host,
+        port,
+    );
 
-    match redis_data.get_role() {
-        ReplicationRole::Slave => {
-            let (host, port) = redis_data.get_host_port();
-            let mut stream = TcpStream::connect(format!("{}:{}", host.unwrap(), port.unwrap()))
-                .await
-                .expect("failed to connect to master server");
+    redis_data.notify_keyspace_events = config_struct.get_notify_keyspace_events();
+    redis_data.replication.backlog_size = config_struct.get_repl_backlog_size();
 
-            stream
-                .write_all(b"*1\r\n$4\r\nping\r\n")
-                .await
-                .expect("failed to ping master server");
+    //println!("{:?}", config_struct.config.get("--replicaof").unwrap());
+    // Starting empty over a file that failed to load would overwrite it on the next save.
+    // With appendonly on, the AOF is the more complete of the two and the RDB is ignored.
+    if config_struct.get_appendonly() {
+        let manifest = match aof::load(&mut redis_data, &mut config_struct) {
+            Ok((manifest, message)) => {
+                println!("{}", message);
+                manifest
+            }
+            Err(e) => {
+                eprintln!("Failed to load AOF: {}", e);
+                std::process::exit(1);
+            }
+        };
+        match aof::Aof::open(&config_struct, manifest) {
+            Ok(aof) => redis_data.aof = Some(aof),
+            Err(e) => {
+                eprintln!(
+                    "Can't open the append-only file in {}: {}",
+                    config_struct.get_aof_dir().display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+        // A new AOF gets its base file right away
+        if redis_data.aof.as_ref().is_some_and(|aof| !aof.has_base()) {
+            redis_data.bgrewriteaof(config_struct.get_aof_use_rdb_preamble());
+        }
+    } else {
+        match redis_data.read_from_file(&config_struct) {
+            RedisResponse::OK(message) => println!("{}", message),
+            RedisResponse::Error(e) => {
+                eprintln!("Failed to load RDB file: {}", e);
+                std::process::exit(1);
+            }
         }
-        ReplicationRole::Master => {}
     }
 
-    //println!("{:?}", config_struct.config.get("--replicaof").unwrap());
-    let read_file = redis_data.read_from_file(&config_struct);
-
     //println!("database:");
     //println!("{:?}", redis_data.data);
 
@@ -133,7 +308,7 @@ async fn main() {
     //let mut handles = vec![];
 
     let port = match config_struct.get_port() {
-        Some(port) => port.clone(),
+        Some(port) => port.to_string(),
         None => "6379".to_string(),
     };
 
@@ -145,9 +320,50 @@ async fn main() {
     //let db_instances = Arc::new(RwLock::new(redis_data));
     //let config_settings = Arc::new(RwLock::new(config_struct));
 
+    let master = match redis_data.get_role() {
+        ReplicationRole::Slave => redis_data.get_host_port(),
+        ReplicationRole::Master => (None, None),
+    };
+
     let config_settings = Arc::new(AsyncRwLock::new(config_struct));
     let db_instances = Arc::new(AsyncRwLock::new(redis_data));
 
+    if let (Some(host), Some(port)) = master {
+        let link = task::spawn(replication::replica_link(
+            Arc::clone(&db_instances),
+            Arc::clone(&config_settings),
+            host,
+            port,
+        ));
+        db_instances.write().await.replication.link = Some(link);
+    }
+
+    // Deletes expired keys nobody reads, which is also when their "expired" events fire,
+    // starts background saves when a save point is reached, fsyncs the AOF and pings
+    // replicas
+    let cron_db = Arc::clone(&db_instances);
+    let cron_config = Arc::clone(&config_settings);
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let mut db = cron_db.write().await;
+            let config = cron_config.read().await;
+            // A replica's keys expire with the DELs in its master's stream, and its own
+            // replicas get its master's PINGs with the rest of the stream
+            let is_master = matches!(db.get_role(), ReplicationRole::Master);
+            if is_master {
+                db.active_expire_cycle();
+            }
+            db.flush_propagation();
+            if is_master {
+                db.replication.cron(config.get_repl_ping_replica_period());
+            }
+            db.aof_cron(&config);
+            db.save_cron(&config.get_save_points(), &config.get_rdb_path());
+        }
+    });
+
     loop {
         let (mut socket, _) = listener
             .accept()
@@ -155,8 +371,10 @@ async fn main() {
             .expect("Failed to accept connection");
         let db_instances = Arc::clone(&db_instances);
         let config_settings = Arc::clone(&config_settings);
+        let pubsub = Arc::clone(&pubsub);
         task::spawn(async move {
-            if let Err(e) = event_handler(&mut socket, db_instances, config_settings).await {
+            if let Err(e) = event_handler(&mut socket, db_instances, config_settings, pubsub).await
+            {
                 eprintln!("Failed to handle connection: {}", e);
             };
         });
@@ -187,182 +405,247 @@ async fn event_handler(
     stream: &mut TcpStream,
     db_instances: Arc<AsyncRwLock<RedisData>>,
     config_settings: Arc<AsyncRwLock<RedisConfig>>,
+    pubsub: Arc<Mutex<PubSub>>,
 ) -> tokio::io::Result<()> {
-    let mut buf = [0; 1024];
-    loop {
-        let reader = stream.read(&mut buf).await.unwrap();
+    let (mut client, mut receiver) = ClientState::new();
+    db_instances
+        .write()
+        .await
+        .clients
+        .insert(client.id, client.sender.clone());
+
+    let result = read_commands(
+        stream,
+        &mut client,
+        &mut receiver,
+        &db_instances,
+        &config_settings,
+        &pubsub,
+    )
+    .await;
+
+    let mut db = db_instances.write().await;
+    client.unwatch_all_keys(&mut db);
+    db.tracking.disable(client.id);
+    db.clients.remove(&client.id);
+    db.replication.remove_replica(client.id);
+    commands::unsubscribe_all(&mut pubsub.lock().unwrap(), &mut client);
+    result
+}
 
-        let readable = match str::from_utf8(&buf) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid utf8 {}", e),
-        }
-        .trim_end_matches('\0');
-
-        let command = RedisCommand::parser_receive(&readable)
-            .expect("Error when trying to parse the command");
-
-        if let Some(first_cmd) = command.str_cmd.get(0) {
-            match first_cmd.as_str() {
-                "echo" => {
-                    let final_str = command.format_response_code(None);
-                    stream
-                        .write_all(final_str.as_bytes())
-                        .await
-                        .expect("failed to write to client");
+async fn read_commands(
+    stream: &mut TcpStream,
+    client: &mut ClientState,
+    receiver: &mut UnboundedReceiver<PushMessage>,
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+    pubsub: &Arc<Mutex<PubSub>>,
+) -> tokio::io::Result<()> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        // Wait for either the next command or a message published to this client
+        tokio::select! {
+            reader = stream.read_buf(&mut buf) => {
+                if reader? == 0 {
+                    break;
                 }
-
-                "ping" => {
-                    stream
-                        .write_all(b"+PONG\r\n")
-                        .await
-                        .expect("failed to write to client");
+            }
+            Some(message) = receiver.recv() => {
+                if client.accepts_push(&message) {
+                    stream.write_all(message.encode(client.resp3).as_bytes()).await?;
                 }
+                continue;
+            }
+        }
 
-                "set" => {
-                    let mut db = db_instances.write().await;
-                    let response = db.set_value(&command);
-                    //println!("{:?}", &db.data);
-                    stream
-                        .write_all(response.to_string().as_bytes())
-                        .await
-                        .expect("failed to write to client");
+        // A single read can carry several pipelined commands, or only part of one
+        loop {
+            let (command, used) = match RedisCommand::parser_receive(&buf) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => break,
+                Err(e) => {
+                    let response = RedisResponse::Error(format!("ERR Protocol error: {}", e));
+                    stream.write_all(response.to_string().as_bytes()).await?;
+                    return Ok(());
                 }
+            };
+            buf.advance(used);
 
-                "get" => {
-                    let mut db = db_instances.write().await;
-                    match db.get_value(&command) {
-                        Some(string_return) => {
-                            let parsed_return = command.format_response_code(Some(string_return));
-                            stream
-                                .write_all(parsed_return.as_bytes())
-                                .await
-                                .expect("failed to write to client");
-                        }
-                        None => {
-                            stream
-                                .write_all("$-1\r\n".as_bytes())
-                                .await
-                                .expect("failed to write to client");
-                        }
-                    };
-                }
+            if command.str_cmd.is_empty() {
+                continue;
+            }
+            if command.str_cmd[0].eq_ignore_ascii_case("quit") {
+                stream.write_all(b"+OK\r\n").await?;
+                return Ok(());
+            }
+            // The connection belongs to a replica from now on
+            if command.str_cmd[0].eq_ignore_ascii_case("psync")
+                && command.str_cmd.len() == 3
+                && client.multi_queue.is_none()
+            {
+                return replication::serve_replica(
+                    stream,
+                    client,
+                    &command,
+                    &mut buf,
+                    db_instances,
+                    config_settings,
+                )
+                .await;
+            }
 
-                "config" => {
-                    if let Some(config_cmd) = command.str_cmd.get(1) {
-                        match config_cmd.as_str() {
-                            "get" => {
-                                let config = config_settings.read().await;
-                                let get_string = config.get_config(&command);
-                                println!("{:?}", get_string);
-                                stream
-                                    .write_all(get_string.as_bytes())
-                                    .await
-                                    .expect("failed to write to client");
-                            }
-
-                            "set" => {}
-
-                            _ => {}
-                        }
-                    }
-                }
+            let response =
+                process_command(command, client, db_instances, config_settings, pubsub).await;
+            stream.write_all(response.as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
 
-                "keys" => {
-                    let db = db_instances.read().await;
-                    if let Some(config_cmd) = command.str_cmd.get(1) {
-                        match config_cmd.as_str() {
-                            "*" => {
-                                let lst_of_keys = match db.get_all_keys() {
-                                    Some(vec_str) => vec_str,
-                                    None => {
-                                        let final_str = format!("*0\r\n");
-
-                                        stream
-                                            .write_all(final_str.as_bytes())
-                                            .await
-                                            .expect("failed to write to client");
-                                        return Ok(());
-                                    }
-                                };
-
-                                let base_str = format!("*{}\r\n", lst_of_keys.len());
-
-                                let formatted_item = lst_of_keys
-                                    .iter()
-                                    .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
-                                    .collect::<String>();
-
-                                let final_str = base_str + &formatted_item;
-
-                                stream
-                                    .write_all(final_str.as_bytes())
-                                    .await
-                                    .expect("failed to write to client");
-                            }
-
-                            _ => {}
-                        }
-                    }
-                }
+async fn process_command(
+    command: RedisCommand,
+    client: &mut ClientState,
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+    pubsub: &Arc<Mutex<PubSub>>,
+) -> String {
+    let spec = match commands::validate(&command) {
+        Ok(spec) => spec,
+        Err(e) => {
+            if client.multi_queue.is_some() {
+                client.multi_error = true;
+            }
+            return e.to_string();
+        }
+    };
+
+    if !client.resp3
+        && client.subscription_count() > 0
+        && spec.flags & commands::PUBSUB_CONTEXT == 0
+    {
+        return RedisResponse::Error(format!(
+            "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            spec.name
+        ))
+        .to_string();
+    }
+    if client.multi_queue.is_some() && spec.flags & commands::NO_MULTI != 0 {
+        client.multi_error = true;
+        return RedisResponse::Error("ERR Command not allowed inside a transaction".to_string())
+            .to_string();
+    }
+
+    match spec.name {
+        "multi" => {
+            if client.multi_queue.is_some() {
+                client.multi_error = true;
+                return RedisResponse::Error("ERR MULTI calls can not be nested".to_string())
+                    .to_string();
+            }
+            client.multi_queue = Some(Vec::new());
+            RedisResponse::OK("OK".to_string()).to_string()
+        }
 
-                "info" => {
-                    let config = config_settings.read().await;
-
-                    let mut db_info: HashMap<String, Vec<String>> = HashMap::new();
-                    let mut lst_info: Vec<String> = vec![];
-
-                    let role = match config.get_replicaof() {
-                        Some(_) => "role:slave".to_string(),
-                        None => "role:master".to_string(),
-                    };
-
-                    let master_replid =
-                        "master_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string();
-                    let master_repl_offset = "master_repl_offset:0".to_string();
-
-                    db_info.insert(
-                        "replication".to_string(),
-                        vec![role, master_replid, master_repl_offset],
-                    );
-
-                    let lst_info = match command.str_cmd.get(1).map(|s| s.as_str()) {
-                        Some("replication") => {
-                            // Return only replication info
-                            db_info.get("replication").cloned().unwrap_or_default()
-                        }
-                        Some(_) => {
-                            eprintln!("Command not found");
-                            Vec::new()
-                        }
-                        None => {
-                            // Return all info
-                            db_info.values().flatten().cloned().collect()
-                        }
-                    };
-                    //println!("{:?}", lst_info);
-                    let total_response = lst_info
-                        .iter()
-                        .map(|item| format!("{}\r\n", item))
-                        .collect::<String>();
-
-                    let total_length = total_response.len();
-
-                    let format_string = format!("${}\r\n{}\r\n", total_length, total_response);
-
-                    //println!("{:?}", format_string);
-                    stream
-                        .write_all(format_string.as_bytes())
-                        .await
-                        .expect("failed to write to client");
+        "exec" => {
+            let queue = match client.multi_queue.take() {
+                Some(queue) => queue,
+                None => {
+                    return RedisResponse::Error("ERR EXEC without MULTI".to_string()).to_string()
                 }
+            };
+            // Holding the write lock for the whole batch keeps other clients out until
+            // every queued command has run
+            let mut db = db_instances.write().await;
+            let watch_dirty = db.is_watch_dirty(client.id, &client.watched_keys);
+            client.unwatch_all_keys(&mut db);
+
+            if std::mem::take(&mut client.multi_error) {
+                return RedisResponse::Error(
+                    "EXECABORT Transaction discarded because of previous errors.".to_string(),
+                )
+                .to_string();
+            }
+            if watch_dirty {
+                return "*-1\r\n".to_string();
+            }
 
-                _ => {}
+            let mut config = config_settings.write().await;
+            let mut response = format!("*{}\r\n", queue.len());
+            for queued in &queue {
+                response += &commands::execute_command(&mut db, &mut config, client, queued);
             }
+            db.flush_propagation();
+            // CLIENT CACHING before MULTI covers the whole transaction
+            client.caching = None;
+            response
         }
 
-        if reader == 0 {
-            break;
+        "discard" => {
+            if client.multi_queue.take().is_none() {
+                return RedisResponse::Error("ERR DISCARD without MULTI".to_string()).to_string();
+            }
+            client.multi_error = false;
+            let mut db = db_instances.write().await;
+            client.unwatch_all_keys(&mut db);
+            RedisResponse::OK("OK".to_string()).to_string()
+        }
+
+        "watch" => {
+            if client.multi_queue.is_some() {
+                client.multi_error = true;
+                return RedisResponse::Error("ERR WATCH inside MULTI is not allowed".to_string())
+                    .to_string();
+            }
+            let mut db = db_instances.write().await;
+            for key in &command.str_cmd[1..] {
+                let index = client.selected_db;
+                if client
+                    .watched_keys
+                    .iter()
+                    .any(|(i, k, _)| *i == index && k == key)
+                {
+                    continue;
+                }
+                let expired = db.watch_key(client.id, index, key);
+                client.watched_keys.push((index, key.clone(), expired));
+            }
+            RedisResponse::OK("OK".to_string()).to_string()
+        }
+
+        "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ssubscribe"
+        | "sunsubscribe" => commands::pubsub_command(&mut pubsub.lock().unwrap(), client, &command),
+
+        "wait" | "waitaof" if client.multi_queue.is_none() => {
+            replication::wait(db_instances, &command).await
+        }
+
+        "replicaof" | "slaveof" => {
+            replication::replicaof(db_instances, config_settings, &command).await
+        }
+
+        "unwatch" if client.multi_queue.is_none() => {
+            let mut db = db_instances.write().await;
+            client.unwatch_all_keys(&mut db);
+            RedisResponse::OK("OK".to_string()).to_string()
+        }
+
+        _ => {
+            if let Some(queue) = client.multi_queue.as_mut() {
+                queue.push(command);
+                return RedisResponse::OK("QUEUED".to_string()).to_string();
+            }
+
+            let mut db = db_instances.write().await;
+            let mut config = config_settings.write().await;
+            let response = commands::execute_command(&mut db, &mut config, client, &command);
+            db.flush_propagation();
+
+            let is_caching =
+                spec.name == "client" && command.str_cmd[1].eq_ignore_ascii_case("caching");
+            if !is_caching {
+                client.caching = None;
+            }
+            response
         }
     }
-    Ok(())
 }
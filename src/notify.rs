@@ -0,0 +1,68 @@
+// Classes of keyspace notifications, selected with the notify-keyspace-events setting
+pub const KEYSPACE: u32 = 1 << 0; // K: __keyspace@<db>__:<key> channel
+pub const KEYEVENT: u32 = 1 << 1; // E: __keyevent@<db>__:<event> channel
+pub const GENERIC: u32 = 1 << 2; // g: DEL, EXPIRE, ...
+pub const STRING: u32 = 1 << 3; // $
+pub const LIST: u32 = 1 << 4; // l
+pub const SET: u32 = 1 << 5; // s
+pub const HASH: u32 = 1 << 6; // h
+pub const ZSET: u32 = 1 << 7; // z
+pub const EXPIRED: u32 = 1 << 8; // x
+pub const EVICTED: u32 = 1 << 9; // e, accepted but never fired without maxmemory
+pub const STREAM: u32 = 1 << 10; // t
+pub const KEY_MISS: u32 = 1 << 11; // m, not part of A
+pub const NEW: u32 = 1 << 12; // n, not part of A
+pub const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+const CLASSES: [(char, u32); 9] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+];
+
+// "KEA" -> flags, None when the string holds an unknown class
+pub fn parse_flags(classes: &str) -> Option<u32> {
+    let mut flags = 0;
+    for c in classes.chars() {
+        flags |= match c {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            _ => CLASSES.iter().find(|(class, _)| *class == c)?.1,
+        };
+    }
+    Some(flags)
+}
+
+// Canonical form reported by CONFIG GET, e.g. "AKE" or "gxE"
+pub fn flags_to_string(flags: u32) -> String {
+    let mut classes = String::new();
+    if flags & ALL == ALL {
+        classes.push('A');
+    } else {
+        for (class, flag) in CLASSES {
+            if flags & flag != 0 {
+                classes.push(class);
+            }
+        }
+    }
+    for (class, flag) in [
+        ('K', KEYSPACE),
+        ('E', KEYEVENT),
+        ('m', KEY_MISS),
+        ('n', NEW),
+    ] {
+        if flags & flag != 0 {
+            classes.push(class);
+        }
+    }
+    classes
+}
@@ -0,0 +1,222 @@
+use crate::glob;
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
+
+// Messages pushed to a connection outside of the request/response flow
+#[derive(Clone)]
+pub enum PushMessage {
+    Message {
+        channel: String,
+        payload: String,
+    },
+    PMessage {
+        pattern: String,
+        channel: String,
+        payload: String,
+    },
+    SMessage {
+        channel: String,
+        payload: String,
+    },
+    // Client-side caching: these keys changed, None meaning everything was flushed
+    Invalidate {
+        keys: Option<Vec<String>>,
+    },
+}
+
+impl PushMessage {
+    // RESP3 connections get push frames. On RESP2 an invalidation can only travel as a
+    // message on __redis__:invalidate, which read_commands drops unless the connection
+    // is in subscribed mode.
+    pub fn encode(&self, resp3: bool) -> String {
+        let kind = if resp3 { '>' } else { '*' };
+
+        if let PushMessage::Invalidate { keys } = self {
+            let keys = match keys {
+                Some(keys) => {
+                    let formatted_item = keys
+                        .iter()
+                        .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+                        .collect::<String>();
+                    format!("*{}\r\n{}", keys.len(), formatted_item)
+                }
+                None if resp3 => "_\r\n".to_string(),
+                None => "*-1\r\n".to_string(),
+            };
+            return if resp3 {
+                format!(">2\r\n$10\r\ninvalidate\r\n{}", keys)
+            } else {
+                format!(
+                    "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n{}",
+                    keys
+                )
+            };
+        }
+
+        let items = match self {
+            PushMessage::Message { channel, payload } => vec!["message", channel, payload],
+            PushMessage::PMessage {
+                pattern,
+                channel,
+                payload,
+            } => vec!["pmessage", pattern, channel, payload],
+            PushMessage::SMessage { channel, payload } => vec!["smessage", channel, payload],
+            PushMessage::Invalidate { .. } => unreachable!(),
+        };
+
+        let formatted_item = items
+            .iter()
+            .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+            .collect::<String>();
+
+        format!("{}{}\r\n{}", kind, items.len(), formatted_item)
+    }
+}
+
+// Unbounded on purpose: PUBLISH never waits for a slow subscriber to drain its socket
+pub type ClientSender = UnboundedSender<PushMessage>;
+
+type Subscriptions = HashMap<String, HashMap<u64, ClientSender>>;
+
+// Channel, pattern and shard channel subscriptions of every connection. Shard channels
+// are their own namespace: SPUBLISH never reaches SUBSCRIBE/PSUBSCRIBE clients and
+// PUBLISH never reaches SSUBSCRIBE clients.
+pub struct PubSub {
+    channels: Subscriptions,
+    patterns: Subscriptions,
+    shard_channels: Subscriptions,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            shard_channels: HashMap::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, client_id: u64, sender: &ClientSender, channel: &str) {
+        add_subscription(&mut self.channels, client_id, sender, channel);
+    }
+
+    pub fn unsubscribe(&mut self, client_id: u64, channel: &str) {
+        remove_subscription(&mut self.channels, client_id, channel);
+    }
+
+    pub fn psubscribe(&mut self, client_id: u64, sender: &ClientSender, pattern: &str) {
+        add_subscription(&mut self.patterns, client_id, sender, pattern);
+    }
+
+    pub fn punsubscribe(&mut self, client_id: u64, pattern: &str) {
+        remove_subscription(&mut self.patterns, client_id, pattern);
+    }
+
+    pub fn ssubscribe(&mut self, client_id: u64, sender: &ClientSender, channel: &str) {
+        add_subscription(&mut self.shard_channels, client_id, sender, channel);
+    }
+
+    pub fn sunsubscribe(&mut self, client_id: u64, channel: &str) {
+        remove_subscription(&mut self.shard_channels, client_id, channel);
+    }
+
+    // Returns the number of clients the message was handed to
+    pub fn publish(&self, channel: &str, payload: &str) -> usize {
+        let mut receivers = 0;
+
+        if let Some(clients) = self.channels.get(channel) {
+            let message = PushMessage::Message {
+                channel: channel.to_string(),
+                payload: payload.to_string(),
+            };
+            for sender in clients.values() {
+                if sender.send(message.clone()).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        for (pattern, clients) in &self.patterns {
+            if !glob::string_match(pattern, channel, false) {
+                continue;
+            }
+            let message = PushMessage::PMessage {
+                pattern: pattern.clone(),
+                channel: channel.to_string(),
+                payload: payload.to_string(),
+            };
+            for sender in clients.values() {
+                if sender.send(message.clone()).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        receivers
+    }
+
+    // Shard channels have no pattern subscriptions
+    pub fn spublish(&self, channel: &str, payload: &str) -> usize {
+        let message = PushMessage::SMessage {
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        };
+        self.shard_channels.get(channel).map_or(0, |clients| {
+            clients
+                .values()
+                .filter(|sender| sender.send(message.clone()).is_ok())
+                .count()
+        })
+    }
+
+    // PUBSUB CHANNELS [pattern]
+    pub fn active_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        matching_names(&self.channels, pattern)
+    }
+
+    // PUBSUB SHARDCHANNELS [pattern]
+    pub fn active_shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        matching_names(&self.shard_channels, pattern)
+    }
+
+    // PUBSUB NUMSUB channel
+    pub fn num_subscribers(&self, channel: &str) -> usize {
+        self.channels
+            .get(channel)
+            .map_or(0, |clients| clients.len())
+    }
+
+    // PUBSUB SHARDNUMSUB channel
+    pub fn num_shard_subscribers(&self, channel: &str) -> usize {
+        self.shard_channels
+            .get(channel)
+            .map_or(0, |clients| clients.len())
+    }
+
+    // PUBSUB NUMPAT, counted per pattern like Redis does rather than per subscription
+    pub fn num_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+fn add_subscription(map: &mut Subscriptions, client_id: u64, sender: &ClientSender, name: &str) {
+    map.entry(name.to_string())
+        .or_default()
+        .insert(client_id, sender.clone());
+}
+
+fn remove_subscription(map: &mut Subscriptions, client_id: u64, name: &str) {
+    if let Some(clients) = map.get_mut(name) {
+        clients.remove(&client_id);
+        if clients.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+fn matching_names(map: &Subscriptions, pattern: Option<&str>) -> Vec<String> {
+    map.keys()
+        .filter(|name| pattern.is_none_or(|p| glob::string_match(p, name, false)))
+        .cloned()
+        .collect()
+}
@@ -1,25 +1,153 @@
-use crate::redis_config;
+use crate::aof::Aof;
+use crate::crc64;
+use crate::db::{Database, RedisValue, ScanMap, Stream, StreamConsumer, StreamGroup, StreamId};
+use crate::lzf;
+use crate::notify;
+use crate::pubsub::{ClientSender, PubSub, PushMessage};
+use crate::rdb_writer::{self, DbSnapshot};
 use crate::redis_config::RedisConfig;
-use crate::RedisCommand;
+use crate::replication::Replication;
+use crate::tracking::Tracking;
 use core::panic;
 use core::str;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::fs::read;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use std::u64;
+
+// Value types, numbered as in Redis' rdb.h. 8 was never used.
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// Newest format this loader understands (Redis 7.4)
+const RDB_VERSION: u32 = 12;
+
+// Opcodes that can appear where a value type is expected
+const RDB_OPCODE_SLOT_INFO: u8 = 244;
+const RDB_OPCODE_FUNCTION2: u8 = 245;
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 246;
+const RDB_OPCODE_MODULE_AUX: u8 = 247;
+const RDB_OPCODE_IDLE: u8 = 248;
+const RDB_OPCODE_FREQ: u8 = 249;
+const RDB_OPCODE_AUX: u8 = 250;
+const RDB_OPCODE_RESIZEDB: u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+const RDB_OPCODE_EXPIRETIME: u8 = 253;
+const RDB_OPCODE_SELECTDB: u8 = 254;
+const RDB_OPCODE_EOF: u8 = 255;
+
+const RDB_32BITLEN: u8 = 0x80;
+const RDB_64BITLEN: u8 = 0x81;
+
+// Special string encodings, the low 6 bits of a 0b11 length byte
+const RDB_ENC_INT8: usize = 0;
+const RDB_ENC_INT16: usize = 1;
+const RDB_ENC_INT32: usize = 2;
+const RDB_ENC_LZF: usize = 3;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: usize = 2;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+// Why an RDB file could not be loaded, and where
+pub struct RdbError {
+    pub offset: usize,
+    pub kind: RdbErrorKind,
+}
+
+pub enum RdbErrorKind {
+    BadMagic,
+    UnsupportedVersion(String),
+    UnexpectedEof,
+    InvalidLength(u8),
+    UnknownEncoding(usize),
+    UnknownType(u8),
+    Unsupported(&'static str),
+    Corrupt(&'static str),
+    DbIndexOutOfRange(usize),
+    ChecksumMismatch { expected: u64, computed: u64 },
+}
+
+impl RdbError {
+    fn new(offset: usize, kind: RdbErrorKind) -> Self {
+        Self { offset, kind }
+    }
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RdbErrorKind::BadMagic => write!(f, "not an RDB file")?,
+            RdbErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported RDB version '{}'", version)?
+            }
+            RdbErrorKind::UnexpectedEof => write!(f, "unexpected end of file")?,
+            RdbErrorKind::InvalidLength(byte) => write!(f, "invalid length 0x{:02x}", byte)?,
+            RdbErrorKind::UnknownEncoding(encoding) => {
+                write!(f, "unknown string encoding {}", encoding)?
+            }
+            RdbErrorKind::UnknownType(value_type) => {
+                write!(f, "unknown value type {}", value_type)?
+            }
+            RdbErrorKind::Unsupported(what) => write!(f, "{} are not supported", what)?,
+            RdbErrorKind::Corrupt(what) => write!(f, "corrupt {}", what)?,
+            RdbErrorKind::DbIndexOutOfRange(index) => {
+                write!(f, "database {} is out of range", index)?
+            }
+            RdbErrorKind::ChecksumMismatch { expected, computed } => write!(
+                f,
+                "checksum mismatch, expected {:016x} but computed {:016x}",
+                expected, computed
+            )?,
+        }
+        write!(f, " at byte {}", self.offset)
+    }
+}
+
+// What an RDB file says about itself besides the keys
+pub struct RdbMetadata {
+    pub version: u32,
+    // AUX fields such as redis-ver, ctime or used-mem, in file order
+    pub aux: Vec<(String, String)>,
+}
 
 pub enum RedisResponse {
     OK(String),
     Error(String),
 }
 
-impl RedisResponse {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for RedisResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            RedisResponse::OK(message) => format!("+{}\r\n", message),
-            RedisResponse::Error(message) => format!("-{}\r\n", message),
+            RedisResponse::OK(message) => write!(f, "+{}\r\n", message),
+            RedisResponse::Error(message) => write!(f, "-{}\r\n", message),
         }
     }
 }
@@ -30,254 +158,1024 @@ pub enum ReplicationRole {
 }
 
 pub struct RedisData {
-    pub data: HashMap<String, String>,
-    pub expiry: HashMap<String, std::time::SystemTime>,
+    pub databases: Vec<Database>,
+
+    // WATCHed keys: (db index, key) -> ids of the clients watching it
+    watched_keys: HashMap<(usize, String), HashSet<u64>>,
+    // Clients whose watched keys were touched, their next EXEC fails
+    dirty_cas: HashSet<u64>,
+
+    // Shared with the connections, which subscribe through it without taking this lock
+    pub pubsub: Arc<Mutex<PubSub>>,
+    // Parsed notify-keyspace-events, see notify.rs
+    pub notify_keyspace_events: u32,
+
+    // Every connected client, by id, for messages that target a specific connection
+    pub clients: HashMap<u64, ClientSender>,
+    pub tracking: Tracking,
+    // Client whose command is running, so NOLOOP tracking can skip its own writes
+    pub current_client: Option<u64>,
+
+    // Changes since the last successful save, checked against the save points
+    pub dirty: u64,
+    // Database the active expire cycle continues with when it ran out of time
+    expire_db: usize,
+    // Shared with the thread writing a background save
+    pub save_status: Arc<Mutex<SaveStatus>>,
+
+    // Writes of the running command, as (db index, argv), waiting to be appended to the AOF
+    // and streamed to replicas
+    pending_propagation: Vec<(usize, Vec<String>)>,
+    // Open while appendonly is on
+    pub aof: Option<Aof>,
 
     pub replication_role: ReplicationRole,
+    pub replication: Replication,
     pub host: Option<String>,
     pub port: Option<u16>,
 }
 
+pub struct SaveStatus {
+    pub bgsave_in_progress: bool,
+    // Unix time of the last successful save, LASTSAVE
+    pub lastsave: u64,
+    pub last_bgsave_ok: bool,
+    // Unix time the last background save was started, failed ones are retried after a delay
+    pub last_bgsave_try: u64,
+    // Changes written by a finished background save, taken off dirty by save_cron
+    pub saved_changes: u64,
+}
+
+// Seconds to wait after a failed background save before a save point may start another
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
 impl RedisData {
-    pub fn init_db(role: ReplicationRole, host: Option<String>, port: Option<u16>) -> Self {
+    pub fn init_db(
+        databases: usize,
+        pubsub: Arc<Mutex<PubSub>>,
+        role: ReplicationRole,
+        host: Option<String>,
+        port: Option<u16>,
+    ) -> Self {
         Self {
-            data: HashMap::new(),
-            expiry: HashMap::new(),
+            databases: (0..databases).map(|_| Database::new()).collect(),
+            watched_keys: HashMap::new(),
+            dirty_cas: HashSet::new(),
+            pubsub,
+            notify_keyspace_events: 0,
+            clients: HashMap::new(),
+            tracking: Tracking::new(),
+            current_client: None,
+            dirty: 0,
+            expire_db: 0,
+            save_status: Arc::new(Mutex::new(SaveStatus {
+                bgsave_in_progress: false,
+                lastsave: unix_time(),
+                last_bgsave_ok: true,
+                last_bgsave_try: 0,
+                saved_changes: 0,
+            })),
+            pending_propagation: Vec::new(),
+            aof: None,
             replication_role: role,
-            host: host,
-            port: port,
+            replication: Replication::new(),
+            host,
+            port,
         }
     }
 
-    pub fn decode_length(&self, content: &Vec<u8>, cursor: &mut usize) -> Option<usize> {
-        let first_byte = content[*cursor];
-        *cursor += 1;
-        //return Some(first_byte as usize);
+    pub fn db(&self, index: usize) -> &Database {
+        &self.databases[index]
+    }
 
-        match first_byte >> 6 {
-            0b00 => Some((first_byte & 0x3F) as usize), // 6-bit length
-            0b01 => {
-                if *cursor < content.len() {
-                    let second_byte = content[*cursor];
-                    *cursor += 1;
-                    Some((((first_byte & 0x3F) as usize) << 8) | (second_byte as usize))
-                // 14-bit length
-                } else {
-                    None
-                }
+    pub fn db_mut(&mut self, index: usize) -> &mut Database {
+        &mut self.databases[index]
+    }
+
+    // Parses a SELECT / SWAPDB index argument against the configured number of databases
+    pub fn parse_db_index(&self, arg: &str) -> Result<usize, RedisResponse> {
+        match arg.parse::<i64>() {
+            Ok(index) if index >= 0 && (index as usize) < self.databases.len() => {
+                Ok(index as usize)
             }
-            0b10 => {
-                if *cursor + 3 < content.len() {
-                    let mut length_bytes = [0u8; 4];
-                    length_bytes.copy_from_slice(&content[*cursor..*cursor + 4]);
-                    *cursor += 4;
-                    Some(u32::from_be_bytes(length_bytes) as usize) // 32-bit length
-                } else {
-                    None
+            Ok(_) => Err(RedisResponse::Error(
+                "ERR DB index is out of range".to_string(),
+            )),
+            Err(_) => Err(RedisResponse::Error(
+                "ERR value is not an integer or out of range".to_string(),
+            )),
+        }
+    }
+
+    pub fn swap_db(&mut self, first: usize, second: usize) {
+        // Watches stay with the db index, so a watched key is touched if it exists on
+        // either side of the swap
+        self.touch_watched_keys_in_db(first, Some(second));
+        self.touch_watched_keys_in_db(second, Some(first));
+        self.databases.swap(first, second);
+        self.dirty += 1;
+    }
+
+    // Empties one database. With lazy set the old contents are dropped on a background
+    // thread, so freeing a large keyspace does not hold up the caller.
+    pub fn flush_db(&mut self, index: usize, lazy: bool) {
+        self.touch_watched_keys_in_db(index, None);
+        self.invalidate_tracked_keys(None);
+        let old = std::mem::replace(&mut self.databases[index], Database::new());
+        self.dirty += old.data.len() as u64;
+        if lazy {
+            thread::spawn(move || drop(old));
+        }
+    }
+
+    pub fn flush_all(&mut self, lazy: bool) {
+        for index in 0..self.databases.len() {
+            self.touch_watched_keys_in_db(index, None);
+        }
+        self.invalidate_tracked_keys(None);
+        let fresh = (0..self.databases.len()).map(|_| Database::new()).collect();
+        let old = std::mem::replace(&mut self.databases, fresh);
+        self.dirty += old.iter().map(|db| db.data.len() as u64).sum::<u64>();
+        if lazy {
+            thread::spawn(move || drop(old));
+        }
+    }
+
+    // Deletes the key if its TTL has passed. Every command touching a key goes through
+    // here first, so watchers see the expiry as a modification. A replica leaves the key
+    // in place and waits for the master's DEL; reads still treat it as missing.
+    pub fn expire_if_needed(&mut self, index: usize, key: &str) {
+        if let ReplicationRole::Slave = self.replication_role {
+            return;
+        }
+        if self.databases[index].is_expired(key, SystemTime::now()) {
+            self.delete_expired_key(index, key);
+        }
+    }
+
+    // Logged as an explicit DEL, so a reload does not depend on the clock
+    fn delete_expired_key(&mut self, index: usize, key: &str) {
+        self.databases[index].remove_key(key);
+        self.signal_modified_key(index, key);
+        self.notify_keyspace_event(notify::EXPIRED, "expired", key, index);
+        self.propagate(index, vec!["DEL".to_string(), key.to_string()]);
+    }
+
+    // Background counterpart of expire_if_needed for keys nobody reads anymore. Like Redis
+    // it samples ACTIVE_EXPIRE_KEYS_PER_LOOP keys with a TTL at a time and keeps going in
+    // a database only while more than a quarter of the sample had expired, within a time
+    // budget, so the lock is never held for a walk over every volatile key.
+    pub fn active_expire_cycle(&mut self) {
+        const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+        const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+        let started = Instant::now();
+        let now = SystemTime::now();
+
+        for _ in 0..self.databases.len() {
+            let index = self.expire_db % self.databases.len();
+            loop {
+                let sampled = self.databases[index].sample_volatile(ACTIVE_EXPIRE_KEYS_PER_LOOP);
+                let expired = sampled
+                    .iter()
+                    .filter(|key| self.databases[index].is_expired(key, now))
+                    .cloned()
+                    .collect::<Vec<String>>();
+                for key in &expired {
+                    self.delete_expired_key(index, key);
+                }
+
+                if started.elapsed() >= ACTIVE_EXPIRE_TIME_LIMIT {
+                    return;
+                }
+                if expired.len() * 4 <= sampled.len() {
+                    break;
                 }
             }
-            0b11 => {
-                // Special encoding for integers or other data, handle separately
-                Some((first_byte & 0x3F) as usize) // You may need to modify for specific cases
+            self.expire_db = index + 1;
+        }
+    }
+
+    // Publishes __keyspace@<db>__:<key> and/or __keyevent@<db>__:<event>, depending on
+    // which classes notify-keyspace-events enables
+    pub fn notify_keyspace_event(&self, class: u32, event: &str, key: &str, index: usize) {
+        let flags = self.notify_keyspace_events;
+        if flags & class == 0 {
+            return;
+        }
+
+        let pubsub = self.pubsub.lock().unwrap();
+        if flags & notify::KEYSPACE != 0 {
+            pubsub.publish(&format!("__keyspace@{}__:{}", index, key), event);
+        }
+        if flags & notify::KEYEVENT != 0 {
+            pubsub.publish(&format!("__keyevent@{}__:{}", index, event), key);
+        }
+    }
+
+    // Called after every write to a key
+    pub fn signal_modified_key(&mut self, index: usize, key: &str) {
+        if let Some(clients) = self.watched_keys.get(&(index, key.to_string())) {
+            self.dirty_cas.extend(clients);
+        }
+        self.invalidate_tracked_keys(Some(key));
+        self.dirty += 1;
+    }
+
+    // Queues a command that changed the dataset. Commands are logged in the form that
+    // replays to the same result, e.g. a relative TTL as an absolute one.
+    pub fn propagate(&mut self, index: usize, argv: Vec<String>) {
+        self.pending_propagation.push((index, argv));
+    }
+
+    // Hands everything propagated since the last call to the AOF and the replicas. Called
+    // once the command (or the whole EXEC) has run and before its reply is sent.
+    pub fn flush_propagation(&mut self) {
+        let commands = std::mem::take(&mut self.pending_propagation);
+        if commands.is_empty() {
+            return;
+        }
+        if let ReplicationRole::Master = self.replication_role {
+            // With AOF on the offset counts even without replicas, WAITAOF waits on it
+            self.replication.feed(&commands, self.aof.is_some());
+        }
+        if let Some(aof) = self.aof.as_mut() {
+            aof.feed(&commands);
+            aof.reploff = self.replication.offset;
+        }
+    }
+
+    // On a replica, once a command of the master's stream was applied. The bytes go on
+    // to the backlog as they are.
+    pub fn feed_master_stream(&mut self, bytes: &[u8]) {
+        self.replication.feed_raw(bytes);
+        if let Some(aof) = self.aof.as_mut() {
+            aof.reploff = self.replication.offset;
+        }
+    }
+
+    // Tells tracking clients that a key changed, or with None that everything was flushed
+    fn invalidate_tracked_keys(&mut self, key: Option<&str>) {
+        let targets = match key {
+            Some(key) => self.tracking.invalidate_key(key, self.current_client),
+            None => self.tracking.invalidate_all(),
+        };
+
+        let message = PushMessage::Invalidate {
+            keys: key.map(|key| vec![key.to_string()]),
+        };
+        for client_id in targets {
+            let recipient = self.tracking.recipient(client_id);
+            if let Some(sender) = self.clients.get(&recipient) {
+                let _ = sender.send(message.clone());
             }
-            _ => None,
         }
     }
 
-    pub fn read_values(&self, content: &Vec<u8>, cursor: &mut usize) -> Option<(String, String)> {
-        match content[*cursor] {
-            0x00 => {
-                // string
-                *cursor += 1;
-                let keys = self
-                    .read_string(content, cursor)
-                    .expect("Error reading the keys");
-                let values = self
-                    .read_string(content, cursor)
-                    .expect("Error reading the values");
-                Some((keys, values))
+    // Marks the watchers of keys in a db that is about to be emptied or swapped. Only keys
+    // that actually exist there (or in the db taking its place) count as modified.
+    fn touch_watched_keys_in_db(&mut self, index: usize, replaced_with: Option<usize>) {
+        for ((db_index, key), clients) in &self.watched_keys {
+            if *db_index != index {
+                continue;
+            }
+            let exists = self.databases[index].data.contains_key(key)
+                || replaced_with.is_some_and(|other| self.databases[other].data.contains_key(key));
+            if exists {
+                self.dirty_cas.extend(clients);
             }
-            // other values to be implemented
-            _ => None,
         }
     }
 
-    pub fn read_string(&self, content: &Vec<u8>, cursor: &mut usize) -> Option<String> {
-        let length = self.decode_length(content, cursor)?;
-        if *cursor + length <= content.len() {
-            let string_data = &content[*cursor..*cursor + length];
-            *cursor += length;
-            Some(String::from_utf8_lossy(string_data).to_string())
-        } else {
-            None
-        }
-    }
-
-    pub fn parse_db_key_val(&mut self, content: &Vec<u8>, cursor: &mut usize) {
-        *cursor += 1;
-        let size_table_all = content[*cursor];
-        println!("Table size all: {}", size_table_all);
-
-        *cursor += 1;
-        let size_table_expired = content[*cursor];
-        println!("Table size expired: {}", size_table_expired);
-
-        *cursor += 1;
-        //let tmp_cursor = cursor.clone();
-        for _ in 0..size_table_all {
-            match content[*cursor] {
-                0xFC => {
-                    *cursor += 1; // move 1 step toward the time stamp
-                    let mut timestamp = content[*cursor..*cursor + 8].to_vec();
-                    *cursor += 8; //skip the timestamp
-                    timestamp.reverse();
-                    let timestamp_ms = u64::from_be_bytes(timestamp.try_into().unwrap());
-                    let time = UNIX_EPOCH + Duration::from_millis(timestamp_ms);
-                    let (keys, values) = self
-                        .read_values(content, cursor)
-                        .expect("Error reading the keys and values");
-                    self.data.insert(keys.clone(), values.clone());
-                    self.expiry.insert(keys.clone(), time);
-                    println!(
-                        "Key: {}, Value: {}, timestamp: {}",
-                        keys, values, timestamp_ms
-                    );
+    // Returns whether the key was already expired, EXEC compares that with its state then
+    pub fn watch_key(&mut self, client_id: u64, index: usize, key: &str) -> bool {
+        self.watched_keys
+            .entry((index, key.to_string()))
+            .or_default()
+            .insert(client_id);
+        self.databases[index].is_expired(key, SystemTime::now())
+    }
+
+    pub fn unwatch_key(&mut self, client_id: u64, index: usize, key: &str) {
+        let watch = (index, key.to_string());
+        if let Some(clients) = self.watched_keys.get_mut(&watch) {
+            clients.remove(&client_id);
+            if clients.is_empty() {
+                self.watched_keys.remove(&watch);
+            }
+        }
+        self.dirty_cas.remove(&client_id);
+    }
+
+    // Whether EXEC has to fail for this client: a watched key was written, deleted or
+    // flushed, or it expired after WATCH without anyone accessing it
+    pub fn is_watch_dirty(&self, client_id: u64, watched: &[(usize, String, bool)]) -> bool {
+        let now = SystemTime::now();
+        self.dirty_cas.contains(&client_id)
+            || watched.iter().any(|(index, key, expired_at_watch)| {
+                !expired_at_watch && self.databases[*index].is_expired(key, now)
+            })
+    }
+
+    // A length, the 0b11 special string encodings are rejected here
+    pub fn decode_length(content: &[u8], cursor: &mut usize) -> Result<usize, RdbError> {
+        let offset = *cursor;
+        match Self::decode_length_or_encoding(content, cursor)? {
+            (length, false) => Ok(length),
+            (_, true) => Err(RdbError::new(
+                offset,
+                RdbErrorKind::InvalidLength(content[offset]),
+            )),
+        }
+    }
+
+    // The first two bits pick the format:
+    //   00 6 bit length, 01 14 bit length, 10 32 bit (0x80) or 64 bit (0x81) big endian
+    //   length, 11 special string encoding given by the remaining 6 bits
+    // The bool is true for the special encodings.
+    fn decode_length_or_encoding(
+        content: &[u8],
+        cursor: &mut usize,
+    ) -> Result<(usize, bool), RdbError> {
+        let offset = *cursor;
+        let first_byte = read_u8(content, cursor)?;
+
+        match first_byte >> 6 {
+            0b00 => Ok(((first_byte & 0x3F) as usize, false)),
+            0b01 => {
+                let second_byte = read_u8(content, cursor)?;
+                Ok((
+                    (((first_byte & 0x3F) as usize) << 8) | second_byte as usize,
+                    false,
+                ))
+            }
+            0b10 => {
+                let length = match first_byte {
+                    RDB_32BITLEN => u32::from_be_bytes(read_array(content, cursor)?) as u64,
+                    RDB_64BITLEN => u64::from_be_bytes(read_array(content, cursor)?),
+                    _ => {
+                        return Err(RdbError::new(
+                            offset,
+                            RdbErrorKind::InvalidLength(first_byte),
+                        ))
+                    }
+                };
+                let length = usize::try_from(length)
+                    .map_err(|_| RdbError::new(offset, RdbErrorKind::InvalidLength(first_byte)))?;
+                Ok((length, false))
+            }
+            _ => Ok(((first_byte & 0x3F) as usize, true)),
+        }
+    }
+
+    // Value of the given type, the key has already been read. Type errors are reported at
+    // type_offset, where the type byte is.
+    fn read_object(
+        value_type: u8,
+        type_offset: usize,
+        content: &[u8],
+        cursor: &mut usize,
+    ) -> Result<RedisValue, RdbError> {
+        let offset = *cursor;
+        let corrupt = |what| RdbError::new(offset, RdbErrorKind::Corrupt(what));
+
+        let value = match value_type {
+            RDB_TYPE_STRING => RedisValue::String(Self::read_string(content, cursor)?),
+
+            RDB_TYPE_LIST => {
+                let len = Self::decode_length(content, cursor)?;
+                let list = (0..len)
+                    .map(|_| Self::read_string(content, cursor))
+                    .collect::<Result<VecDeque<String>, RdbError>>()?;
+                RedisValue::List(list)
+            }
+
+            RDB_TYPE_SET => {
+                let len = Self::decode_length(content, cursor)?;
+                let set = (0..len)
+                    .map(|_| Self::read_string(content, cursor))
+                    .collect::<Result<ScanMap<()>, RdbError>>()?;
+                RedisValue::Set(set)
+            }
+
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = Self::decode_length(content, cursor)?;
+                let mut zset = ScanMap::new();
+                for _ in 0..len {
+                    let member = Self::read_string(content, cursor)?;
+                    let score = if value_type == RDB_TYPE_ZSET_2 {
+                        f64::from_le_bytes(read_array(content, cursor)?)
+                    } else {
+                        Self::read_string_double(content, cursor)?
+                    };
+                    zset.insert(member, score);
                 }
+                RedisValue::ZSet(zset)
+            }
 
-                0xFD => {
-                    *cursor += 1;
-                    let mut timestamp = content[*cursor..*cursor + 4].to_vec();
-                    *cursor += 8;
-                    timestamp.reverse();
-                    let timestamp_sec = u64::from_be_bytes(timestamp.try_into().unwrap());
-                    let time = UNIX_EPOCH + Duration::from_secs(timestamp_sec);
-                    println!("timestamp: {}", timestamp_sec);
-                    let (keys, values) = self
-                        .read_values(content, cursor)
-                        .expect("Error reading the keys and values");
-
-                    self.data.insert(keys.clone(), values.clone());
-                    self.expiry.insert(keys.clone(), time);
-                    println!(
-                        "Key: {}, Value: {}, timestamp: {}",
-                        keys, values, timestamp_sec
-                    );
+            RDB_TYPE_HASH => {
+                let len = Self::decode_length(content, cursor)?;
+                let mut hash = ScanMap::new();
+                for _ in 0..len {
+                    let field = Self::read_string(content, cursor)?;
+                    let value = Self::read_string(content, cursor)?;
+                    hash.insert(field, value);
                 }
+                RedisValue::Hash(hash)
+            }
+
+            // The compact encodings below store the whole value as one string blob
+            RDB_TYPE_HASH_ZIPMAP => {
+                let blob = Self::read_blob(content, cursor)?;
+                let pairs = parse_zipmap(&blob).ok_or_else(|| corrupt("zipmap"))?;
+                RedisValue::Hash(pairs.into_iter().collect())
+            }
+
+            RDB_TYPE_LIST_ZIPLIST => {
+                let blob = Self::read_blob(content, cursor)?;
+                RedisValue::List(
+                    parse_ziplist(&blob)
+                        .ok_or_else(|| corrupt("ziplist"))?
+                        .into(),
+                )
+            }
+
+            RDB_TYPE_SET_INTSET => {
+                let blob = Self::read_blob(content, cursor)?;
+                let items = parse_intset(&blob).ok_or_else(|| corrupt("intset"))?;
+                RedisValue::Set(items.into_iter().collect())
+            }
+
+            RDB_TYPE_SET_LISTPACK => {
+                let blob = Self::read_blob(content, cursor)?;
+                let items = parse_listpack(&blob).ok_or_else(|| corrupt("listpack"))?;
+                RedisValue::Set(items.into_iter().collect())
+            }
+
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let blob = Self::read_blob(content, cursor)?;
+                let items = if value_type == RDB_TYPE_ZSET_ZIPLIST {
+                    parse_ziplist(&blob).ok_or_else(|| corrupt("ziplist"))?
+                } else {
+                    parse_listpack(&blob).ok_or_else(|| corrupt("listpack"))?
+                };
+                let zset = into_pairs(items)
+                    .ok_or_else(|| corrupt("sorted set"))?
+                    .into_iter()
+                    .map(|(member, score)| Some((member, score.parse::<f64>().ok()?)))
+                    .collect::<Option<ScanMap<f64>>>()
+                    .ok_or_else(|| corrupt("sorted set score"))?;
+                RedisValue::ZSet(zset)
+            }
+
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let blob = Self::read_blob(content, cursor)?;
+                let items = if value_type == RDB_TYPE_HASH_ZIPLIST {
+                    parse_ziplist(&blob).ok_or_else(|| corrupt("ziplist"))?
+                } else {
+                    parse_listpack(&blob).ok_or_else(|| corrupt("listpack"))?
+                };
+                let pairs = into_pairs(items).ok_or_else(|| corrupt("hash"))?;
+                RedisValue::Hash(pairs.into_iter().collect())
+            }
 
-                _ => {
-                    let (keys, values) = self
-                        .read_values(content, cursor)
-                        .expect("Error reading the keys and values");
-                    self.data.insert(keys.clone(), values.clone());
-                    println!("Key: {}, Value: {}", keys, values);
+            // A linked list of ziplists, or in version 2 of listpacks and plain elements
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = Self::decode_length(content, cursor)?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                        Self::decode_length(content, cursor)?
+                    } else {
+                        QUICKLIST_NODE_CONTAINER_PACKED
+                    };
+                    let blob = Self::read_blob(content, cursor)?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        list.push_back(String::from_utf8_lossy(&blob).to_string());
+                    } else if value_type == RDB_TYPE_LIST_QUICKLIST_2 {
+                        list.extend(parse_listpack(&blob).ok_or_else(|| corrupt("listpack"))?);
+                    } else {
+                        list.extend(parse_ziplist(&blob).ok_or_else(|| corrupt("ziplist"))?);
+                    }
                 }
+                RedisValue::List(list)
+            }
+
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                RedisValue::Stream(Self::read_stream(value_type, content, cursor)?)
+            }
+
+            RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => {
+                return Err(RdbError::new(
+                    type_offset,
+                    RdbErrorKind::Unsupported("module values"),
+                ))
+            }
+
+            _ => {
+                return Err(RdbError::new(
+                    type_offset,
+                    RdbErrorKind::UnknownType(value_type),
+                ))
+            }
+        };
+        Ok(value)
+    }
+
+    // Score of the old ZSET type: a one byte length followed by the number as text, with
+    // three reserved lengths for the values that have no text form
+    fn read_string_double(content: &[u8], cursor: &mut usize) -> Result<f64, RdbError> {
+        let offset = *cursor;
+        match read_u8(content, cursor)? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = read_exact(content, cursor, len as usize)?;
+                str::from_utf8(text)
+                    .ok()
+                    .and_then(|text| text.parse().ok())
+                    .ok_or_else(|| RdbError::new(offset, RdbErrorKind::Corrupt("sorted set score")))
             }
         }
     }
 
-    pub fn read_from_file(&mut self, rconfig: &RedisConfig) -> RedisResponse {
-        let mut dir = match rconfig.config.get("--dir") {
-            Some(val) => val.clone(),
-            None => {
-                return RedisResponse::Error("No directory!".to_string());
+    // Streams are saved as a radix tree of listpacks keyed by their master entry id,
+    // followed by the stream metadata and the consumer groups
+    fn read_stream(value_type: u8, content: &[u8], cursor: &mut usize) -> Result<Stream, RdbError> {
+        let mut stream = Stream::default();
+
+        let listpacks = Self::decode_length(content, cursor)?;
+        for _ in 0..listpacks {
+            let offset = *cursor;
+            let master_id = parse_stream_id(&Self::read_blob(content, cursor)?);
+            let items = parse_listpack(&Self::read_blob(content, cursor)?);
+            master_id
+                .zip(items)
+                .and_then(|(master_id, items)| read_stream_listpack(&mut stream, master_id, &items))
+                .ok_or_else(|| RdbError::new(offset, RdbErrorKind::Corrupt("stream listpack")))?;
+        }
+
+        // Number of entries, which the listpacks already gave us
+        Self::decode_length(content, cursor)?;
+        stream.last_id = Self::read_stream_id(content, cursor)?;
+
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            stream.first_id = Self::read_stream_id(content, cursor)?;
+            stream.max_deleted_id = Self::read_stream_id(content, cursor)?;
+            stream.entries_added = Self::decode_length(content, cursor)? as u64;
+        } else {
+            stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+            stream.entries_added = stream.entries.len() as u64;
+        }
+
+        let groups = Self::decode_length(content, cursor)?;
+        for _ in 0..groups {
+            let name = Self::read_string(content, cursor)?;
+            let last_id = Self::read_stream_id(content, cursor)?;
+            let entries_read = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                Self::decode_length(content, cursor)? as u64
+            } else {
+                0
+            };
+
+            let pending_len = Self::decode_length(content, cursor)?;
+            let mut pending = Vec::new();
+            for _ in 0..pending_len {
+                let id = Self::read_raw_stream_id(content, cursor)?;
+                let delivery_time = u64::from_le_bytes(read_array(content, cursor)?);
+                let delivery_count = Self::decode_length(content, cursor)? as u64;
+                pending.push((id, delivery_time, delivery_count));
             }
-        };
 
-        let file = match rconfig.config.get("--dbfilename") {
-            Some(val) => val,
-            None => {
-                return RedisResponse::Error("No file!".to_string());
+            let consumers_len = Self::decode_length(content, cursor)?;
+            let mut consumers = Vec::new();
+            for _ in 0..consumers_len {
+                let name = Self::read_string(content, cursor)?;
+                let seen_time = u64::from_le_bytes(read_array(content, cursor)?);
+                let active_time = if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    u64::from_le_bytes(read_array(content, cursor)?)
+                } else {
+                    seen_time
+                };
+                let pending_len = Self::decode_length(content, cursor)?;
+                let pending = (0..pending_len)
+                    .map(|_| Self::read_raw_stream_id(content, cursor))
+                    .collect::<Result<Vec<StreamId>, RdbError>>()?;
+                consumers.push(StreamConsumer {
+                    name,
+                    seen_time,
+                    active_time,
+                    pending,
+                });
             }
-        };
 
-        if !dir.ends_with("/") {
-            dir.push('/');
+            stream.groups.push(StreamGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
         }
 
-        let file_path = dir.clone() + file;
+        Ok(stream)
+    }
 
-        let content = match fs::read(&file_path) {
-            Ok(file) => file,
-            Err(e) => {
-                //eprintln!("Error: {e}");
-                return RedisResponse::Error(format!("{}", e));
+    fn read_stream_id(content: &[u8], cursor: &mut usize) -> Result<StreamId, RdbError> {
+        let ms = Self::decode_length(content, cursor)? as u64;
+        let seq = Self::decode_length(content, cursor)? as u64;
+        Ok(StreamId { ms, seq })
+    }
+
+    // PEL ids are stored as 16 raw bytes, not as a string
+    fn read_raw_stream_id(content: &[u8], cursor: &mut usize) -> Result<StreamId, RdbError> {
+        let bytes: [u8; 16] = read_array(content, cursor)?;
+        Ok(parse_stream_id(&bytes).unwrap_or_default())
+    }
+
+    pub fn read_string(content: &[u8], cursor: &mut usize) -> Result<String, RdbError> {
+        let blob = Self::read_blob(content, cursor)?;
+        Ok(String::from_utf8_lossy(&blob).to_string())
+    }
+
+    // A string in any of its encodings: raw, integer (stored in binary, returned as its
+    // decimal text like Redis does) or LZF compressed
+    fn read_blob(content: &[u8], cursor: &mut usize) -> Result<Vec<u8>, RdbError> {
+        let offset = *cursor;
+        let (length, encoded) = Self::decode_length_or_encoding(content, cursor)?;
+        if !encoded {
+            return read_exact(content, cursor, length).map(|bytes| bytes.to_vec());
+        }
+
+        let integer = match length {
+            RDB_ENC_INT8 => read_signed(read_exact(content, cursor, 1)?),
+            RDB_ENC_INT16 => read_signed(read_exact(content, cursor, 2)?),
+            RDB_ENC_INT32 => read_signed(read_exact(content, cursor, 4)?),
+            RDB_ENC_LZF => {
+                let compressed_len = Self::decode_length(content, cursor)?;
+                let len = Self::decode_length(content, cursor)?;
+                let compressed = read_exact(content, cursor, compressed_len)?;
+                return lzf::decompress(compressed, len).ok_or_else(|| {
+                    RdbError::new(offset, RdbErrorKind::Corrupt("LZF compressed string"))
+                });
+            }
+            encoding => {
+                return Err(RdbError::new(
+                    offset,
+                    RdbErrorKind::UnknownEncoding(encoding),
+                ))
             }
         };
-        let mut cursor = 0;
+        Ok(integer.to_string().into())
+    }
 
-        if str::from_utf8(&content[0..5]).unwrap() != "REDIS" {
-            return RedisResponse::Error("Magic string failed".to_string());
+    // Parses a whole RDB file. The keyspace is only replaced once the file has been read
+    // to the end and its checksum verified, a bad file leaves the current data untouched.
+    pub fn load_rdb(&mut self, content: &[u8]) -> Result<RdbMetadata, RdbError> {
+        let mut cursor = 0;
+        let header = read_exact(content, &mut cursor, 9)
+            .map_err(|_| RdbError::new(0, RdbErrorKind::BadMagic))?;
+        if !header.starts_with(b"REDIS") {
+            return Err(RdbError::new(0, RdbErrorKind::BadMagic));
         }
+        let version_text = String::from_utf8_lossy(&header[5..]).to_string();
+        let version = match version_text.parse::<u32>() {
+            Ok(version) if (1..=RDB_VERSION).contains(&version) => version,
+            _ => {
+                return Err(RdbError::new(
+                    5,
+                    RdbErrorKind::UnsupportedVersion(version_text),
+                ))
+            }
+        };
 
-        while cursor < content.len() {
-            match content[cursor] {
-                0xFA => {
-                    cursor += 1;
+        let mut metadata = RdbMetadata {
+            version,
+            aux: Vec::new(),
+        };
+        let mut databases = (0..self.databases.len())
+            .map(|_| Database::new())
+            .collect::<Vec<Database>>();
+        let mut db_index = 0;
+        // Set by an expire opcode, applies to the key that follows it
+        let mut expire_at = None;
+
+        loop {
+            let offset = cursor;
+            match read_u8(content, &mut cursor)? {
+                RDB_OPCODE_EOF => break,
+
+                RDB_OPCODE_SELECTDB => {
+                    let index = Self::decode_length(content, &mut cursor)?;
+                    if index >= databases.len() {
+                        return Err(RdbError::new(
+                            offset,
+                            RdbErrorKind::DbIndexOutOfRange(index),
+                        ));
+                    }
+                    db_index = index;
                 }
 
-                0xFB => {
-                    self.parse_db_key_val(&content, &mut cursor);
+                // Hash table size hints, not needed to load the keys
+                RDB_OPCODE_RESIZEDB => {
+                    Self::decode_length(content, &mut cursor)?;
+                    Self::decode_length(content, &mut cursor)?;
                 }
 
-                0xFE => {
-                    cursor += 1;
+                RDB_OPCODE_AUX => {
+                    let field = Self::read_string(content, &mut cursor)?;
+                    let value = Self::read_string(content, &mut cursor)?;
+                    metadata.aux.push((field, value));
                 }
 
-                0xFF => {
-                    break;
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    let ms = u64::from_le_bytes(read_array(content, &mut cursor)?);
+                    expire_at = UNIX_EPOCH.checked_add(Duration::from_millis(ms));
+                }
+
+                RDB_OPCODE_EXPIRETIME => {
+                    let secs = u32::from_le_bytes(read_array(content, &mut cursor)?);
+                    expire_at = UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64));
+                }
+
+                // LFU frequency and LRU idle time of the next key, there is no eviction
+                RDB_OPCODE_FREQ => {
+                    read_u8(content, &mut cursor)?;
+                }
+                RDB_OPCODE_IDLE => {
+                    Self::decode_length(content, &mut cursor)?;
+                }
+
+                // Cluster slot sizes: slot, keys, keys with an expire
+                RDB_OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        Self::decode_length(content, &mut cursor)?;
+                    }
+                }
+
+                // Function libraries, skipped as there is no scripting
+                RDB_OPCODE_FUNCTION2 => {
+                    Self::read_blob(content, &mut cursor)?;
+                }
+
+                RDB_OPCODE_FUNCTION_PRE_GA | RDB_OPCODE_MODULE_AUX => {
+                    return Err(RdbError::new(
+                        offset,
+                        RdbErrorKind::Unsupported("module and pre-GA function data"),
+                    ));
+                }
+
+                value_type => {
+                    let key = Self::read_string(content, &mut cursor)?;
+                    let value = Self::read_object(value_type, offset, content, &mut cursor)?;
+                    let db = &mut databases[db_index];
+                    db.insert_key(key.clone(), value);
+                    if let Some(time) = expire_at.take() {
+                        db.set_expiry(key, time);
+                    }
                 }
+            }
+        }
+
+        // CRC64 of everything up to and including the EOF opcode, zero when the file was
+        // written with checksums disabled
+        if version >= 5 {
+            let end = cursor;
+            let expected = u64::from_le_bytes(read_array(content, &mut cursor)?);
+            let computed = crc64::crc64(0, &content[..end]);
+            if expected != 0 && expected != computed {
+                return Err(RdbError::new(
+                    end,
+                    RdbErrorKind::ChecksumMismatch { expected, computed },
+                ));
+            }
+        }
+
+        self.databases = databases;
+        Ok(metadata)
+    }
+
+    // A missing file is not an error, the server then starts empty
+    pub fn read_from_file(&mut self, rconfig: &RedisConfig) -> RedisResponse {
+        let file_path = rconfig.get_rdb_path().display().to_string();
 
-                _ => {
-                    cursor += 1;
+        let content = match fs::read(&file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return RedisResponse::OK(format!("No RDB file at {}", file_path));
+            }
+            Err(e) => return RedisResponse::Error(format!("{}: {}", file_path, e)),
+        };
+
+        match self.load_rdb(&content) {
+            Ok(metadata) => {
+                for (field, value) in &metadata.aux {
+                    println!("RDB {}: {}", field, value);
                 }
+                let keys = self.databases.iter().map(|db| db.data.len()).sum::<usize>();
+                RedisResponse::OK(format!(
+                    "Loaded {} keys from {} (RDB version {})",
+                    keys, file_path, metadata.version
+                ))
             }
+            Err(e) => RedisResponse::Error(format!("{}: {}", file_path, e)),
         }
+    }
 
-        return RedisResponse::OK("OK".to_string());
+    pub fn snapshot(&self) -> Vec<DbSnapshot> {
+        self.databases
+            .iter()
+            .map(|db| DbSnapshot {
+                data: db.data.clone(),
+                expiry: db.expiry.clone(),
+            })
+            .collect()
     }
 
-    pub fn set_value(&mut self, command: &RedisCommand) -> RedisResponse {
-        self.data
-            .insert(command.str_cmd[1].clone(), command.str_cmd[2].clone());
-        if command.cmd_len == 5 && command.str_cmd[3].as_str() == "px" {
-            self.expiry.insert(
-                command.str_cmd[1].clone(),
-                SystemTime::now()
-                    + Duration::from_millis(
-                        command.str_cmd[4]
-                            .parse::<u64>()
-                            .expect("Parsing time failed!"),
-                    ),
+    // SAVE: encodes and writes the file while the caller holds the lock
+    pub fn save(&mut self, path: &Path) -> RedisResponse {
+        if self.save_status.lock().unwrap().bgsave_in_progress {
+            return RedisResponse::Error("ERR Background save already in progress".to_string());
+        }
+        match rdb_writer::write_file(path, &rdb_writer::encode(&self.snapshot())) {
+            Ok(()) => {
+                let mut status = self.save_status.lock().unwrap();
+                status.lastsave = unix_time();
+                status.last_bgsave_ok = true;
+                self.dirty = 0;
+                RedisResponse::OK("OK".to_string())
+            }
+            Err(e) => {
+                eprintln!("Failed to save {}: {}", path.display(), e);
+                RedisResponse::Error(format!("ERR Save failed: {}", e))
+            }
+        }
+    }
+
+    // BGSAVE: only copying the keyspace happens under the lock, encoding and writing the
+    // file run on their own thread
+    pub fn bgsave(&mut self, path: &Path) -> RedisResponse {
+        {
+            let mut status = self.save_status.lock().unwrap();
+            if status.bgsave_in_progress {
+                return RedisResponse::Error("ERR Background save already in progress".to_string());
+            }
+            status.bgsave_in_progress = true;
+            status.last_bgsave_try = unix_time();
+        }
+
+        // Changes made while the file is written stay counted once it is done
+        let saved_changes = self.dirty;
+        let snapshot = self.snapshot();
+        let status = Arc::clone(&self.save_status);
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            let result = rdb_writer::write_file(&path, &rdb_writer::encode(&snapshot));
+            if let Err(e) = &result {
+                eprintln!("Background save of {} failed: {}", path.display(), e);
+            }
+
+            let mut status = status.lock().unwrap();
+            status.bgsave_in_progress = false;
+            status.last_bgsave_ok = result.is_ok();
+            if result.is_ok() {
+                status.lastsave = unix_time();
+                status.saved_changes += saved_changes;
+            }
+        });
+
+        RedisResponse::OK("Background saving started".to_string())
+    }
+
+    // Runs periodically: accounts for finished background saves and starts a new one
+    // once a save point is reached
+    pub fn save_cron(&mut self, save_points: &[(u64, u64)], path: &Path) {
+        let now = unix_time();
+        let (seconds, changes) = {
+            let mut status = self.save_status.lock().unwrap();
+            let saved = std::mem::take(&mut status.saved_changes);
+            self.dirty = self.dirty.saturating_sub(saved);
+
+            if status.bgsave_in_progress
+                || (!status.last_bgsave_ok
+                    && now.saturating_sub(status.last_bgsave_try) <= BGSAVE_RETRY_DELAY)
+            {
+                return;
+            }
+            let since_last_save = now.saturating_sub(status.lastsave);
+            match save_points
+                .iter()
+                .find(|(seconds, changes)| self.dirty >= *changes && since_last_save > *seconds)
+            {
+                Some(point) => *point,
+                None => return,
+            }
+        };
+
+        println!("{} changes in {} seconds. Saving...", changes, seconds);
+        self.bgsave(path);
+    }
+
+    // BGREWRITEAOF: the keyspace is copied under the lock, the base file is written on a
+    // thread while new writes keep going to the AOF
+    pub fn bgrewriteaof(&mut self, rdb_preamble: bool) -> RedisResponse {
+        let in_progress = match self.aof.as_ref() {
+            Some(aof) => aof.rewrite_in_progress(),
+            None => {
+                return RedisResponse::Error(
+                    "ERR Background append only file rewriting needs appendonly yes".to_string(),
+                )
+            }
+        };
+        if in_progress {
+            return RedisResponse::Error(
+                "ERR Background append only file rewriting already in progress".to_string(),
             );
         }
 
-        RedisResponse::OK(String::from("OK"))
+        let snapshot = self.snapshot();
+        match self
+            .aof
+            .as_mut()
+            .map(|aof| aof.start_rewrite(snapshot, rdb_preamble))
+        {
+            Some(Err(e)) => RedisResponse::Error(e),
+            _ => RedisResponse::OK("Background append only file rewriting started".to_string()),
+        }
     }
 
-    pub fn get_value(&mut self, command: &RedisCommand) -> Option<String> {
-        let key = &command.str_cmd[1];
+    // CONFIG SET appendonly: turning it on writes the current dataset as the base of a
+    // new AOF, turning it off closes the file and leaves it on disk
+    pub fn set_appendonly(&mut self, config: &RedisConfig) -> Result<(), String> {
+        if !config.get_appendonly() {
+            self.aof = None;
+            return Ok(());
+        }
+        if self.aof.is_some() {
+            return Ok(());
+        }
 
-        if let Some(&expiry_time) = self.expiry.get(key) {
-            if SystemTime::now() > expiry_time {
-                self.data.remove(key);
-                self.expiry.remove(key);
-                return None;
+        self.aof = Some(Aof::start(config)?);
+        match self.bgrewriteaof(config.get_aof_use_rdb_preamble()) {
+            RedisResponse::OK(_) => Ok(()),
+            RedisResponse::Error(e) => {
+                self.aof = None;
+                Err(e)
             }
         }
+    }
 
-        match self.data.get(key) {
-            Some(value) => Some(value.clone()),
-            None => None,
+    // Runs periodically next to save_cron: AOF housekeeping and automatic rewrites
+    pub fn aof_cron(&mut self, config: &RedisConfig) {
+        let Some(aof) = self.aof.as_mut() else {
+            return;
+        };
+        if let Err(e) = aof.cron() {
+            eprintln!("Turning AOF off: {}", e);
+            self.aof = None;
+            return;
         }
+
+        let percentage = config.get_auto_aof_rewrite_percentage();
+        if let Some(growth) = aof.rewrite_due(percentage, config.get_auto_aof_rewrite_min_size()) {
+            println!("Starting automatic rewriting of AOF on {}% growth", growth);
+            if let RedisResponse::Error(e) = self.bgrewriteaof(config.get_aof_use_rdb_preamble()) {
+                eprintln!("Automatic AOF rewrite failed to start: {}", e);
+            }
+        }
+        // An fsync may have finished, WAITAOF clients check again
+        self.replication.acks.notify_waiters();
     }
 
-    pub fn get_all_keys(&self) -> Option<Vec<String>> {
-        let keys = self.data.clone().into_keys().collect::<Vec<String>>();
-        if keys.len() == 0 {
-            None
-        } else {
-            Some(keys)
+    // MISCONF: writes are refused while the data cannot be persisted, either because
+    // the AOF cannot be written or, with stop-writes-on-bgsave-error, because the last
+    // background save failed. Clients notice instead of silently losing data.
+    pub fn writes_denied(
+        &self,
+        stop_writes_on_bgsave_error: bool,
+        has_save_points: bool,
+    ) -> Option<RedisResponse> {
+        if let Some(e) = self
+            .aof
+            .as_ref()
+            .and_then(|aof| aof.last_write_error.as_ref())
+        {
+            return Some(RedisResponse::Error(format!(
+                "MISCONF Errors writing to the AOF file: {}",
+                e
+            )));
         }
+        if stop_writes_on_bgsave_error
+            && has_save_points
+            && !self.save_status.lock().unwrap().last_bgsave_ok
+        {
+            return Some(RedisResponse::Error(
+                "MISCONF Redis is configured to save RDB snapshots, but it's currently unable to persist to disk. Commands that may modify the data set are disabled, because this instance is configured to report errors during writes if RDB snapshotting fails (stop-writes-on-bgsave-error option). Please check the Redis logs for details about the RDB error.".to_string(),
+            ));
+        }
+        None
     }
 
     pub fn get_role(&self) -> &ReplicationRole {
@@ -285,6 +1183,1059 @@ impl RedisData {
     }
 
     pub fn get_host_port(&self) -> (Option<String>, Option<u16>) {
-        (self.host.clone(), self.port.clone())
+        (self.host.clone(), self.port)
+    }
+}
+
+fn read_bytes<'a>(content: &'a [u8], cursor: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = content.get(*cursor..cursor.checked_add(len)?)?;
+    *cursor += len;
+    Some(bytes)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn read_exact<'a>(content: &'a [u8], cursor: &mut usize, len: usize) -> Result<&'a [u8], RdbError> {
+    read_bytes(content, cursor, len)
+        .ok_or_else(|| RdbError::new(*cursor, RdbErrorKind::UnexpectedEof))
+}
+
+fn read_array<const N: usize>(content: &[u8], cursor: &mut usize) -> Result<[u8; N], RdbError> {
+    let mut array = [0u8; N];
+    array.copy_from_slice(read_exact(content, cursor, N)?);
+    Ok(array)
+}
+
+fn read_u8(content: &[u8], cursor: &mut usize) -> Result<u8, RdbError> {
+    Ok(read_array::<1>(content, cursor)?[0])
+}
+
+// Little endian two's complement integer of 1 to 8 bytes
+fn read_signed(bytes: &[u8]) -> i64 {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as u64) << (8 * i);
+    }
+    let shift = 64 - 8 * bytes.len() as u32;
+    ((value << shift) as i64) >> shift
+}
+
+fn take_string(blob: &[u8], cursor: &mut usize, len: usize) -> Option<String> {
+    read_bytes(blob, cursor, len).map(|bytes| String::from_utf8_lossy(bytes).to_string())
+}
+
+fn into_pairs(items: Vec<String>) -> Option<Vec<(String, String)>> {
+    if items.len() % 2 == 1 {
+        return None;
+    }
+    let mut items = items.into_iter();
+    let mut pairs = vec![];
+    while let (Some(first), Some(second)) = (items.next(), items.next()) {
+        pairs.push((first, second));
+    }
+    Some(pairs)
+}
+
+// 128-bit big endian id, as used for stream listpack keys and PEL entries
+fn parse_stream_id(bytes: &[u8]) -> Option<StreamId> {
+    if bytes.len() != 16 {
+        return None;
+    }
+    Some(StreamId {
+        ms: u64::from_be_bytes(bytes[..8].try_into().ok()?),
+        seq: u64::from_be_bytes(bytes[8..].try_into().ok()?),
+    })
+}
+
+// Ziplist: <zlbytes u32><zltail u32><zllen u16> entries... 0xFF. Each entry is
+// <prevlen><encoding><data>, integers come back as their decimal text.
+fn parse_ziplist(blob: &[u8]) -> Option<Vec<String>> {
+    let mut cursor = 10;
+    let mut items = vec![];
+    loop {
+        match *blob.get(cursor)? {
+            0xFF => break,
+            0xFE => cursor += 5,
+            _ => cursor += 1,
+        }
+
+        let encoding = *blob.get(cursor)?;
+        cursor += 1;
+        let item = match encoding >> 6 {
+            0b00 => take_string(blob, &mut cursor, (encoding & 0x3F) as usize)?,
+            0b01 => {
+                let len = ((encoding & 0x3F) as usize) << 8 | *blob.get(cursor)? as usize;
+                cursor += 1;
+                take_string(blob, &mut cursor, len)?
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(read_bytes(blob, &mut cursor, 4)?.try_into().ok()?);
+                take_string(blob, &mut cursor, len as usize)?
+            }
+            _ => {
+                let size = match encoding {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    // 4 bit immediate, 0001 to 1101 standing for 0 to 12
+                    0xF1..=0xFD => 0,
+                    _ => return None,
+                };
+                if size == 0 {
+                    ((encoding & 0x0F) - 1).to_string()
+                } else {
+                    read_signed(read_bytes(blob, &mut cursor, size)?).to_string()
+                }
+            }
+        };
+        items.push(item);
+    }
+    Some(items)
+}
+
+// Listpack: <total bytes u32><count u16> entries... 0xFF. Each entry is
+// <encoding><data><backlen>, where backlen is the entry size for reverse traversal.
+fn parse_listpack(blob: &[u8]) -> Option<Vec<String>> {
+    let mut cursor = 6;
+    let mut items = vec![];
+    loop {
+        let start = cursor;
+        let encoding = *blob.get(cursor)?;
+        if encoding == 0xFF {
+            break;
+        }
+        cursor += 1;
+
+        let item = if encoding & 0x80 == 0 {
+            // 7 bit unsigned integer
+            (encoding & 0x7F).to_string()
+        } else if encoding & 0xC0 == 0x80 {
+            take_string(blob, &mut cursor, (encoding & 0x3F) as usize)?
+        } else if encoding & 0xE0 == 0xC0 {
+            // 13 bit signed integer
+            let value = ((encoding & 0x1F) as i64) << 8 | *blob.get(cursor)? as i64;
+            cursor += 1;
+            let value = if value >= 1 << 12 {
+                value - (1 << 13)
+            } else {
+                value
+            };
+            value.to_string()
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = ((encoding & 0x0F) as usize) << 8 | *blob.get(cursor)? as usize;
+            cursor += 1;
+            take_string(blob, &mut cursor, len)?
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len =
+                        u32::from_le_bytes(read_bytes(blob, &mut cursor, 4)?.try_into().ok()?);
+                    take_string(blob, &mut cursor, len as usize)?
+                }
+                0xF1 => read_signed(read_bytes(blob, &mut cursor, 2)?).to_string(),
+                0xF2 => read_signed(read_bytes(blob, &mut cursor, 3)?).to_string(),
+                0xF3 => read_signed(read_bytes(blob, &mut cursor, 4)?).to_string(),
+                0xF4 => read_signed(read_bytes(blob, &mut cursor, 8)?).to_string(),
+                _ => return None,
+            }
+        };
+
+        // Sizes as lpEncodeBacklen() picks them, whose upper bounds are exclusive: an entry
+        // of exactly 16383 bytes already gets a 3 byte backlen
+        cursor += match cursor - start {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        items.push(item);
+    }
+    Some(items)
+}
+
+// Intset: <encoding u32><length u32> then sorted little endian integers of that width
+fn parse_intset(blob: &[u8]) -> Option<Vec<String>> {
+    let mut cursor = 0;
+    let width = u32::from_le_bytes(read_bytes(blob, &mut cursor, 4)?.try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(read_bytes(blob, &mut cursor, 4)?.try_into().ok()?) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return None;
+    }
+    (0..len)
+        .map(|_| Some(read_signed(read_bytes(blob, &mut cursor, width)?).to_string()))
+        .collect()
+}
+
+// Zipmap, the pre 2.6 small hash: <zmlen> then <len>key<len><free>value<free bytes>... 0xFF
+fn parse_zipmap(blob: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut cursor = 1;
+    let mut pairs = vec![];
+    while *blob.get(cursor)? != 0xFF {
+        let key_len = zipmap_len(blob, &mut cursor)?;
+        let key = take_string(blob, &mut cursor, key_len)?;
+        let value_len = zipmap_len(blob, &mut cursor)?;
+        let free = *blob.get(cursor)? as usize;
+        cursor += 1;
+        let value = take_string(blob, &mut cursor, value_len)?;
+        cursor += free;
+        pairs.push((key, value));
+    }
+    Some(pairs)
+}
+
+fn zipmap_len(blob: &[u8], cursor: &mut usize) -> Option<usize> {
+    let first = *blob.get(*cursor)?;
+    *cursor += 1;
+    match first {
+        0..=253 => Some(first as usize),
+        254 => Some(u32::from_le_bytes(read_bytes(blob, cursor, 4)?.try_into().ok()?) as usize),
+        _ => None,
+    }
+}
+
+// Entries of one stream listpack. The master entry comes first:
+//   count, deleted, number of master fields, the master fields, 0
+// then every entry:
+//   flags, ms delta, seq delta, [field count, field value pairs | master field values], lp-count
+// with deltas relative to the master id.
+fn read_stream_listpack(stream: &mut Stream, master_id: StreamId, items: &[String]) -> Option<()> {
+    let number = |index: usize| items.get(index)?.parse::<i64>().ok();
+    // Counts come from the file, anything negative or overflowing is corrupt
+    let length = |index: usize| usize::try_from(number(index)?).ok();
+
+    let count = length(0)?.checked_add(length(1)?)?;
+    let master_fields_len = length(2)?;
+    let master_fields = items.get(3..3usize.checked_add(master_fields_len)?)?;
+    let mut index = 3 + master_fields_len + 1;
+
+    for _ in 0..count {
+        let flags = number(index)?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(number(index + 1)? as u64),
+            seq: master_id.seq.wrapping_add(number(index + 2)? as u64),
+        };
+        index += 3;
+
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            let values = items.get(index..index.checked_add(master_fields_len)?)?;
+            index += master_fields_len;
+            master_fields
+                .iter()
+                .cloned()
+                .zip(values.iter().cloned())
+                .collect()
+        } else {
+            let pairs_len = length(index)?.checked_mul(2)?;
+            index += 1;
+            let pairs = items.get(index..index.checked_add(pairs_len)?)?;
+            index += pairs_len;
+            into_pairs(pairs.to_vec())?
+        };
+        // lp-count
+        index += 1;
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_data() -> RedisData {
+        RedisData::init_db(
+            16,
+            Arc::new(Mutex::new(PubSub::new())),
+            ReplicationRole::Master,
+            None,
+            None,
+        )
+    }
+
+    // A version 11 file with body in db 0, ending in its checksum
+    fn rdb_file(body: &[u8]) -> Vec<u8> {
+        let mut content = b"REDIS0011".to_vec();
+        content.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0]);
+        content.extend_from_slice(body);
+        content.push(RDB_OPCODE_EOF);
+        let checksum = crc64::crc64(0, &content);
+        content.extend_from_slice(&checksum.to_le_bytes());
+        content
+    }
+
+    fn load(body: &[u8]) -> Result<RedisData, String> {
+        let mut data = new_data();
+        data.load_rdb(&rdb_file(body)).map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    // 6 or 14 bit length followed by the bytes
+    fn string(bytes: &[u8]) -> Vec<u8> {
+        let mut out = match bytes.len() {
+            len if len < 64 => vec![len as u8],
+            len => vec![0x40 | (len >> 8) as u8, len as u8],
+        };
+        out.extend_from_slice(bytes);
+        out
+    }
+
+    fn record(value_type: u8, key: &str, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![value_type];
+        out.extend(string(key.as_bytes()));
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|(first, second)| (first.to_string(), second.to_string()))
+            .collect()
+    }
+
+    // Ziplist from <encoding><data> entries, with the header and prevlens filled in
+    fn ziplist(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![];
+        let mut prevlen = 0;
+        let mut tail = 10;
+        for entry in entries {
+            tail = 10 + body.len();
+            let start = body.len();
+            if prevlen < 254 {
+                body.push(prevlen as u8);
+            } else {
+                body.push(0xFE);
+                body.extend_from_slice(&(prevlen as u32).to_le_bytes());
+            }
+            body.extend_from_slice(entry);
+            prevlen = body.len() - start;
+        }
+        body.push(0xFF);
+        let mut out = ((10 + body.len()) as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&(tail as u32).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    // Listpack from <encoding><data> entries, with the header and backlens filled in
+    fn listpack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = vec![];
+        for entry in entries {
+            body.extend_from_slice(entry);
+            // lpEncodeBacklen(), up to 3 bytes
+            match entry.len() {
+                len if len <= 127 => body.push(len as u8),
+                len if len < 16383 => body.extend_from_slice(&[(len >> 7) as u8, len as u8 | 128]),
+                len => body.extend_from_slice(&[
+                    (len >> 14) as u8,
+                    (len >> 7) as u8 | 128,
+                    len as u8 | 128,
+                ]),
+            }
+        }
+        body.push(0xFF);
+        let mut out = ((6 + body.len()) as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend(body);
+        out
+    }
+
+    // Listpack entries the way Redis writes small stream fields: 7 bit integers, short
+    // strings for the rest
+    fn listpack_of(items: &[&str]) -> Vec<u8> {
+        let entries = items
+            .iter()
+            .map(|item| match item.parse::<u8>() {
+                Ok(number) if number < 128 => vec![number],
+                _ => [&[0x80 | item.len() as u8], item.as_bytes()].concat(),
+            })
+            .collect::<Vec<Vec<u8>>>();
+        listpack(&entries)
+    }
+
+    // Ziplist of short strings, enough for the ziplist backed types
+    fn ziplist_of(items: &[&str]) -> Vec<u8> {
+        let entries = items
+            .iter()
+            .map(|item| [&[item.len() as u8][..], item.as_bytes()].concat())
+            .collect::<Vec<Vec<u8>>>();
+        ziplist(&entries)
+    }
+
+    fn value<'a>(data: &'a RedisData, key: &str) -> &'a RedisValue {
+        &data.databases[0].data[key]
+    }
+
+    fn list(items: &[&str]) -> RedisValue {
+        RedisValue::List(strings(items).into())
+    }
+
+    fn set(items: &[&str]) -> RedisValue {
+        RedisValue::Set(strings(items).into_iter().collect())
+    }
+
+    fn hash(items: &[(&str, &str)]) -> RedisValue {
+        RedisValue::Hash(pairs(items).into_iter().collect())
+    }
+
+    fn zset(items: &[(&str, f64)]) -> RedisValue {
+        RedisValue::ZSet(
+            items
+                .iter()
+                .map(|(member, score)| (member.to_string(), *score))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn ziplist_from_the_redis_docs() {
+        // The example in ziplist.c: the integers 2 and 5
+        let blob = [
+            0x0f, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0xf3, 0x02, 0xf6,
+            0xff,
+        ];
+        assert_eq!(parse_ziplist(&blob), Some(strings(&["2", "5"])));
+        assert_eq!(parse_ziplist(&ziplist(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn ziplist_encodings() {
+        let long = "x".repeat(300);
+        let blob = ziplist(&[
+            [&[0x05][..], b"hello"].concat(),
+            // 14 bit length, which also makes the next prevlen take 5 bytes
+            [&[0x41, 0x2c][..], long.as_bytes()].concat(),
+            [&[0x80, 0, 0, 0, 3][..], b"abc"].concat(),
+            [&[0xc0][..], &(-2i16).to_le_bytes()].concat(),
+            [&[0xd0][..], &100000i32.to_le_bytes()].concat(),
+            [&[0xe0][..], &(-(1i64 << 40)).to_le_bytes()].concat(),
+            vec![0xf0, 0xff, 0xff, 0x7f],
+            vec![0xfe, 0x80],
+            vec![0xf1],
+            vec![0xfd],
+        ]);
+        assert_eq!(
+            parse_ziplist(&blob),
+            Some(strings(&[
+                "hello",
+                &long,
+                "abc",
+                "-2",
+                "100000",
+                "-1099511627776",
+                "8388607",
+                "-128",
+                "0",
+                "12"
+            ]))
+        );
+    }
+
+    #[test]
+    fn ziplist_rejects_bad_entries() {
+        let blob = ziplist(&[[&[0x05][..], b"hello"].concat()]);
+        // No end marker, data running past the blob, unknown integer encoding
+        assert_eq!(parse_ziplist(&blob[..blob.len() - 1]), None);
+        assert_eq!(parse_ziplist(&ziplist(&[vec![0x05, b'h']])), None);
+        assert_eq!(parse_ziplist(&ziplist(&[vec![0xc1, 0, 0]])), None);
+        assert_eq!(parse_ziplist(&[0x0f, 0x00]), None);
+    }
+
+    #[test]
+    fn listpack_encodings() {
+        let long = "y".repeat(200);
+        let blob = listpack(&[
+            vec![0x05],
+            vec![0x7f],
+            vec![0x81, b'a'],
+            // -4000 as a 13 bit integer
+            vec![0xd0, 0x60],
+            [&[0xe0, 200][..], long.as_bytes()].concat(),
+            [&[0xf0, 3, 0, 0, 0][..], b"xyz"].concat(),
+            [&[0xf1][..], &(-300i16).to_le_bytes()].concat(),
+            vec![0xf2, 0x00, 0x00, 0x10],
+            [&[0xf3][..], &(-100000i32).to_le_bytes()].concat(),
+            [&[0xf4][..], &(1i64 << 40).to_le_bytes()].concat(),
+        ]);
+        assert_eq!(
+            parse_listpack(&blob),
+            Some(strings(&[
+                "5",
+                "127",
+                "a",
+                "-4000",
+                &long,
+                "xyz",
+                "-300",
+                "1048576",
+                "-100000",
+                "1099511627776"
+            ]))
+        );
+        assert_eq!(parse_listpack(&listpack(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn listpack_backlen_sizes() {
+        // Entries of 16382 and 16383 bytes, the last sizes with a 2 and a 3 byte backlen
+        let entry = |len: usize| {
+            [
+                &[0xf0][..],
+                &(len as u32 - 5).to_le_bytes(),
+                &vec![b'z'; len - 5],
+            ]
+            .concat()
+        };
+        let blob = listpack(&[entry(16382), entry(16383), vec![0x01]]);
+        assert_eq!(blob[6 + 16382..6 + 16384], [0x7f, 0xfe]);
+        assert_eq!(
+            blob[6 + 16384 + 16383..6 + 16384 + 16386],
+            [0x00, 0xff, 0xff]
+        );
+        assert_eq!(
+            parse_listpack(&blob),
+            Some(strings(&[&"z".repeat(16377), &"z".repeat(16378), "1"]))
+        );
+    }
+
+    #[test]
+    fn listpack_rejects_bad_entries() {
+        let blob = listpack(&[vec![0x81, b'a']]);
+        assert_eq!(parse_listpack(&blob[..blob.len() - 1]), None);
+        assert_eq!(parse_listpack(&listpack(&[vec![0x85, b'a']])), None);
+        assert_eq!(parse_listpack(&listpack(&[vec![0xf5]])), None);
+        assert_eq!(parse_listpack(&listpack(&[vec![0xf4, 1, 2]])), None);
+    }
+
+    #[test]
+    fn intset_widths() {
+        let mut blob = vec![2, 0, 0, 0, 3, 0, 0, 0];
+        for value in [-2i16, 1, 300] {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(parse_intset(&blob), Some(strings(&["-2", "1", "300"])));
+
+        let mut blob = vec![4, 0, 0, 0, 1, 0, 0, 0];
+        blob.extend_from_slice(&(-70000i32).to_le_bytes());
+        assert_eq!(parse_intset(&blob), Some(strings(&["-70000"])));
+
+        let mut blob = vec![8, 0, 0, 0, 1, 0, 0, 0];
+        blob.extend_from_slice(&i64::MIN.to_le_bytes());
+        assert_eq!(
+            parse_intset(&blob),
+            Some(strings(&["-9223372036854775808"]))
+        );
+    }
+
+    #[test]
+    fn intset_rejects_bad_headers() {
+        assert_eq!(parse_intset(&[3, 0, 0, 0, 1, 0, 0, 0, 1, 2, 3]), None);
+        assert_eq!(parse_intset(&[2, 0, 0, 0, 2, 0, 0, 0, 1, 0]), None);
+        assert_eq!(parse_intset(&[2, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn zipmap_from_the_redis_docs() {
+        // The example in zipmap.c: "foo" => "bar", "hello" => "world"
+        let blob = b"\x02\x03foo\x03\x00bar\x05hello\x05\x00world\xff";
+        assert_eq!(
+            parse_zipmap(blob),
+            Some(pairs(&[("foo", "bar"), ("hello", "world")]))
+        );
+    }
+
+    #[test]
+    fn zipmap_free_space_and_long_lengths() {
+        // Two free bytes after the value, and a key length in the 5 byte form
+        let blob = b"\x02\x01k\x01\x02v..\xfe\x03\x00\x00\x00key\x01\x00w\xff";
+        assert_eq!(parse_zipmap(blob), Some(pairs(&[("k", "v"), ("key", "w")])));
+        assert_eq!(parse_zipmap(b"\x01\x01k\xff\x00v\xff"), None);
+        assert_eq!(parse_zipmap(b"\x01\x01k\x01\x00v"), None);
+    }
+
+    #[test]
+    fn loads_the_plain_types() {
+        let mut body = record(RDB_TYPE_STRING, "string", &string(b"hello"));
+        body.extend(record(
+            RDB_TYPE_LIST,
+            "list",
+            &[vec![2], string(b"a"), string(b"b")].concat(),
+        ));
+        body.extend(record(
+            RDB_TYPE_SET,
+            "set",
+            &[vec![2], string(b"x"), string(b"y")].concat(),
+        ));
+        // Old scores are text, with reserved lengths for the infinities
+        body.extend(record(
+            RDB_TYPE_ZSET,
+            "zset",
+            &[
+                vec![3],
+                string(b"a"),
+                string(b"1.5"),
+                string(b"b"),
+                vec![254],
+                string(b"c"),
+                vec![255],
+            ]
+            .concat(),
+        ));
+        body.extend(record(
+            RDB_TYPE_ZSET_2,
+            "zset2",
+            &[vec![1], string(b"a"), 2.25f64.to_le_bytes().to_vec()].concat(),
+        ));
+        body.extend(record(
+            RDB_TYPE_HASH,
+            "hash",
+            &[vec![1], string(b"f"), string(b"v")].concat(),
+        ));
+
+        let data = load(&body).unwrap();
+        assert_eq!(
+            value(&data, "string"),
+            &RedisValue::String("hello".to_string())
+        );
+        assert_eq!(value(&data, "list"), &list(&["a", "b"]));
+        assert_eq!(value(&data, "set"), &set(&["x", "y"]));
+        assert_eq!(
+            value(&data, "zset"),
+            &zset(&[("a", 1.5), ("b", f64::INFINITY), ("c", f64::NEG_INFINITY)])
+        );
+        assert_eq!(value(&data, "zset2"), &zset(&[("a", 2.25)]));
+        assert_eq!(value(&data, "hash"), &hash(&[("f", "v")]));
+    }
+
+    #[test]
+    fn loads_the_compact_encodings() {
+        let zl = |items: &[&str]| string(&ziplist_of(items));
+        let lp = |items: &[&str]| string(&listpack_of(items));
+
+        let mut body = record(
+            RDB_TYPE_HASH_ZIPMAP,
+            "zipmap",
+            &string(b"\x01\x01k\x01\x00v\xff"),
+        );
+        body.extend(record(RDB_TYPE_LIST_ZIPLIST, "zllist", &zl(&["a", "b"])));
+        body.extend(record(
+            RDB_TYPE_SET_INTSET,
+            "intset",
+            &string(&[2, 0, 0, 0, 1, 0, 0, 0, 7, 0]),
+        ));
+        body.extend(record(
+            RDB_TYPE_ZSET_ZIPLIST,
+            "zlzset",
+            &zl(&["a", "1", "b", "2.5"]),
+        ));
+        body.extend(record(RDB_TYPE_HASH_ZIPLIST, "zlhash", &zl(&["f", "v"])));
+        body.extend(record(
+            RDB_TYPE_LIST_QUICKLIST,
+            "quicklist",
+            &[vec![2], zl(&["a", "b"]), zl(&["c"])].concat(),
+        ));
+        body.extend(record(RDB_TYPE_HASH_LISTPACK, "lphash", &lp(&["f", "1"])));
+        body.extend(record(
+            RDB_TYPE_ZSET_LISTPACK,
+            "lpzset",
+            &lp(&["a", "-3", "b", "1.5"]),
+        ));
+        // A packed node and a plain one
+        body.extend(record(
+            RDB_TYPE_LIST_QUICKLIST_2,
+            "quicklist2",
+            &[
+                vec![2, QUICKLIST_NODE_CONTAINER_PACKED as u8],
+                lp(&["a", "1"]),
+                vec![QUICKLIST_NODE_CONTAINER_PLAIN as u8],
+                string(b"plain"),
+            ]
+            .concat(),
+        ));
+        body.extend(record(RDB_TYPE_SET_LISTPACK, "lpset", &lp(&["p", "3"])));
+
+        let data = load(&body).unwrap();
+        assert_eq!(value(&data, "zipmap"), &hash(&[("k", "v")]));
+        assert_eq!(value(&data, "zllist"), &list(&["a", "b"]));
+        assert_eq!(value(&data, "intset"), &set(&["7"]));
+        assert_eq!(value(&data, "zlzset"), &zset(&[("a", 1.0), ("b", 2.5)]));
+        assert_eq!(value(&data, "zlhash"), &hash(&[("f", "v")]));
+        assert_eq!(value(&data, "quicklist"), &list(&["a", "b", "c"]));
+        assert_eq!(value(&data, "lphash"), &hash(&[("f", "1")]));
+        assert_eq!(value(&data, "lpzset"), &zset(&[("a", -3.0), ("b", 1.5)]));
+        assert_eq!(value(&data, "quicklist2"), &list(&["a", "1", "plain"]));
+        assert_eq!(value(&data, "lpset"), &set(&["p", "3"]));
+    }
+
+    fn raw_id(ms: u64, seq: u64) -> Vec<u8> {
+        [ms.to_be_bytes(), seq.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn loads_a_stream_with_groups() {
+        // Master entry 1000-5 with fields a and b, then 1000-5 with the master fields,
+        // 1001-0 with its own and a deleted 1002-1
+        let items = listpack_of(&[
+            "2", "1", "2", "a", "b", "0", "2", "0", "0", "1", "2", "3", "0", "1", "-5", "1", "c",
+            "3", "5", "3", "2", "-4", "9", "9", "3",
+        ]);
+        let mut payload = vec![1];
+        payload.extend(string(&raw_id(1000, 5)));
+        payload.extend(string(&items));
+        // Length, last id, first id, max deleted id, entries added
+        payload.extend_from_slice(&[2, 0x43, 0xea, 1, 0x43, 0xe8, 5, 0x43, 0xea, 1, 3]);
+        // One group with one pending entry, owned by its only consumer
+        payload.push(1);
+        payload.extend(string(b"grp"));
+        payload.extend_from_slice(&[0x43, 0xe8, 5, 1, 1]);
+        payload.extend(raw_id(1000, 5));
+        payload.extend_from_slice(&123456u64.to_le_bytes());
+        payload.extend_from_slice(&[1, 1]);
+        payload.extend(string(b"alice"));
+        payload.extend_from_slice(&10u64.to_le_bytes());
+        payload.extend_from_slice(&20u64.to_le_bytes());
+        payload.push(1);
+        payload.extend(raw_id(1000, 5));
+
+        let data = load(&record(RDB_TYPE_STREAM_LISTPACKS_3, "stream", &payload)).unwrap();
+        let id = |ms, seq| StreamId { ms, seq };
+        let expected = Stream {
+            entries: [
+                (id(1000, 5), pairs(&[("a", "1"), ("b", "2")])),
+                (id(1001, 0), pairs(&[("c", "3")])),
+            ]
+            .into(),
+            last_id: id(1002, 1),
+            first_id: id(1000, 5),
+            max_deleted_id: id(1002, 1),
+            entries_added: 3,
+            groups: vec![StreamGroup {
+                name: "grp".to_string(),
+                last_id: id(1000, 5),
+                entries_read: 1,
+                pending: vec![(id(1000, 5), 123456, 1)],
+                consumers: vec![StreamConsumer {
+                    name: "alice".to_string(),
+                    seen_time: 10,
+                    active_time: 20,
+                    pending: vec![id(1000, 5)],
+                }],
+            }],
+        };
+        assert_eq!(value(&data, "stream"), &RedisValue::Stream(expected));
+    }
+
+    fn length(content: &[u8]) -> Result<usize, String> {
+        RedisData::decode_length(content, &mut 0).map_err(|e| e.to_string())
+    }
+
+    fn blob(content: &[u8]) -> Result<Vec<u8>, String> {
+        RedisData::read_blob(content, &mut 0).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn length_encodings() {
+        assert_eq!(length(&[0x05]), Ok(5));
+        assert_eq!(length(&[0x41, 0x2c]), Ok(300));
+        assert_eq!(length(&[0x80, 0x00, 0x01, 0x00, 0x00]), Ok(65536));
+        assert_eq!(
+            length(&[0x81, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]),
+            Ok(1 << 32)
+        );
+        assert_eq!(
+            length(&[0x82]),
+            Err("invalid length 0x82 at byte 0".to_string())
+        );
+        // A string encoding where a plain length is expected
+        assert_eq!(
+            length(&[0xc0]),
+            Err("invalid length 0xc0 at byte 0".to_string())
+        );
+        assert_eq!(
+            length(&[0x81, 0x00]),
+            Err("unexpected end of file at byte 1".to_string())
+        );
+    }
+
+    #[test]
+    fn integer_and_lzf_strings() {
+        assert_eq!(blob(&[0xc0, 0xfb]), Ok(b"-5".to_vec()));
+        assert_eq!(
+            blob(&[&[0xc1][..], &(-1234i16).to_le_bytes()].concat()),
+            Ok(b"-1234".to_vec())
+        );
+        assert_eq!(
+            blob(&[&[0xc2][..], &123456789i32.to_le_bytes()].concat()),
+            Ok(b"123456789".to_vec())
+        );
+        // Compressed length, uncompressed length, then the LZF data
+        assert_eq!(
+            blob(b"\xc3\x07\x0c\x02abc\xe0\x00\x02"),
+            Ok(b"abcabcabcabc".to_vec())
+        );
+        assert_eq!(
+            blob(b"\xc3\x07\x0d\x02abc\xe0\x00\x02"),
+            Err("corrupt LZF compressed string at byte 0".to_string())
+        );
+        assert_eq!(
+            blob(&[0xc4]),
+            Err("unknown string encoding 4 at byte 0".to_string())
+        );
+        assert_eq!(
+            blob(&[0xc2, 0x01]),
+            Err("unexpected end of file at byte 1".to_string())
+        );
+    }
+
+    #[test]
+    fn long_lengths_past_the_end_of_the_file() {
+        // A 64 bit length larger than anything loaded must fail, not allocate or wrap
+        let huge = [&[0x81][..], &u64::MAX.to_be_bytes(), b"abc"].concat();
+        assert_eq!(
+            blob(&huge),
+            Err("unexpected end of file at byte 9".to_string())
+        );
+        let big = [&[0x80][..], &1000u32.to_be_bytes(), b"abc"].concat();
+        assert_eq!(
+            blob(&big),
+            Err("unexpected end of file at byte 5".to_string())
+        );
+    }
+
+    #[test]
+    fn loads_keys_and_values_in_every_string_encoding() {
+        let mut body = record(RDB_TYPE_STRING, "int", &[0xc1, 0x2e, 0xfb]);
+        // Integer encoded key
+        body.extend([&[RDB_TYPE_STRING, 0xc0, 0x07][..], &string(b"seven")].concat());
+        body.extend(record(
+            RDB_TYPE_STRING,
+            "lzf",
+            b"\xc3\x05\x28\x00a\xe0\x1e\x00",
+        ));
+        let long = "z".repeat(70000);
+        let mut encoded = vec![0x80];
+        encoded.extend_from_slice(&70000u32.to_be_bytes());
+        encoded.extend_from_slice(long.as_bytes());
+        body.extend(record(RDB_TYPE_STRING, "long", &encoded));
+        body.extend(record(
+            RDB_TYPE_SET,
+            "set",
+            &[vec![2], vec![0xc0, 0x01], string(b"x")].concat(),
+        ));
+
+        let data = load(&body).unwrap();
+        let string_value = |value: &str| RedisValue::String(value.to_string());
+        assert_eq!(value(&data, "int"), &string_value("-1234"));
+        assert_eq!(value(&data, "7"), &string_value("seven"));
+        assert_eq!(value(&data, "lzf"), &string_value(&"a".repeat(40)));
+        assert_eq!(value(&data, "long"), &string_value(&long));
+        assert_eq!(value(&data, "set"), &set(&["1", "x"]));
+    }
+
+    fn load_error(content: &[u8]) -> String {
+        let error = new_data().load_rdb(content).err();
+        error.expect("loaded a bad file").to_string()
+    }
+
+    #[test]
+    fn checks_the_header() {
+        assert_eq!(load_error(b"RDIS0011\xff"), "not an RDB file at byte 0");
+        assert_eq!(load_error(b"REDIS"), "not an RDB file at byte 0");
+        assert_eq!(
+            load_error(b"REDIS0013\xff"),
+            "unsupported RDB version '0013' at byte 5"
+        );
+        assert_eq!(
+            load_error(b"REDIS0000\xff"),
+            "unsupported RDB version '0000' at byte 5"
+        );
+    }
+
+    #[test]
+    fn verifies_the_checksum() {
+        let content = rdb_file(&record(RDB_TYPE_STRING, "k", &string(b"v")));
+        let end = content.len() - 8;
+        let expected = u64::from_le_bytes(content[end..].try_into().unwrap());
+
+        let mut corrupt = content.clone();
+        corrupt[end - 2] ^= 1;
+        assert_eq!(
+            load_error(&corrupt),
+            format!(
+                "checksum mismatch, expected {:016x} but computed {:016x} at byte {}",
+                expected,
+                crc64::crc64(0, &corrupt[..end]),
+                end
+            )
+        );
+
+        // A zero checksum means it was written with checksums off
+        let mut unchecked = content.clone();
+        unchecked[end..].fill(0);
+        assert!(new_data().load_rdb(&unchecked).is_ok());
+        // Before version 5 there is none at all
+        let mut old = b"REDIS0004".to_vec();
+        old.extend_from_slice(&content[9..end]);
+        assert!(new_data().load_rdb(&old).is_ok());
+    }
+
+    #[test]
+    fn truncated_files_fail_at_the_end() {
+        let mut body = record(RDB_TYPE_STRING, "k", &string(b"value"));
+        body.extend(record(
+            RDB_TYPE_LIST_ZIPLIST,
+            "l",
+            &string(&ziplist_of(&["a", "b"])),
+        ));
+        let content = rdb_file(&body);
+        // Reported where the read that ran out started
+        for len in 9..content.len() {
+            let error = load_error(&content[..len]);
+            let offset = error
+                .strip_prefix("unexpected end of file at byte ")
+                .and_then(|offset| offset.parse::<usize>().ok());
+            assert!(
+                offset.is_some_and(|offset| offset <= len),
+                "truncated to {} bytes: {}",
+                len,
+                error
+            );
+        }
+        // Cut in the middle of "value", which starts at byte 15
+        assert_eq!(
+            load_error(&content[..17]),
+            "unexpected end of file at byte 15"
+        );
+        // Cut right before the checksum
+        assert_eq!(
+            load_error(&content[..content.len() - 3]),
+            format!("unexpected end of file at byte {}", content.len() - 8)
+        );
+    }
+
+    #[test]
+    fn reports_where_the_file_is_bad() {
+        // Header and SELECTDB take bytes 0 to 10, the first record starts at 11
+        assert_eq!(
+            load(&[RDB_TYPE_STRING, 0x82]).err().unwrap(),
+            "invalid length 0x82 at byte 12"
+        );
+        assert_eq!(
+            load(&record(0x20, "k", &[])).err().unwrap(),
+            "unknown value type 32 at byte 11"
+        );
+        assert_eq!(
+            load(&[RDB_TYPE_STRING, 0xc4]).err().unwrap(),
+            "unknown string encoding 4 at byte 12"
+        );
+        assert_eq!(
+            load(&record(RDB_TYPE_MODULE_2, "k", &[])).err().unwrap(),
+            "module values are not supported at byte 11"
+        );
+        // The value starts after the type byte and the key
+        assert_eq!(
+            load(&record(
+                RDB_TYPE_LIST_ZIPLIST,
+                "k",
+                &string(b"\x0a\x00\x00\x00")
+            ))
+            .err()
+            .unwrap(),
+            "corrupt ziplist at byte 14"
+        );
+        assert_eq!(
+            load(&record(
+                RDB_TYPE_HASH_LISTPACK,
+                "k",
+                &string(&listpack_of(&["f"]))
+            ))
+            .err()
+            .unwrap(),
+            "corrupt hash at byte 14"
+        );
+        assert_eq!(
+            load(&record(
+                RDB_TYPE_ZSET_LISTPACK,
+                "k",
+                &string(&listpack_of(&["m", "x"]))
+            ))
+            .err()
+            .unwrap(),
+            "corrupt sorted set score at byte 14"
+        );
+        assert_eq!(
+            load(&[RDB_OPCODE_SELECTDB, 16]).err().unwrap(),
+            "database 16 is out of range at byte 11"
+        );
+    }
+
+    #[test]
+    fn a_bad_file_keeps_the_loaded_data() {
+        let mut data = load(&record(RDB_TYPE_STRING, "k", &string(b"v"))).unwrap();
+        let mut content = rdb_file(&record(RDB_TYPE_STRING, "other", &string(b"v")));
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        assert!(data.load_rdb(&content).is_err());
+        assert_eq!(value(&data, "k"), &RedisValue::String("v".to_string()));
+        assert!(!data.databases[0].data.contains_key("other"));
+    }
+
+    fn stream_listpack(items: &[&str]) -> Option<Stream> {
+        let mut stream = Stream::default();
+        let master_id = StreamId { ms: 1000, seq: 5 };
+        read_stream_listpack(&mut stream, master_id, &strings(items))?;
+        Some(stream)
+    }
+
+    #[test]
+    fn stream_listpack_skips_deleted_entries() {
+        // 1000-5 with the master field, then a deleted 1002-1
+        let stream = stream_listpack(&[
+            "1", "1", "1", "a", "0", "2", "0", "0", "1", "2", "3", "2", "-4", "7", "3",
+        ])
+        .unwrap();
+        assert_eq!(
+            stream.entries,
+            [(StreamId { ms: 1000, seq: 5 }, pairs(&[("a", "1")]))].into()
+        );
+    }
+
+    #[test]
+    fn stream_listpack_rejects_negative_and_overflowing_counts() {
+        let huge = i64::MAX.to_string();
+        let beyond = "99999999999999999999";
+        for items in [
+            // Entry count, deleted count, master field count
+            ["-1", "0", "1", "a", "0"].as_slice(),
+            ["1", "-1", "1", "a", "0"].as_slice(),
+            ["1", "0", "-1", "a", "0"].as_slice(),
+            [beyond, "0", "1", "a", "0"].as_slice(),
+            [&huge, &huge, "1", "a", "0"].as_slice(),
+            ["1", "0", &huge, "a", "0"].as_slice(),
+            // An entry with its own fields: a negative or huge field count
+            ["1", "0", "1", "a", "0", "0", "0", "0", "-1", "3"].as_slice(),
+            ["1", "0", "1", "a", "0", "0", "0", "0", &huge, "3"].as_slice(),
+            ["1", "0", "1", "a", "0", "0", "0", "0", beyond, "3"].as_slice(),
+            // Fewer items than the counts promise
+            ["2", "0", "1", "a", "0", "2", "0", "0", "1", "3"].as_slice(),
+        ] {
+            assert!(stream_listpack(items).is_none(), "accepted {:?}", items);
+        }
     }
 }
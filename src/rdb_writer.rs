@@ -0,0 +1,606 @@
+use crate::crc64;
+use crate::db::{RedisValue, Stream, StreamId};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Same format version and server version as Redis 7.2, the oldest release that can load
+// STREAM_LISTPACKS_3
+const RDB_VERSION: &str = "0011";
+pub const REDIS_VERSION: &str = "7.2.0";
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const RDB_OPCODE_AUX: u8 = 250;
+const RDB_OPCODE_RESIZEDB: u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+const RDB_OPCODE_SELECTDB: u8 = 254;
+const RDB_OPCODE_EOF: u8 = 255;
+
+const STREAM_ITEM_FLAG_SAMEFIELDS: &str = "2";
+
+// Copy of one database taken under the lock, so it can be encoded without holding it
+pub struct DbSnapshot {
+    pub data: HashMap<String, RedisValue>,
+    pub expiry: HashMap<String, SystemTime>,
+}
+
+// Whole RDB file for the given databases, checksum included. Values are written in
+// their plain encodings, which every Redis version since 7.2 can load.
+pub fn encode(databases: &[DbSnapshot]) -> Vec<u8> {
+    encode_with_aux(databases, &[])
+}
+
+// Same, with extra aux fields after the standard ones, e.g. repl-stream-db in the
+// snapshot sent to a replica
+pub fn encode_with_aux(databases: &[DbSnapshot], aux: &[(&str, String)]) -> Vec<u8> {
+    let mut out = format!("REDIS{}", RDB_VERSION).into_bytes();
+
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let standard = [
+        ("redis-ver", REDIS_VERSION.to_string()),
+        ("redis-bits", (usize::BITS).to_string()),
+        ("ctime", ctime.to_string()),
+        ("used-mem", used_memory().to_string()),
+    ];
+    for (field, value) in standard.iter().chain(aux) {
+        out.push(RDB_OPCODE_AUX);
+        write_string(&mut out, field.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+
+    for (index, db) in databases.iter().enumerate() {
+        if db.data.is_empty() {
+            continue;
+        }
+        out.push(RDB_OPCODE_SELECTDB);
+        write_length(&mut out, index as u64);
+        out.push(RDB_OPCODE_RESIZEDB);
+        write_length(&mut out, db.data.len() as u64);
+        write_length(&mut out, db.expiry.len() as u64);
+
+        for (key, value) in &db.data {
+            if let Some(time) = db.expiry.get(key) {
+                let ms = time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                out.push(RDB_OPCODE_EXPIRETIME_MS);
+                out.extend_from_slice(&ms.to_le_bytes());
+            }
+            write_object(&mut out, key, value);
+        }
+    }
+
+    out.push(RDB_OPCODE_EOF);
+    let checksum = crc64::crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+// Writes to a temporary file in the same directory, syncs it and renames it over the
+// target, so the previous version stays intact until the new one is complete. Each call
+// gets its own temporary name, so a SAVE and a BGSAVE of the same file running at once
+// never write into each other's temporary file.
+pub fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = dir.join(format!(
+        "temp-{}-{}-{}",
+        process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed),
+        name
+    ));
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    if let Err(e) = result.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Resident set size, the closest thing to Redis' used_memory without its allocator stats
+fn used_memory() -> u64 {
+    fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4096)
+}
+
+fn write_object(out: &mut Vec<u8>, key: &str, value: &RedisValue) {
+    match value {
+        RedisValue::String(string) => {
+            out.push(RDB_TYPE_STRING);
+            write_string(out, key.as_bytes());
+            write_string(out, string.as_bytes());
+        }
+        RedisValue::List(list) => {
+            out.push(RDB_TYPE_LIST);
+            write_string(out, key.as_bytes());
+            write_length(out, list.len() as u64);
+            for item in list {
+                write_string(out, item.as_bytes());
+            }
+        }
+        RedisValue::Set(set) => {
+            out.push(RDB_TYPE_SET);
+            write_string(out, key.as_bytes());
+            write_length(out, set.len() as u64);
+            for (member, _) in set.iter() {
+                write_string(out, member.as_bytes());
+            }
+        }
+        RedisValue::ZSet(zset) => {
+            out.push(RDB_TYPE_ZSET_2);
+            write_string(out, key.as_bytes());
+            write_length(out, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                write_string(out, member.as_bytes());
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        RedisValue::Hash(hash) => {
+            out.push(RDB_TYPE_HASH);
+            write_string(out, key.as_bytes());
+            write_length(out, hash.len() as u64);
+            for (field, value) in hash.iter() {
+                write_string(out, field.as_bytes());
+                write_string(out, value.as_bytes());
+            }
+        }
+        RedisValue::Stream(stream) => {
+            out.push(RDB_TYPE_STREAM_LISTPACKS_3);
+            write_string(out, key.as_bytes());
+            write_stream(out, stream);
+        }
+    }
+}
+
+// One listpack node per entry, each entry being the master entry of its own node.
+// Redis groups up to stream-node-max-entries per node, but loads either layout.
+fn write_stream(out: &mut Vec<u8>, stream: &Stream) {
+    write_length(out, stream.entries.len() as u64);
+    for (id, fields) in &stream.entries {
+        write_string(out, &stream_id_bytes(id));
+
+        let mut items = vec!["1".to_string(), "0".to_string(), fields.len().to_string()];
+        items.extend(fields.iter().map(|(field, _)| field.clone()));
+        items.push("0".to_string());
+        items.extend([STREAM_ITEM_FLAG_SAMEFIELDS, "0", "0"].map(String::from));
+        items.extend(fields.iter().map(|(_, value)| value.clone()));
+        items.push((fields.len() + 3).to_string());
+        write_string(out, &listpack(&items));
+    }
+
+    write_length(out, stream.entries.len() as u64);
+    for id in [&stream.last_id, &stream.first_id, &stream.max_deleted_id] {
+        write_length(out, id.ms);
+        write_length(out, id.seq);
+    }
+    write_length(out, stream.entries_added);
+
+    write_length(out, stream.groups.len() as u64);
+    for group in &stream.groups {
+        write_string(out, group.name.as_bytes());
+        write_length(out, group.last_id.ms);
+        write_length(out, group.last_id.seq);
+        write_length(out, group.entries_read);
+
+        write_length(out, group.pending.len() as u64);
+        for (id, delivery_time, delivery_count) in &group.pending {
+            out.extend_from_slice(&stream_id_bytes(id));
+            out.extend_from_slice(&delivery_time.to_le_bytes());
+            write_length(out, *delivery_count);
+        }
+
+        write_length(out, group.consumers.len() as u64);
+        for consumer in &group.consumers {
+            write_string(out, consumer.name.as_bytes());
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.active_time.to_le_bytes());
+            write_length(out, consumer.pending.len() as u64);
+            for id in &consumer.pending {
+                out.extend_from_slice(&stream_id_bytes(id));
+            }
+        }
+    }
+}
+
+fn stream_id_bytes(id: &StreamId) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&id.ms.to_be_bytes());
+    bytes[8..].copy_from_slice(&id.seq.to_be_bytes());
+    bytes
+}
+
+// Smallest of the 6 bit, 14 bit, 32 bit and 64 bit length formats
+fn write_length(out: &mut Vec<u8>, length: u64) {
+    if length < 1 << 6 {
+        out.push(length as u8);
+    } else if length < 1 << 14 {
+        out.push(0x40 | (length >> 8) as u8);
+        out.push(length as u8);
+    } else if length <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&length.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_length(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Listpack with the same encoding choices as Redis: strings that are the canonical form
+// of an integer are stored as integers
+fn listpack(items: &[String]) -> Vec<u8> {
+    let mut body = vec![];
+    for item in items {
+        let start = body.len();
+        match item.parse::<i64>() {
+            Ok(value) if value.to_string() == *item => encode_listpack_int(&mut body, value),
+            _ => encode_listpack_string(&mut body, item.as_bytes()),
+        }
+        let entry_len = body.len() - start;
+        encode_backlen(&mut body, entry_len);
+    }
+    body.push(0xFF);
+
+    let mut out = ((6 + body.len()) as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&(items.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+fn encode_listpack_int(out: &mut Vec<u8>, value: i64) {
+    if (0..=127).contains(&value) {
+        out.push(value as u8);
+    } else if (-4096..=4095).contains(&value) {
+        let value = (value & 0x1FFF) as u16;
+        out.push(0xC0 | (value >> 8) as u8);
+        out.push(value as u8);
+    } else if (i16::MIN as i64..=i16::MAX as i64).contains(&value) {
+        out.push(0xF1);
+        out.extend_from_slice(&(value as i16).to_le_bytes());
+    } else if (-(1 << 23)..1 << 23).contains(&value) {
+        out.push(0xF2);
+        out.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+    } else if (i32::MIN as i64..=i32::MAX as i64).contains(&value) {
+        out.push(0xF3);
+        out.extend_from_slice(&(value as i32).to_le_bytes());
+    } else {
+        out.push(0xF4);
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn encode_listpack_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    let len = bytes.len();
+    if len < 1 << 6 {
+        out.push(0x80 | len as u8);
+    } else if len < 1 << 12 {
+        out.push(0xE0 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else {
+        out.push(0xF0);
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    out.extend_from_slice(bytes);
+}
+
+// Entry size written after each entry for backwards traversal: 7 bits per byte, most
+// significant group first, every byte but the first flagged with the high bit. The sizes
+// are lpEncodeBacklen()'s, whose upper bounds are exclusive.
+fn encode_backlen(out: &mut Vec<u8>, len: usize) {
+    let bytes = match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    };
+    for i in (0..bytes).rev() {
+        let group = ((len >> (7 * i)) & 0x7F) as u8;
+        out.push(if i == bytes - 1 { group } else { group | 0x80 });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ScanMap, StreamConsumer, StreamGroup};
+    use crate::pubsub::PubSub;
+    use crate::rdb::{RedisData, ReplicationRole};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn new_data() -> RedisData {
+        RedisData::init_db(
+            16,
+            Arc::new(Mutex::new(PubSub::new())),
+            ReplicationRole::Master,
+            None,
+            None,
+        )
+    }
+
+    fn load(content: &[u8]) -> RedisData {
+        let mut data = new_data();
+        if let Err(e) = data.load_rdb(content) {
+            panic!("{}", e);
+        }
+        data
+    }
+
+    fn stream() -> Stream {
+        let id = |ms, seq| StreamId { ms, seq };
+        let fields = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(field, value)| (field.to_string(), value.to_string()))
+                .collect::<Vec<(String, String)>>()
+        };
+        Stream {
+            entries: [
+                (id(1000, 0), fields(&[("a", "1"), ("b", "two")])),
+                (id(1000, 1), fields(&[("c", "-70000")])),
+                (id(1005, 0), fields(&[("text", &"x".repeat(5000))])),
+            ]
+            .into(),
+            last_id: id(1006, 3),
+            first_id: id(1000, 0),
+            max_deleted_id: id(1006, 3),
+            entries_added: 5,
+            groups: vec![StreamGroup {
+                name: "group".to_string(),
+                last_id: id(1000, 1),
+                entries_read: 2,
+                pending: vec![
+                    (id(1000, 0), 1700000000000, 3),
+                    (id(1000, 1), 1700000000001, 1),
+                ],
+                consumers: vec![
+                    StreamConsumer {
+                        name: "alice".to_string(),
+                        seen_time: 1700000000002,
+                        active_time: 1700000000001,
+                        pending: vec![id(1000, 0), id(1000, 1)],
+                    },
+                    StreamConsumer {
+                        name: "bob".to_string(),
+                        seen_time: 5,
+                        active_time: 0,
+                        pending: vec![],
+                    },
+                ],
+            }],
+        }
+    }
+
+    fn strings<T: FromIterator<String>>(items: &[&str]) -> T {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn snapshot() -> Vec<DbSnapshot> {
+        let mut db0 = HashMap::new();
+        db0.insert(
+            "string".to_string(),
+            RedisValue::String("hello".to_string()),
+        );
+        db0.insert(
+            "number".to_string(),
+            RedisValue::String("12345".to_string()),
+        );
+        db0.insert("long".to_string(), RedisValue::String("y".repeat(70000)));
+        db0.insert(
+            "list".to_string(),
+            RedisValue::List(strings(&["a", "", "3"])),
+        );
+        db0.insert(
+            "set".to_string(),
+            RedisValue::Set(strings(&["x", "y", "-1"])),
+        );
+        db0.insert(
+            "zset".to_string(),
+            RedisValue::ZSet(
+                [
+                    ("a", 1.5),
+                    ("b", -0.25),
+                    ("c", f64::INFINITY),
+                    ("d", f64::NEG_INFINITY),
+                ]
+                .into_iter()
+                .map(|(member, score)| (member.to_string(), score))
+                .collect(),
+            ),
+        );
+        db0.insert(
+            "hash".to_string(),
+            RedisValue::Hash(
+                [("f", "v"), ("n", "7")]
+                    .into_iter()
+                    .map(|(field, value)| (field.to_string(), value.to_string()))
+                    .collect::<ScanMap<String>>(),
+            ),
+        );
+        db0.insert("stream".to_string(), RedisValue::Stream(stream()));
+
+        let mut db3 = HashMap::new();
+        db3.insert("volatile".to_string(), RedisValue::String("v".to_string()));
+        db3.insert(
+            "persistent".to_string(),
+            RedisValue::String("p".to_string()),
+        );
+        let mut expiry3 = HashMap::new();
+        expiry3.insert(
+            "volatile".to_string(),
+            UNIX_EPOCH + Duration::from_millis(4102444800123),
+        );
+
+        let mut databases = (0..16)
+            .map(|_| DbSnapshot {
+                data: HashMap::new(),
+                expiry: HashMap::new(),
+            })
+            .collect::<Vec<DbSnapshot>>();
+        databases[0].data = db0;
+        databases[3].data = db3;
+        databases[3].expiry = expiry3;
+        databases
+    }
+
+    #[test]
+    fn every_type_loads_back_unchanged() {
+        let databases = snapshot();
+        let data = load(&encode(&databases));
+        for (index, db) in databases.iter().enumerate() {
+            assert_eq!(data.databases[index].data, db.data, "db {}", index);
+            assert_eq!(data.databases[index].expiry, db.expiry, "db {}", index);
+        }
+    }
+
+    #[test]
+    fn writes_the_aux_fields() {
+        let content = encode_with_aux(&snapshot(), &[("repl-stream-db", "3".to_string())]);
+        assert!(content.starts_with(b"REDIS0011"));
+
+        let metadata = match new_data().load_rdb(&content) {
+            Ok(metadata) => metadata,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(metadata.version, 11);
+        let fields = metadata
+            .aux
+            .iter()
+            .map(|(field, _)| field.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            fields,
+            [
+                "redis-ver",
+                "redis-bits",
+                "ctime",
+                "used-mem",
+                "repl-stream-db"
+            ]
+        );
+        assert_eq!(metadata.aux[0].1, REDIS_VERSION);
+        assert_eq!(metadata.aux[4].1, "3");
+    }
+
+    fn listpack_int(value: i64) -> Vec<u8> {
+        let mut out = vec![];
+        encode_listpack_int(&mut out, value);
+        out
+    }
+
+    fn backlen(len: usize) -> Vec<u8> {
+        let mut out = vec![];
+        encode_backlen(&mut out, len);
+        out
+    }
+
+    fn length(length: u64) -> Vec<u8> {
+        let mut out = vec![];
+        write_length(&mut out, length);
+        out
+    }
+
+    #[test]
+    fn listpack_integers_as_redis_encodes_them() {
+        // The smallest encoding that fits, at both ends of every range
+        assert_eq!(listpack_int(0), [0x00]);
+        assert_eq!(listpack_int(127), [0x7f]);
+        assert_eq!(listpack_int(128), [0xc0, 0x80]);
+        assert_eq!(listpack_int(-1), [0xdf, 0xff]);
+        assert_eq!(listpack_int(4095), [0xcf, 0xff]);
+        assert_eq!(listpack_int(-4096), [0xd0, 0x00]);
+        assert_eq!(listpack_int(4096), [0xf1, 0x00, 0x10]);
+        assert_eq!(listpack_int(-4097), [0xf1, 0xff, 0xef]);
+        assert_eq!(listpack_int(32767), [0xf1, 0xff, 0x7f]);
+        assert_eq!(listpack_int(32768), [0xf2, 0x00, 0x80, 0x00]);
+        assert_eq!(listpack_int(-8388608), [0xf2, 0x00, 0x00, 0x80]);
+        assert_eq!(listpack_int(8388608), [0xf3, 0x00, 0x00, 0x80, 0x00]);
+        assert_eq!(
+            listpack_int(i32::MIN as i64),
+            [0xf3, 0x00, 0x00, 0x00, 0x80]
+        );
+        assert_eq!(
+            listpack_int(1 << 31),
+            [0xf4, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            listpack_int(i64::MIN),
+            [0xf4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]
+        );
+    }
+
+    #[test]
+    fn listpack_backlens_as_redis_encodes_them() {
+        assert_eq!(backlen(1), [0x01]);
+        assert_eq!(backlen(127), [0x7f]);
+        assert_eq!(backlen(128), [0x01, 0x80]);
+        assert_eq!(backlen(16382), [0x7f, 0xfe]);
+        // lpEncodeBacklen() switches to 3 bytes at 16383, not 16384
+        assert_eq!(backlen(16383), [0x00, 0xff, 0xff]);
+        assert_eq!(backlen(2097150), [0x7f, 0xff, 0xfe]);
+        assert_eq!(backlen(2097151), [0x00, 0xff, 0xff, 0xff]);
+        assert_eq!(backlen(268435455), [0x00, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn whole_listpacks_as_redis_writes_them() {
+        // lpNew(): just the header and the end marker
+        assert_eq!(listpack(&[]), [0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff]);
+        assert_eq!(
+            listpack(&strings::<Vec<String>>(&["1", "2", "3"])),
+            [0x0d, 0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x01, 0x02, 0x01, 0x03, 0x01, 0xff]
+        );
+        // Only the canonical form of an integer is stored as one
+        assert_eq!(
+            listpack(&strings::<Vec<String>>(&["hello", "01", "-0"])),
+            [
+                0x16, 0x00, 0x00, 0x00, 0x03, 0x00, 0x85, b'h', b'e', b'l', b'l', b'o', 0x06, 0x82,
+                b'0', b'1', 0x03, 0x82, b'-', b'0', 0x03, 0xff
+            ]
+        );
+        let long = "s".repeat(100);
+        let encoded = listpack(&strings::<Vec<String>>(&[&long]));
+        assert_eq!(encoded[6..8], [0xe0, 100]);
+        assert_eq!(encoded[108..], [0x66, 0xff]);
+    }
+
+    #[test]
+    fn rdb_lengths() {
+        assert_eq!(length(63), [0x3f]);
+        assert_eq!(length(64), [0x40, 0x40]);
+        assert_eq!(length(16383), [0x7f, 0xff]);
+        assert_eq!(length(16384), [0x80, 0x00, 0x00, 0x40, 0x00]);
+        assert_eq!(length(u32::MAX as u64), [0x80, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            length(1 << 32),
+            [0x81, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+}
@@ -1,5 +1,58 @@
+use crate::aof::AppendFsync;
+use crate::glob;
+use crate::notify;
+use crate::rdb::RedisResponse;
+use crate::sentinel;
 use crate::RedisCommand;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Values used for settings that were not passed on the command line
+const DEFAULTS: [(&str, &str); 20] = [
+    ("--dir", "."),
+    ("--dbfilename", "dump.rdb"),
+    ("--save", "3600 1 300 100 60 10000"),
+    ("--stop-writes-on-bgsave-error", "yes"),
+    ("--appendonly", "no"),
+    ("--appendfilename", "appendonly.aof"),
+    ("--appendfsync", "everysec"),
+    ("--aof-load-truncated", "yes"),
+    ("--appenddirname", "appendonlydir"),
+    ("--aof-use-rdb-preamble", "yes"),
+    ("--auto-aof-rewrite-percentage", "100"),
+    ("--auto-aof-rewrite-min-size", "64mb"),
+    ("--repl-ping-replica-period", "10"),
+    ("--repl-backlog-size", "1mb"),
+    ("--replica-read-only", "yes"),
+    ("--replica-serve-stale-data", "yes"),
+    ("--repl-diskless-sync", "yes"),
+    ("--repl-diskless-load", "disabled"),
+    ("--databases", "16"),
+    ("--notify-keyspace-events", ""),
+];
+
+// Settings CONFIG SET may change at runtime
+const SETTABLE: [&str; 15] = [
+    "notify-keyspace-events",
+    "save",
+    "stop-writes-on-bgsave-error",
+    "appendonly",
+    "appendfsync",
+    "aof-load-truncated",
+    "aof-use-rdb-preamble",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+    "repl-ping-replica-period",
+    "repl-backlog-size",
+    "replica-read-only",
+    "replica-serve-stale-data",
+    "repl-diskless-sync",
+    "repl-diskless-load",
+];
+
+// Ways a replica may load the RDB of a full synchronization
+const REPL_DISKLESS_LOAD: [&str; 3] = ["disabled", "on-empty-db", "swapdb"];
 
 pub struct RedisConfig {
     pub config: HashMap<String, String>,
@@ -10,7 +63,33 @@ impl RedisConfig {
         args.remove(0);
         let mut config = HashMap::new();
         let mut args_iter = args.iter().peekable();
-        let flags = ["--dir", "--dbfilename", "--port", "--replicaof"];
+        let flags = [
+            "--dir",
+            "--dbfilename",
+            "--port",
+            "--replicaof",
+            "--databases",
+            "--notify-keyspace-events",
+            "--save",
+            "--stop-writes-on-bgsave-error",
+            "--appendonly",
+            "--appendfilename",
+            "--appendfsync",
+            "--aof-load-truncated",
+            "--appenddirname",
+            "--aof-use-rdb-preamble",
+            "--auto-aof-rewrite-percentage",
+            "--auto-aof-rewrite-min-size",
+            "--repl-ping-replica-period",
+            "--repl-backlog-size",
+            "--replica-read-only",
+            "--replica-serve-stale-data",
+            "--repl-diskless-sync",
+            "--repl-diskless-load",
+            "--sentinel-monitor",
+            "--sentinel-down-after-milliseconds",
+            "--sentinel-failover-timeout",
+        ];
 
         while let Some(arg) = args_iter.next() {
             if flags.contains(&arg.as_str()) {
@@ -28,31 +107,420 @@ impl RedisConfig {
                 }
             }
         }
+        if let Some(classes) = config.get("--notify-keyspace-events") {
+            match notify::parse_flags(classes) {
+                Some(flags) => {
+                    config.insert(
+                        "--notify-keyspace-events".to_string(),
+                        notify::flags_to_string(flags),
+                    );
+                }
+                None => panic!("Invalid value for --notify-keyspace-events"),
+            }
+        }
+
+        if let Some(save) = config.get("--save") {
+            if parse_save_points(save).is_none() {
+                panic!("Invalid value for --save");
+            }
+        }
+        for flag in [
+            "--stop-writes-on-bgsave-error",
+            "--appendonly",
+            "--aof-load-truncated",
+            "--aof-use-rdb-preamble",
+            "--replica-read-only",
+            "--replica-serve-stale-data",
+            "--repl-diskless-sync",
+        ] {
+            if let Some(value) = config.get(flag) {
+                match parse_yes_no(value) {
+                    Some(_) => {
+                        let value = value.to_lowercase();
+                        config.insert(flag.to_string(), value);
+                    }
+                    None => panic!("Invalid value for {}", flag),
+                }
+            }
+        }
+        if let Some(value) = config.get("--auto-aof-rewrite-percentage") {
+            if value.parse::<u64>().is_err() {
+                panic!("Invalid value for --auto-aof-rewrite-percentage");
+            }
+        }
+        if let Some(value) = config.get("--repl-ping-replica-period") {
+            if !matches!(value.parse::<u64>(), Ok(period) if period > 0) {
+                panic!("Invalid value for --repl-ping-replica-period");
+            }
+        }
+        for flag in ["--auto-aof-rewrite-min-size", "--repl-backlog-size"] {
+            if let Some(value) = config.get(flag) {
+                if parse_memory(value).is_none() {
+                    panic!("Invalid value for {}", flag);
+                }
+            }
+        }
+        if let Some(value) = config.get("--appendfsync") {
+            match AppendFsync::parse(value) {
+                Some(_) => {
+                    let value = value.to_lowercase();
+                    config.insert("--appendfsync".to_string(), value);
+                }
+                None => panic!("Invalid value for --appendfsync"),
+            }
+        }
+        if let Some(value) = config.get("--repl-diskless-load") {
+            if !REPL_DISKLESS_LOAD.contains(&value.to_lowercase().as_str()) {
+                panic!("Invalid value for --repl-diskless-load");
+            }
+            let value = value.to_lowercase();
+            config.insert("--repl-diskless-load".to_string(), value);
+        }
+
+        if let Some(value) = config.get("--sentinel-monitor") {
+            if sentinel::Monitor::parse(value).is_none() {
+                panic!("Invalid value for --sentinel-monitor");
+            }
+        }
+        for flag in [
+            "--sentinel-down-after-milliseconds",
+            "--sentinel-failover-timeout",
+        ] {
+            if let Some(value) = config.get(flag) {
+                if !matches!(value.parse::<u64>(), Ok(ms) if ms > 0) {
+                    panic!("Invalid value for {}", flag);
+                }
+            }
+        }
+
+        for (flag, value) in DEFAULTS {
+            config
+                .entry(flag.to_string())
+                .or_insert_with(|| value.to_string());
+        }
         println!("{:?}", config);
-        return Self { config };
+        Self { config }
+    }
+
+    // Where SAVE / BGSAVE write the snapshot and startup loads it from
+    pub fn get_rdb_path(&self) -> PathBuf {
+        let dir = self.config.get("--dir").map_or(".", |dir| dir.as_str());
+        let file = self
+            .config
+            .get("--dbfilename")
+            .map_or("dump.rdb", |file| file.as_str());
+        Path::new(dir).join(file)
+    }
+
+    // Where AOFs were kept before the multi part format, only looked at to upgrade them
+    pub fn get_aof_path(&self) -> PathBuf {
+        let dir = self.config.get("--dir").map_or(".", |dir| dir.as_str());
+        Path::new(dir).join(self.get_appendfilename())
+    }
+
+    // appenddirname inside dir, holding the manifest and the files it lists
+    pub fn get_aof_dir(&self) -> PathBuf {
+        let dir = self.config.get("--dir").map_or(".", |dir| dir.as_str());
+        let aof_dir = self
+            .config
+            .get("--appenddirname")
+            .map_or("appendonlydir", |aof_dir| aof_dir.as_str());
+        Path::new(dir).join(aof_dir)
+    }
+
+    // Only a file name, the prefix of every file in the AOF directory
+    pub fn get_appendfilename(&self) -> String {
+        self.config
+            .get("--appendfilename")
+            .map_or("appendonly.aof", |file| file.as_str())
+            .to_string()
     }
 
     pub fn get_port(&self) -> Option<&String> {
         self.config.get("--port")
     }
 
+    pub fn get_databases(&self) -> usize {
+        match self.config.get("--databases").map(|s| s.parse::<usize>()) {
+            Some(Ok(databases)) if databases > 0 => databases,
+            _ => panic!("Invalid value for --databases"),
+        }
+    }
+
+    pub fn get_notify_keyspace_events(&self) -> u32 {
+        self.config
+            .get("--notify-keyspace-events")
+            .and_then(|classes| notify::parse_flags(classes))
+            .unwrap_or(0)
+    }
+
+    // (seconds, changes) pairs: snapshot when at least that many changes happened in
+    // that many seconds
+    pub fn get_save_points(&self) -> Vec<(u64, u64)> {
+        self.config
+            .get("--save")
+            .and_then(|save| parse_save_points(save))
+            .unwrap_or_default()
+    }
+
+    pub fn get_stop_writes_on_bgsave_error(&self) -> bool {
+        self.config
+            .get("--stop-writes-on-bgsave-error")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // Replicas refuse writes from their clients, only the master's stream changes them
+    pub fn get_replica_read_only(&self) -> bool {
+        self.config
+            .get("--replica-read-only")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // Whether a replica keeps answering with its possibly outdated data while the link to
+    // its master is down
+    pub fn get_replica_serve_stale_data(&self) -> bool {
+        self.config
+            .get("--replica-serve-stale-data")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // Full synchronizations stream the RDB to replicas that support it instead of saving
+    // it to disk first
+    pub fn get_repl_diskless_sync(&self) -> bool {
+        self.config
+            .get("--repl-diskless-sync")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // disabled, on-empty-db or swapdb
+    pub fn get_repl_diskless_load(&self) -> String {
+        self.config
+            .get("--repl-diskless-load")
+            .cloned()
+            .unwrap_or_else(|| "disabled".to_string())
+    }
+
+    pub fn get_appendonly(&self) -> bool {
+        self.config
+            .get("--appendonly")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(false)
+    }
+
+    pub fn get_appendfsync(&self) -> AppendFsync {
+        self.config
+            .get("--appendfsync")
+            .and_then(|value| AppendFsync::parse(value))
+            .unwrap_or(AppendFsync::EverySec)
+    }
+
+    pub fn get_aof_load_truncated(&self) -> bool {
+        self.config
+            .get("--aof-load-truncated")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // BGREWRITEAOF writes the base file as RDB rather than as commands
+    pub fn get_aof_use_rdb_preamble(&self) -> bool {
+        self.config
+            .get("--aof-use-rdb-preamble")
+            .and_then(|value| parse_yes_no(value))
+            .unwrap_or(true)
+    }
+
+    // Growth since the last rewrite, in percent, that starts a new one. 0 disables it.
+    pub fn get_auto_aof_rewrite_percentage(&self) -> u64 {
+        self.config
+            .get("--auto-aof-rewrite-percentage")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(100)
+    }
+
+    // AOFs smaller than this are never rewritten automatically
+    pub fn get_auto_aof_rewrite_min_size(&self) -> u64 {
+        self.config
+            .get("--auto-aof-rewrite-min-size")
+            .and_then(|value| parse_memory(value))
+            .unwrap_or(64 * 1024 * 1024)
+    }
+
+    // Seconds between the PINGs a master sends down the replication stream
+    pub fn get_repl_ping_replica_period(&self) -> u64 {
+        self.config
+            .get("--repl-ping-replica-period")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10)
+    }
+
+    // Bytes of replication stream kept for partial resynchronizations
+    pub fn get_repl_backlog_size(&self) -> usize {
+        self.config
+            .get("--repl-backlog-size")
+            .and_then(|value| parse_memory(value))
+            .map_or(1024 * 1024, |size| size.max(16 * 1024) as usize)
+    }
+
+    // Set when the process runs as a sentinel instead of a server
+    pub fn get_sentinel_monitor(&self) -> Option<sentinel::Monitor> {
+        self.config
+            .get("--sentinel-monitor")
+            .and_then(|value| sentinel::Monitor::parse(value))
+    }
+
+    // Time without a valid PING reply before an instance is considered down
+    pub fn get_sentinel_down_after(&self) -> Duration {
+        let ms = self
+            .config
+            .get("--sentinel-down-after-milliseconds")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(30000);
+        Duration::from_millis(ms)
+    }
+
+    pub fn get_sentinel_failover_timeout(&self) -> Duration {
+        let ms = self
+            .config
+            .get("--sentinel-failover-timeout")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(180000);
+        Duration::from_millis(ms)
+    }
+
+    // config set notify-keyspace-events KEA [parameter value ...]
+    pub fn set_config(&mut self, command: &RedisCommand) -> RedisResponse {
+        let args = &command.str_cmd[2..];
+        if args.is_empty() || args.len() % 2 == 1 {
+            return RedisResponse::Error(
+                "ERR wrong number of arguments for 'config|set' command".to_string(),
+            );
+        }
+
+        // Validate everything first so a bad pair leaves the whole config untouched
+        let mut updates = vec![];
+        for pair in args.chunks(2) {
+            let key = pair[0].to_lowercase();
+            let value = match key.as_str() {
+                "notify-keyspace-events" => match notify::parse_flags(&pair[1]) {
+                    Some(flags) => notify::flags_to_string(flags),
+                    None => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - Invalid event class character. Use 'Ag$lshzxeKEtmn'.",
+                            key
+                        ))
+                    }
+                },
+                "save" => match parse_save_points(&pair[1]) {
+                    Some(_) => pair[1].split_whitespace().collect::<Vec<&str>>().join(" "),
+                    None => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - Invalid save parameters",
+                            key
+                        ))
+                    }
+                },
+                "stop-writes-on-bgsave-error"
+                | "appendonly"
+                | "aof-load-truncated"
+                | "aof-use-rdb-preamble"
+                | "replica-read-only"
+                | "replica-serve-stale-data"
+                | "repl-diskless-sync" => match parse_yes_no(&pair[1]) {
+                    Some(_) => pair[1].to_lowercase(),
+                    None => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be 'yes' or 'no'",
+                            key
+                        ))
+                    }
+                },
+                "appendfsync" => match AppendFsync::parse(&pair[1]) {
+                    Some(_) => pair[1].to_lowercase(),
+                    None => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: always, everysec, no",
+                            key
+                        ))
+                    }
+                },
+                "repl-diskless-load" => {
+                    let value = pair[1].to_lowercase();
+                    if !REPL_DISKLESS_LOAD.contains(&value.as_str()) {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: disabled, on-empty-db, swapdb",
+                            key
+                        ));
+                    }
+                    value
+                }
+                "auto-aof-rewrite-percentage" => match pair[1].parse::<u64>() {
+                    Ok(percentage) => percentage.to_string(),
+                    Err(_) => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+                            key
+                        ))
+                    }
+                },
+                "repl-ping-replica-period" => match pair[1].parse::<u64>() {
+                    Ok(period) if period > 0 => period.to_string(),
+                    _ => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be between 1 and 2147483647 inclusive",
+                            key
+                        ))
+                    }
+                },
+                "auto-aof-rewrite-min-size" | "repl-backlog-size" => match parse_memory(&pair[1]) {
+                    Some(_) => pair[1].to_lowercase(),
+                    None => {
+                        return RedisResponse::Error(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be a memory value",
+                            key
+                        ))
+                    }
+                },
+                _ if SETTABLE.contains(&key.as_str()) => pair[1].clone(),
+                _ => {
+                    return RedisResponse::Error(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        key
+                    ))
+                }
+            };
+            updates.push((key, value));
+        }
+
+        for (key, value) in updates {
+            self.config.insert(format!("--{}", key), value);
+        }
+        RedisResponse::OK("OK".to_string())
+    }
+
     pub fn get_replicaof(&self) -> Option<&String> {
         self.config.get("--replicaof")
     }
 
-    pub fn set_config(&mut self, command: &RedisCommand) {}
-
     pub fn get_config(&self, command: &RedisCommand) -> String {
-        // config get dir
+        // config get dir / config get *file* port
+        let mut lst_str: Vec<String> = vec![];
 
-        let key = command.str_cmd[2].clone(); // get the key
-
-        let value = match self.config.get(&format!("--{}", &key)) {
-            Some(val) => val.clone(),
-            None => "".to_string(),
-        };
+        for pattern in command.str_cmd.iter().skip(2) {
+            for (flag, value) in &self.config {
+                let key = flag.trim_start_matches("--");
+                if glob::string_match(pattern, key, true)
+                    && !lst_str.iter().step_by(2).any(|k| k == key)
+                {
+                    lst_str.push(key.to_string());
+                    lst_str.push(value.clone());
+                }
+            }
+        }
 
-        let lst_str = vec![key, value];
         let base_str = format!("*{}\r\n", lst_str.len());
 
         let formatted_item = lst_str
@@ -60,6 +528,45 @@ impl RedisConfig {
             .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
             .collect::<String>();
 
-        return base_str + &formatted_item;
+        base_str + &formatted_item
+    }
+}
+
+// "3600 1 300 100", or "" to disable automatic snapshots
+fn parse_save_points(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|number| number.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if numbers.len() % 2 == 1 {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+// "64mb", "1gb", "100": k / m / g are powers of 1000, kb / mb / gb powers of 1024
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let units = [
+        ("kb", 1024),
+        ("mb", 1024 * 1024),
+        ("gb", 1024 * 1024 * 1024),
+        ("k", 1000),
+        ("m", 1000 * 1000),
+        ("g", 1000 * 1000 * 1000),
+        ("b", 1),
+    ];
+    let (number, multiplier) = units
+        .iter()
+        .find_map(|(unit, multiplier)| Some((value.strip_suffix(unit)?, *multiplier)))
+        .unwrap_or((value.as_str(), 1));
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
     }
 }
@@ -0,0 +1,1206 @@
+use crate::aof;
+use crate::commands;
+use crate::rdb::{RedisData, RedisResponse, ReplicationRole};
+use crate::rdb_writer::{self, DbSnapshot};
+use crate::redis_config::RedisConfig;
+use crate::{ClientState, RedisCommand};
+use bytes::{Buf, BytesMut};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::BuildHasher;
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{Notify, RwLock as AsyncRwLock};
+use tokio::task::{self, JoinHandle};
+
+// Bytes of the replication stream, on their way to one replica's connection
+pub type ReplicaSender = UnboundedSender<Vec<u8>>;
+
+// A replica connected to us, as seen from the master side
+pub struct Replica {
+    sender: ReplicaSender,
+    pub ip: String,
+    // Port it accepts clients on, from REPLCONF listening-port
+    pub port: u16,
+    // Set once it has the snapshot and is following the stream
+    pub online: bool,
+    // Offsets from its last REPLCONF ACK: applied, and made durable by its AOF
+    pub ack_offset: u64,
+    pub aof_ack_offset: u64,
+    pub last_ack: Instant,
+}
+
+// Last bytes of the replication stream, kept so a replica that lost its link can be sent
+// only what it missed. Holds the bytes at offsets offset - data.len() + 1 ..= offset.
+pub struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(size: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            size,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        if self.data.len() > self.size {
+            let excess = self.data.len() - self.size;
+            self.data.drain(..excess);
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.data.len()
+    }
+}
+
+// Replication id and offset of the dataset. On a replica they are the ones of its
+// master: the offset counts the bytes of the master's stream applied so far.
+pub struct Replication {
+    pub replid: String,
+    pub offset: u64,
+    // Id of the history before the last promotion, valid up to second_repl_offset - 1, so
+    // replicas of our former master can continue with us
+    pub replid2: String,
+    pub second_repl_offset: Option<u64>,
+    // Connected replicas by client id
+    pub replicas: HashMap<u64, Replica>,
+    // Created with the first replica, from then on every write goes through it
+    pub backlog: Option<Backlog>,
+    // repl-backlog-size
+    pub backlog_size: usize,
+    // On a replica, database selected by the master's stream when the link dropped. Set
+    // once a synchronization succeeded, replid and offset then describe a dataset a
+    // partial resynchronization can continue from.
+    pub cached_master_db: Option<usize>,
+    // Woken on every REPLCONF ACK and AOF fsync, for the clients blocked in WAIT / WAITAOF
+    pub acks: Arc<Notify>,
+    // Task keeping the link to our master while we are a replica, and its state
+    pub link: Option<JoinHandle<()>>,
+    pub master_link_up: bool,
+    pub master_last_io: Instant,
+    pub master_link_down_since: Option<Instant>,
+    pub sync_in_progress: bool,
+    // Database the stream's last command applied to, a SELECT goes out when it changes
+    selected_db: Option<usize>,
+    last_ping: Instant,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            replid: random_replid(),
+            offset: 0,
+            replid2: NO_REPLID.to_string(),
+            second_repl_offset: None,
+            replicas: HashMap::new(),
+            backlog: None,
+            backlog_size: 1024 * 1024,
+            cached_master_db: None,
+            acks: Arc::new(Notify::new()),
+            link: None,
+            master_link_up: false,
+            master_last_io: Instant::now(),
+            master_link_down_since: None,
+            sync_in_progress: false,
+            selected_db: None,
+            last_ping: Instant::now(),
+        }
+    }
+
+    // Called under the same lock as the snapshot it will be sent, so the replica gets
+    // every write made after it and none before
+    pub fn add_replica(&mut self, client_id: u64, replica: Replica) {
+        // Nobody could have followed the history without a backlog, the dataset starts
+        // a new one
+        if self.backlog.is_none() {
+            self.replid = random_replid();
+            self.backlog = Some(Backlog::new(self.backlog_size));
+        }
+        self.replicas.insert(client_id, replica);
+        // The replica starts on db 0, the stream has to select its own db again
+        self.selected_db = None;
+    }
+
+    // PSYNC <replid> <offset> continues our history if the bytes from offset on are all
+    // still in the backlog. Returns them, to be sent after +CONTINUE.
+    pub fn try_partial_resync(&self, replid: &str, offset: &str) -> Option<Vec<u8>> {
+        let backlog = self.backlog.as_ref()?;
+        let offset = offset.parse::<u64>().ok()?;
+        let first = self.offset + 1 - backlog.histlen() as u64;
+        let same_history = replid == self.replid
+            || (replid == self.replid2
+                && self
+                    .second_repl_offset
+                    .is_some_and(|second| offset <= second));
+        if !same_history || offset < first || offset > self.offset + 1 {
+            return None;
+        }
+        Some(
+            backlog
+                .data
+                .iter()
+                .skip((offset - first) as usize)
+                .copied()
+                .collect(),
+        )
+    }
+
+    // Registers a replica that continues from the backlog, it is in sync right away
+    pub fn add_partial_replica(&mut self, client_id: u64, replica: Replica) {
+        self.replicas.insert(client_id, replica);
+    }
+
+    // Full synchronization with a master: the dataset starts that master's history, our
+    // own replicas have to follow it from scratch
+    pub fn reset_history(&mut self, replid: &str, offset: u64) {
+        self.replid = replid.to_string();
+        self.offset = offset;
+        self.replid2 = NO_REPLID.to_string();
+        self.second_repl_offset = None;
+        self.backlog = Some(Backlog::new(self.backlog_size));
+        self.replicas.clear();
+    }
+
+    // Our master's history goes on under a new id, after a promotion or because our
+    // master was promoted itself. The old id stays valid up to the current offset.
+    pub fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_repl_offset = Some(self.offset + 1);
+        println!(
+            "Setting secondary replication ID to {}, valid up to offset: {}. New replication ID is {}",
+            self.replid2,
+            self.offset + 1,
+            self.replid
+        );
+    }
+
+    // Synchronized with our master, its stream is being applied
+    fn master_link_established(&mut self) {
+        self.master_link_up = true;
+        self.master_last_io = Instant::now();
+        self.master_link_down_since = None;
+        self.sync_in_progress = false;
+    }
+
+    pub fn master_link_lost(&mut self) {
+        if self.master_link_up {
+            self.master_link_down_since = Some(Instant::now());
+        }
+        self.master_link_up = false;
+        self.sync_in_progress = false;
+    }
+
+    // Closes the links of our replicas, they reconnect and resynchronize on their own
+    pub fn disconnect_replicas(&mut self) {
+        self.replicas.clear();
+    }
+
+    // CONFIG SET repl-backlog-size, the oldest history goes when it shrinks
+    pub fn resize_backlog(&mut self, size: usize) {
+        self.backlog_size = size;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.size = size;
+            backlog.trim();
+        }
+    }
+
+    pub fn remove_replica(&mut self, client_id: u64) {
+        self.replicas.remove(&client_id);
+    }
+
+    // Propagates the writes of one call to the backlog and every replica. Nothing is
+    // produced before the first replica, the offset then only moves if count_offset is set.
+    pub fn feed(&mut self, commands: &[(usize, Vec<String>)], count_offset: bool) {
+        if self.backlog.is_none() && !count_offset {
+            return;
+        }
+        let encoded = aof::encode_propagated(commands, &mut self.selected_db);
+        self.feed_raw(&encoded);
+    }
+
+    // REPLCONF GETACK *, replicas answer with the offset they reached
+    pub fn request_acks(&mut self) {
+        let getack = ["REPLCONF", "GETACK", "*"].map(String::from);
+        self.feed_raw(RedisCommand::encode(&getack).as_bytes());
+    }
+
+    // REPLCONF ACK <offset> [FACK <aofoffset>] from a replica
+    fn record_ack(&mut self, client_id: u64, args: &[String]) {
+        let Some(replica) = self.replicas.get_mut(&client_id) else {
+            return;
+        };
+        if let Some(Ok(offset)) = args.get(2).map(|offset| offset.parse::<u64>()) {
+            replica.ack_offset = offset;
+        }
+        replica.last_ack = Instant::now();
+        if args
+            .get(3)
+            .is_some_and(|arg| arg.eq_ignore_ascii_case("fack"))
+        {
+            if let Some(Ok(offset)) = args.get(4).map(|offset| offset.parse::<u64>()) {
+                replica.aof_ack_offset = offset;
+            }
+        }
+        self.acks.notify_waiters();
+    }
+
+    // Online replicas that acknowledged the offset, by applying it or with their AOF
+    fn count_acks(&self, offset: u64, aof: bool) -> u64 {
+        self.replicas
+            .values()
+            .filter(|replica| replica.online)
+            .filter(|replica| {
+                let acked = if aof {
+                    replica.aof_ack_offset
+                } else {
+                    replica.ack_offset
+                };
+                acked >= offset
+            })
+            .count() as u64
+    }
+
+    // Also the master's stream as a replica applies it, its offsets being ours
+    pub fn feed_raw(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.push(bytes);
+        }
+        for replica in self.replicas.values() {
+            let _ = replica.sender.send(bytes.to_vec());
+        }
+    }
+
+    // Replicas hear from the master at least every repl-ping-replica-period seconds, so
+    // they can tell a quiet master from a dead link
+    pub fn cron(&mut self, ping_period: u64) {
+        if self.replicas.is_empty() || self.last_ping.elapsed() < Duration::from_secs(ping_period) {
+            return;
+        }
+        self.last_ping = Instant::now();
+        self.feed_raw(RedisCommand::encode(&["PING".to_string()]).as_bytes());
+    }
+}
+
+// Length of the mark around a diskless RDB transfer
+const EOF_MARK_LEN: usize = 40;
+
+// replid2 when there was no previous history
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
+
+// 40 hex characters, like Redis' replication ids
+pub fn random_replid() -> String {
+    let state = RandomState::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let id = (0..3)
+        .map(|i| format!("{:016x}", state.hash_one((i, now, process::id()))))
+        .collect::<String>();
+    id[..40].to_string()
+}
+
+// Master side of PSYNC: the connection stops being a client and becomes a replica. It is
+// registered for the stream and, unless it can continue from the backlog, sent a snapshot
+// of the dataset. From then on it gets every write. A replica serves its own replicas the
+// same way, forwarding its master's stream byte for byte under the same replid and offsets.
+pub async fn serve_replica(
+    stream: &mut TcpStream,
+    client: &mut ClientState,
+    command: &RedisCommand,
+    buf: &mut BytesMut,
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+) -> io::Result<()> {
+    let ip = stream.peer_addr()?.ip().to_string();
+    let port = client.replica_port.unwrap_or(0);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let replica = Replica {
+        sender,
+        ip: ip.clone(),
+        port,
+        online: false,
+        ack_offset: 0,
+        aof_ack_offset: 0,
+        last_ack: Instant::now(),
+    };
+
+    let (replid, offset, snapshot, rdb_path) = {
+        let mut db = db_instances.write().await;
+        // Until then our replid and offset describe no history anyone could follow
+        if let (ReplicationRole::Slave, false) = (db.get_role(), db.replication.master_link_up) {
+            drop(db);
+            stream
+                .write_all(b"-NOMASTERLINK Can't SYNC while not connected with my master\r\n")
+                .await?;
+            return Ok(());
+        }
+        let continued = db
+            .replication
+            .try_partial_resync(&command.str_cmd[1], &command.str_cmd[2]);
+        if let Some(missing) = continued {
+            let replid = db.replication.replid.clone();
+            db.replication.add_partial_replica(
+                client.id,
+                Replica {
+                    online: true,
+                    ..replica
+                },
+            );
+            drop(db);
+            println!(
+                "Partial resynchronization request from {}:{} accepted, sending {} bytes of backlog",
+                ip,
+                port,
+                missing.len()
+            );
+            stream
+                .write_all(format!("+CONTINUE {}\r\n", replid).as_bytes())
+                .await?;
+            stream.write_all(&missing).await?;
+            return stream_to_replica(stream, client.id, &mut receiver, buf, db_instances).await;
+        }
+
+        db.replication.add_replica(client.id, replica);
+        // A master selects the db again before its next write. What a replica forwards
+        // is its own master's stream, which goes on in the db that one has selected.
+        let stream_db = match db.get_role() {
+            ReplicationRole::Slave => db.replication.cached_master_db.unwrap_or(0),
+            _ => 0,
+        };
+        // Straight to the socket needs a replica that can read the EOF-marker format,
+        // the size of the RDB is not known up front
+        let config = config_settings.read().await;
+        let rdb_path = (!config.get_repl_diskless_sync() || !client.replica_capa_eof)
+            .then(|| config.get_rdb_path());
+        (
+            db.replication.replid.clone(),
+            db.replication.offset,
+            (db.snapshot(), stream_db),
+            rdb_path,
+        )
+    };
+    println!("Replica {}:{} asks for synchronization", ip, port);
+
+    stream
+        .write_all(format!("+FULLRESYNC {} {}\r\n", replid, offset).as_bytes())
+        .await?;
+    match rdb_path {
+        // Disk-based: the RDB is saved to --dir first, then sent from the file
+        Some(path) => {
+            println!("Starting BGSAVE for SYNC with target: disk");
+            let rdb = task::spawn_blocking(move || {
+                rdb_writer::write_file(&path, &encode_snapshot(snapshot))?;
+                fs::read(&path)
+            })
+            .await
+            .map_err(io::Error::other)??;
+            stream
+                .write_all(format!("${}\r\n", rdb.len()).as_bytes())
+                .await?;
+            stream.write_all(&rdb).await?;
+        }
+        // Diskless: $EOF:<mark>, the RDB, and the mark again. The replica can only find
+        // the end if nothing follows it, the stream waits for its first REPLCONF ACK.
+        None => {
+            println!("Starting BGSAVE for SYNC with target: replicas sockets");
+            let mark = random_replid();
+            let rdb = task::spawn_blocking(move || encode_snapshot(snapshot))
+                .await
+                .map_err(io::Error::other)?;
+            stream
+                .write_all(format!("$EOF:{}\r\n", mark).as_bytes())
+                .await?;
+            stream.write_all(&rdb).await?;
+            stream.write_all(mark.as_bytes()).await?;
+            wait_for_ack(stream, client.id, buf, db_instances).await?;
+        }
+    }
+
+    if let Some(replica) = db_instances
+        .write()
+        .await
+        .replication
+        .replicas
+        .get_mut(&client.id)
+    {
+        replica.online = true;
+    }
+    println!("Synchronization with replica {}:{} succeeded", ip, port);
+    stream_to_replica(stream, client.id, &mut receiver, buf, db_instances).await
+}
+
+// Reads from the replica until its first REPLCONF ACK, which it sends once the RDB loaded
+async fn wait_for_ack(
+    stream: &mut TcpStream,
+    client_id: u64,
+    buf: &mut BytesMut,
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+) -> io::Result<()> {
+    loop {
+        while let Ok(Some((command, used))) = RedisCommand::parser_receive(buf) {
+            buf.advance(used);
+            if is_replconf(&command, "ack") {
+                db_instances
+                    .write()
+                    .await
+                    .replication
+                    .record_ack(client_id, &command.str_cmd);
+                return Ok(());
+            }
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+}
+
+// REPLCONF <option> ..., the replication link's own commands
+fn is_replconf(command: &RedisCommand, option: &str) -> bool {
+    let args = &command.str_cmd;
+    args.len() >= 2
+        && args[0].eq_ignore_ascii_case("replconf")
+        && args[1].eq_ignore_ascii_case(option)
+}
+
+// Writes the stream out as it is produced, until the replica goes away
+async fn stream_to_replica(
+    stream: &mut TcpStream,
+    client_id: u64,
+    receiver: &mut UnboundedReceiver<Vec<u8>>,
+    buf: &mut BytesMut,
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            bytes = receiver.recv() => match bytes {
+                Some(bytes) => stream.write_all(&bytes).await?,
+                // Dropped by disconnect_replicas
+                None => return Ok(()),
+            },
+            read = stream.read_buf(buf) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+                // Replicas only ever send REPLCONF ACK from here on
+                while let Ok(Some((command, used))) = RedisCommand::parser_receive(buf) {
+                    buf.advance(used);
+                    if is_replconf(&command, "ack") {
+                        db_instances
+                            .write()
+                            .await
+                            .replication
+                            .record_ack(client_id, &command.str_cmd);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Arguments of WAIT numreplicas timeout / WAITAOF numlocal numreplicas timeout
+struct WaitRequest {
+    aof: bool,
+    numlocal: u64,
+    numreplicas: u64,
+    // Milliseconds, 0 blocks until enough acknowledgements came
+    timeout: u64,
+    // Replication offset that has to be acknowledged, everything written so far
+    offset: u64,
+}
+
+fn parse_wait(db: &RedisData, command: &RedisCommand) -> Result<WaitRequest, RedisResponse> {
+    let aof = command.str_cmd[0].eq_ignore_ascii_case("waitaof");
+    if let ReplicationRole::Slave = db.get_role() {
+        return Err(RedisResponse::Error(if aof {
+            "ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.".to_string()
+        } else {
+            "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.".to_string()
+        }));
+    }
+
+    let args = &command.str_cmd[1..];
+    let not_integer =
+        || RedisResponse::Error("ERR value is not an integer or out of range".to_string());
+    let timeout = match args[args.len() - 1].parse::<i64>() {
+        Ok(timeout) if timeout < 0 => {
+            return Err(RedisResponse::Error("ERR timeout is negative".to_string()))
+        }
+        Ok(timeout) => timeout as u64,
+        Err(_) => return Err(not_integer()),
+    };
+    let counts = args[..args.len() - 1]
+        .iter()
+        .map(|count| count.parse::<i64>().map(|count| count.max(0) as u64))
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| not_integer())?;
+    let (numlocal, numreplicas) = match counts[..] {
+        [numlocal, numreplicas] => (numlocal, numreplicas),
+        [numreplicas] => (0, numreplicas),
+        _ => unreachable!(),
+    };
+    if numlocal > 0 && db.aof.is_none() {
+        return Err(RedisResponse::Error(
+            "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+                .to_string(),
+        ));
+    }
+
+    Ok(WaitRequest {
+        aof,
+        numlocal,
+        numreplicas,
+        timeout,
+        offset: db.replication.offset,
+    })
+}
+
+// (local AOF, replicas) that acknowledged the request's offset
+fn count_acks(db: &RedisData, request: &WaitRequest) -> (u64, u64) {
+    let local = db
+        .aof
+        .as_ref()
+        .is_some_and(|aof| aof.fsynced_reploff() >= request.offset);
+    (
+        local as u64,
+        db.replication.count_acks(request.offset, request.aof),
+    )
+}
+
+fn wait_reply(request: &WaitRequest, (local, replicas): (u64, u64)) -> String {
+    if request.aof {
+        format!("*2\r\n:{}\r\n:{}\r\n", local, replicas)
+    } else {
+        format!(":{}\r\n", replicas)
+    }
+}
+
+// WAIT / WAITAOF inside MULTI, which can't block: the acknowledgements there already are
+pub fn wait_now(db: &RedisData, command: &RedisCommand) -> String {
+    match parse_wait(db, command) {
+        Ok(request) => wait_reply(&request, count_acks(db, &request)),
+        Err(e) => e.to_string(),
+    }
+}
+
+// WAIT / WAITAOF: blocks the client until enough replicas, and for WAITAOF the local AOF,
+// acknowledged every write made before it, or until the timeout
+pub async fn wait(db_instances: &Arc<AsyncRwLock<RedisData>>, command: &RedisCommand) -> String {
+    let (request, acks) = {
+        let mut db = db_instances.write().await;
+        let request = match parse_wait(&db, command) {
+            Ok(request) => request,
+            Err(e) => return e.to_string(),
+        };
+        let (local, replicas) = count_acks(&db, &request);
+        if local >= request.numlocal && replicas >= request.numreplicas {
+            return wait_reply(&request, (local, replicas));
+        }
+        if replicas < request.numreplicas {
+            db.replication.request_acks();
+        }
+        (request, Arc::clone(&db.replication.acks))
+    };
+
+    let deadline =
+        (request.timeout > 0).then(|| Instant::now() + Duration::from_millis(request.timeout));
+    loop {
+        // Created before checking, so an acknowledgement arriving in between still wakes it
+        let notified = acks.notified();
+        let acked = count_acks(&*db_instances.read().await, &request);
+        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if (acked.0 >= request.numlocal && acked.1 >= request.numreplicas) || timed_out {
+            return wait_reply(&request, acked);
+        }
+        match deadline {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = notified => {}
+                    _ = tokio::time::sleep_until(deadline.into()) => {}
+                }
+            }
+            None => notified.await,
+        }
+    }
+}
+
+// REPLICAOF host port / REPLICAOF NO ONE. Both keep the dataset: a replica continues
+// from it if the new master shares its history, a promoted replica keeps serving it.
+pub async fn replicaof(
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+    command: &RedisCommand,
+) -> String {
+    let mut db = db_instances.write().await;
+    let mut config = config_settings.write().await;
+
+    if command.str_cmd[1].eq_ignore_ascii_case("no")
+        && command.str_cmd[2].eq_ignore_ascii_case("one")
+    {
+        if let ReplicationRole::Slave = db.get_role() {
+            if let Some(link) = db.replication.link.take() {
+                link.abort();
+            }
+            db.replication.shift_replid(random_replid());
+            db.replication.master_link_lost();
+            db.replication.disconnect_replicas();
+            db.replication.cached_master_db = None;
+            // Our replicas kept applying the stream in the database our master selected
+            db.replication.selected_db = None;
+            db.replication_role = ReplicationRole::Master;
+            db.host = None;
+            db.port = None;
+            config.config.remove("--replicaof");
+            println!("MASTER MODE enabled");
+        }
+        return RedisResponse::OK("OK".to_string()).to_string();
+    }
+
+    let host = command.str_cmd[1].clone();
+    let Ok(port) = command.str_cmd[2].parse::<u16>() else {
+        return RedisResponse::Error("ERR Invalid master port".to_string()).to_string();
+    };
+    if let ReplicationRole::Slave = db.get_role() {
+        if db.get_host_port() == (Some(host.clone()), Some(port)) {
+            return RedisResponse::OK("OK Already connected to specified master".to_string())
+                .to_string();
+        }
+    }
+
+    if let Some(link) = db.replication.link.take() {
+        link.abort();
+    }
+    db.replication.master_link_lost();
+    db.replication.disconnect_replicas();
+    // A former master offers its own history, the new master may be a replica that
+    // followed it and then only sends what we miss
+    if let ReplicationRole::Master = db.get_role() {
+        db.replication.cached_master_db = Some(0);
+    }
+    db.replication_role = ReplicationRole::Slave;
+    db.host = Some(host.clone());
+    db.port = Some(port);
+    config
+        .config
+        .insert("--replicaof".to_string(), format!("{} {}", host, port));
+    println!("REPLICAOF {}:{} enabled", host, port);
+
+    db.replication.link = Some(task::spawn(replica_link(
+        Arc::clone(db_instances),
+        Arc::clone(config_settings),
+        host,
+        port,
+    )));
+    RedisResponse::OK("OK".to_string()).to_string()
+}
+
+// Replica side: keeps a link to the master, reconnecting a second after it drops, like
+// Redis' replicationCron
+pub async fn replica_link(
+    db_instances: Arc<AsyncRwLock<RedisData>>,
+    config_settings: Arc<AsyncRwLock<RedisConfig>>,
+    host: String,
+    port: u16,
+) {
+    loop {
+        println!("Connecting to MASTER {}:{}", host, port);
+        if let Err(e) = sync_with_master(&db_instances, &config_settings, &host, port).await {
+            eprintln!("Replication with MASTER {}:{} failed: {}", host, port, e);
+        }
+        db_instances.write().await.replication.master_link_lost();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Handshake, full synchronization from the RDB the master sends, then the command
+// stream until the connection drops
+async fn sync_with_master(
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let mut buf = BytesMut::with_capacity(4096);
+
+    send_command(&mut stream, &["PING"]).await?;
+    let reply = read_line(&mut stream, &mut buf).await?;
+    if reply.starts_with('-') {
+        return Err(handshake_error("PING", &reply));
+    }
+
+    // Both only tell the master about us, older masters may not understand them
+    let listening_port = config_settings
+        .read()
+        .await
+        .get_port()
+        .cloned()
+        .unwrap_or_else(|| "6379".to_string());
+    for replconf in [
+        ["REPLCONF", "listening-port", listening_port.as_str()],
+        ["REPLCONF", "capa", "eof"],
+        ["REPLCONF", "capa", "psync2"],
+    ] {
+        send_command(&mut stream, &replconf).await?;
+        let reply = read_line(&mut stream, &mut buf).await?;
+        if reply.starts_with('-') {
+            eprintln!(
+                "(Non critical) Master does not understand {}: {}",
+                replconf[1], reply
+            );
+        }
+    }
+
+    // Ask to continue from where the last link left off, if anything is cached
+    let (cached_replid, cached_offset, cached_db) = {
+        let db = db_instances.read().await;
+        let replication = &db.replication;
+        (
+            replication.replid.clone(),
+            replication.offset,
+            replication.cached_master_db,
+        )
+    };
+    let psync_offset = (cached_offset + 1).to_string();
+    let psync = match cached_db {
+        Some(_) => ["PSYNC", cached_replid.as_str(), psync_offset.as_str()],
+        None => ["PSYNC", "?", "-1"],
+    };
+    send_command(&mut stream, &psync).await?;
+    let reply = read_line(&mut stream, &mut buf).await?;
+
+    let (mut master, _receiver) = ClientState::new();
+    match reply.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["+CONTINUE", ..] if cached_db.is_some() => {
+            let mut db = db_instances.write().await;
+            // Masters that do not speak psync2 leave the id out, it is unchanged then. A new
+            // one means our master was promoted.
+            if let ["+CONTINUE", replid] = reply.split_whitespace().collect::<Vec<&str>>()[..] {
+                if replid != db.replication.replid {
+                    db.replication.shift_replid(replid.to_string());
+                    db.replication.disconnect_replicas();
+                }
+            }
+            if db.replication.backlog.is_none() {
+                db.replication.backlog = Some(Backlog::new(db.replication.backlog_size));
+            }
+            master.selected_db = cached_db.unwrap_or(0);
+            db.replication.master_link_established();
+            println!("Successful partial resynchronization with master");
+        }
+        ["+FULLRESYNC", replid, offset] => {
+            let offset = offset
+                .parse::<u64>()
+                .map_err(|_| handshake_error("PSYNC", &reply))?;
+            println!("Full resync from master: {}:{}", replid, offset);
+
+            db_instances.write().await.replication.sync_in_progress = true;
+            let payload = read_bulk(&mut stream, &mut buf).await?;
+            let mut db = db_instances.write().await;
+            let config = config_settings.read().await;
+            let stream_db = load_payload(&mut db, &config, &payload)?
+                .filter(|index| *index < db.databases.len())
+                .unwrap_or(0);
+            db.replication.reset_history(replid, offset);
+            // The stream goes on in the db the snapshot says, it may not select it again
+            master.selected_db = stream_db;
+            db.replication.cached_master_db = Some(stream_db);
+            db.replication.master_link_established();
+            // The AOF described the dataset that was just replaced
+            if db.aof.is_some() {
+                db.bgrewriteaof(config.get_aof_use_rdb_preamble());
+            }
+            println!("MASTER <-> REPLICA sync: Finished with success");
+        }
+        _ => return Err(handshake_error("PSYNC", &reply)),
+    }
+
+    // Commands from the master run like a client's, but on a connection that never gets
+    // a reply and is not subject to the write restrictions of regular clients. What it
+    // has selected is cached with the offset, for the next partial resynchronization.
+    master.is_master = true;
+    let result = apply_master_stream(
+        db_instances,
+        config_settings,
+        &mut stream,
+        &mut buf,
+        &mut master,
+    )
+    .await;
+    if master.multi_queue.is_some() {
+        // The offset is past a MULTI whose commands were never applied, the next link
+        // cannot continue after it
+        db_instances.write().await.replication.cached_master_db = None;
+    }
+    result
+}
+
+// Applies the master's command stream until the connection drops. Commands between MULTI
+// and EXEC are queued and applied together, so no client sees half a transaction.
+async fn apply_master_stream(
+    db_instances: &Arc<AsyncRwLock<RedisData>>,
+    config_settings: &Arc<AsyncRwLock<RedisConfig>>,
+    stream: &mut TcpStream,
+    buf: &mut BytesMut,
+    master: &mut ClientState,
+) -> io::Result<()> {
+    let mut ack_interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let mut acks = String::new();
+        if !buf.is_empty() {
+            let mut db = db_instances.write().await;
+            let mut config = config_settings.write().await;
+            loop {
+                let (command, used) = match RedisCommand::parser_receive(buf) {
+                    Ok(Some(parsed)) => parsed,
+                    Ok(None) => break,
+                    Err(e) => {
+                        // A broken stream cannot be continued, the next link starts over
+                        db.replication.cached_master_db = None;
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                };
+                if is_replconf(&command, "getack") {
+                    // The offset up to, not including, the GETACK itself. Each one is
+                    // answered, a master waiting on a later one needs its own offset.
+                    acks += &replconf_ack(&db);
+                } else if command.str_cmd.is_empty() {
+                    // Nothing to apply, the bytes still count towards the offset
+                } else if let Ok(spec) = commands::validate(&command) {
+                    match spec.name {
+                        "multi" => master.multi_queue = Some(Vec::new()),
+                        "exec" => {
+                            for queued in master.multi_queue.take().unwrap_or_default() {
+                                commands::execute_command(&mut db, &mut config, master, &queued);
+                            }
+                        }
+                        _ => match master.multi_queue.as_mut() {
+                            Some(queue) => queue.push(command),
+                            None => {
+                                commands::execute_command(&mut db, &mut config, master, &command);
+                            }
+                        },
+                    }
+                    db.flush_propagation();
+                }
+                db.feed_master_stream(&buf[..used]);
+                buf.advance(used);
+            }
+            db.replication.cached_master_db = Some(master.selected_db);
+            db.replication.master_last_io = Instant::now();
+        }
+        if !acks.is_empty() {
+            stream.write_all(acks.as_bytes()).await?;
+        }
+
+        // Acknowledged every second too, so the master's view of us never gets stale
+        tokio::select! {
+            read = stream.read_buf(buf) => {
+                if read? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection lost",
+                    ));
+                }
+            }
+            _ = ack_interval.tick() => {
+                let ack = replconf_ack(&*db_instances.read().await);
+                stream.write_all(ack.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+// REPLCONF ACK <offset> [FACK <aofoffset>], FACK being how far our AOF is durable
+fn replconf_ack(db: &RedisData) -> String {
+    let mut args = vec![
+        "REPLCONF".to_string(),
+        "ACK".to_string(),
+        db.replication.offset.to_string(),
+    ];
+    if let Some(aof) = db.aof.as_ref() {
+        args.push("FACK".to_string());
+        args.push(aof.fsynced_reploff().to_string());
+    }
+    RedisCommand::encode(&args)
+}
+
+// Loads the RDB received from the master. repl-diskless-load decides whether it is parsed
+// straight from memory or saved to --dir and loaded from there like at startup. Either
+// way a bad payload leaves the current dataset untouched. Returns the repl-stream-db the
+// master wrote in it, if any.
+fn load_payload(
+    db: &mut RedisData,
+    config: &RedisConfig,
+    payload: &[u8],
+) -> io::Result<Option<usize>> {
+    let diskless = match config.get_repl_diskless_load().as_str() {
+        "swapdb" => true,
+        "on-empty-db" => db.databases.iter().all(|db| db.data.is_empty()),
+        _ => false,
+    };
+    let loaded = if diskless {
+        println!("MASTER <-> REPLICA sync: Loading DB in memory");
+        db.load_rdb(payload)
+    } else {
+        let path = config.get_rdb_path();
+        println!(
+            "MASTER <-> REPLICA sync: Flushing received RDB to {}",
+            path.display()
+        );
+        rdb_writer::write_file(&path, payload)?;
+        db.load_rdb(&fs::read(&path)?)
+    };
+    let metadata = loaded.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(metadata
+        .aux
+        .iter()
+        .find(|(field, _)| field == "repl-stream-db")
+        .and_then(|(_, value)| value.parse().ok()))
+}
+
+// The snapshot sent on a full resync, with the db the stream that follows it is in
+fn encode_snapshot((snapshot, stream_db): (Vec<DbSnapshot>, usize)) -> Vec<u8> {
+    rdb_writer::encode_with_aux(&snapshot, &[("repl-stream-db", stream_db.to_string())])
+}
+
+async fn send_command(stream: &mut TcpStream, args: &[&str]) -> io::Result<()> {
+    let args = args
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>();
+    stream
+        .write_all(RedisCommand::encode(&args).as_bytes())
+        .await
+}
+
+fn handshake_error(step: &str, reply: &str) -> io::Error {
+    io::Error::other(format!("unexpected reply to {}: {}", step, reply))
+}
+
+async fn read_line(stream: &mut TcpStream, buf: &mut BytesMut) -> io::Result<String> {
+    loop {
+        if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(end + 2);
+            return Ok(String::from_utf8_lossy(&line[..end]).to_string());
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+}
+
+// $<length>\r\n followed by the RDB itself, without a trailing CRLF, or from a diskless
+// master $EOF:<mark>\r\n, the RDB and the 40 byte mark. Masters send bare newlines as
+// keepalives while the snapshot is being prepared.
+async fn read_bulk(stream: &mut TcpStream, buf: &mut BytesMut) -> io::Result<Vec<u8>> {
+    let header = loop {
+        let line = read_line(stream, buf).await?;
+        let line = line.trim_start_matches('\n');
+        if !line.is_empty() {
+            break line.to_string();
+        }
+    };
+
+    if let Some(mark) = header.strip_prefix("$EOF:") {
+        if mark.len() != EOF_MARK_LEN {
+            return Err(handshake_error("PSYNC", &header));
+        }
+        // Nothing follows the mark until we acknowledge, it ends whatever was read
+        while !buf.ends_with(mark.as_bytes()) {
+            if stream.read_buf(buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+        let mut payload = buf.split().to_vec();
+        payload.truncate(payload.len() - EOF_MARK_LEN);
+        return Ok(payload);
+    }
+
+    let len = match header.strip_prefix('$').map(|len| len.parse::<usize>()) {
+        Some(Ok(len)) => len,
+        _ => return Err(handshake_error("PSYNC", &header)),
+    };
+
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+    Ok(buf.split_to(len).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica() -> (Replica, UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let replica = Replica {
+            sender,
+            ip: "127.0.0.1".to_string(),
+            port: 6380,
+            online: true,
+            ack_offset: 0,
+            aof_ack_offset: 0,
+            last_ack: Instant::now(),
+        };
+        (replica, receiver)
+    }
+
+    // A master with a backlog of size bytes that has streamed bytes so far
+    fn master(size: usize, bytes: &[u8]) -> Replication {
+        let mut replication = Replication::new();
+        replication.backlog = Some(Backlog::new(size));
+        replication.feed_raw(bytes);
+        replication
+    }
+
+    fn backlog(replication: &Replication) -> Vec<u8> {
+        let backlog = replication.backlog.as_ref().unwrap();
+        backlog.data.iter().copied().collect()
+    }
+
+    #[test]
+    fn the_backlog_keeps_the_last_bytes() {
+        let mut replication = master(8, b"abcdef");
+        assert_eq!(backlog(&replication), b"abcdef");
+        replication.feed_raw(b"ghij");
+        assert_eq!(backlog(&replication), b"cdefghij");
+        replication.feed_raw(b"0123456789abcdefghij");
+        assert_eq!(backlog(&replication), b"cdefghij");
+        assert_eq!(replication.offset, 30);
+
+        // Shrinking drops the oldest bytes, growing keeps what is there
+        replication.resize_backlog(3);
+        assert_eq!(backlog(&replication), b"hij");
+        replication.resize_backlog(5);
+        replication.feed_raw(b"kl");
+        assert_eq!(backlog(&replication), b"hijkl");
+        assert_eq!(replication.backlog.as_ref().unwrap().histlen(), 5);
+        assert_eq!(replication.backlog.as_ref().unwrap().size(), 5);
+    }
+
+    #[test]
+    fn psync_continues_from_any_offset_still_in_the_backlog() {
+        let mut replication = master(16, b"0123456789");
+        let replid = replication.replid.clone();
+        let psync = |replication: &Replication, offset: &str| {
+            replication.try_partial_resync(&replid, offset)
+        };
+
+        // PSYNC asks for the first byte it is missing, offset 1 is the first of the stream
+        assert_eq!(psync(&replication, "1"), Some(b"0123456789".to_vec()));
+        assert_eq!(psync(&replication, "6"), Some(b"56789".to_vec()));
+        assert_eq!(psync(&replication, "11"), Some(vec![]));
+        assert_eq!(psync(&replication, "12"), None);
+        assert_eq!(psync(&replication, "0"), None);
+        assert_eq!(psync(&replication, "-1"), None);
+        assert_eq!(psync(&replication, "x"), None);
+        assert_eq!(replication.try_partial_resync(&random_replid(), "1"), None);
+
+        // Offsets 5 ..= 20 are left once it wrapped
+        replication.feed_raw(b"abcdefghij");
+        assert_eq!(psync(&replication, "4"), None);
+        assert_eq!(psync(&replication, "5"), Some(b"456789abcdefghij".to_vec()));
+        assert_eq!(psync(&replication, "20"), Some(b"j".to_vec()));
+        assert_eq!(psync(&replication, "21"), Some(vec![]));
+
+        // Nothing to continue from without a backlog
+        let mut replication = Replication::new();
+        replication.feed(&[(0, vec!["SET".to_string(), "a".to_string()])], true);
+        let replid = replication.replid.clone();
+        let offset = (replication.offset + 1).to_string();
+        assert_eq!(replication.try_partial_resync(&replid, &offset), None);
+    }
+
+    #[test]
+    fn psync_follows_the_history_before_a_promotion() {
+        let mut replication = master(16, b"0123456789");
+        let old = replication.replid.clone();
+        let new = random_replid();
+        replication.shift_replid(new.clone());
+        assert_eq!(replication.second_repl_offset, Some(11));
+        replication.feed_raw(b"ab");
+
+        // A replica of the old master may have got up to offset 10, but no further
+        assert_eq!(
+            replication.try_partial_resync(&old, "11"),
+            Some(b"ab".to_vec())
+        );
+        assert_eq!(
+            replication.try_partial_resync(&old, "3"),
+            Some(b"23456789ab".to_vec())
+        );
+        assert_eq!(replication.try_partial_resync(&old, "12"), None);
+        assert_eq!(
+            replication.try_partial_resync(&new, "12"),
+            Some(b"b".to_vec())
+        );
+
+        // A full resynchronization starts over, with neither id
+        replication.reset_history(&new, 500);
+        assert_eq!(replication.try_partial_resync(&old, "11"), None);
+        assert_eq!(replication.try_partial_resync(&new, "12"), None);
+        assert_eq!(replication.try_partial_resync(&new, "501"), Some(vec![]));
+    }
+
+    #[test]
+    fn writes_reach_the_backlog_and_every_replica() {
+        let set = |key: &str| vec!["SET".to_string(), key.to_string(), "1".to_string()];
+        let mut replication = Replication::new();
+        let replid = replication.replid.clone();
+        // Before the first replica the offset only moves when asked to
+        replication.feed(&[(0, set("a"))], false);
+        assert_eq!(replication.offset, 0);
+
+        let (first, mut first_stream) = replica();
+        replication.add_replica(1, first);
+        assert_ne!(replication.replid, replid);
+        replication.feed(&[(2, set("a"))], false);
+        let select = RedisCommand::encode(&["SELECT".to_string(), "2".to_string()]);
+        let expected = select.clone() + &RedisCommand::encode(&set("a"));
+        assert_eq!(first_stream.try_recv().unwrap(), expected.as_bytes());
+        assert_eq!(backlog(&replication), expected.as_bytes());
+        assert_eq!(replication.offset, expected.len() as u64);
+
+        // A new replica starts on db 0, the next write selects again
+        let (second, mut second_stream) = replica();
+        let replid = replication.replid.clone();
+        replication.add_replica(2, second);
+        assert_eq!(replication.replid, replid);
+        replication.feed(&[(2, set("b"))], false);
+        let expected = select + &RedisCommand::encode(&set("b"));
+        assert_eq!(first_stream.try_recv().unwrap(), expected.as_bytes());
+        assert_eq!(second_stream.try_recv().unwrap(), expected.as_bytes());
+        replication.feed(&[(2, set("c"))], false);
+        let expected = RedisCommand::encode(&set("c"));
+        assert_eq!(first_stream.try_recv().unwrap(), expected.as_bytes());
+        assert_eq!(second_stream.try_recv().unwrap(), expected.as_bytes());
+
+        replication.remove_replica(1);
+        replication.request_acks();
+        assert!(first_stream.try_recv().is_err());
+        assert!(second_stream.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,92 @@
+use crate::glob;
+use crate::rdb::RedisResponse;
+
+// Options shared by SCAN, SSCAN, HSCAN and ZSCAN
+pub struct ScanOptions {
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub value_type: Option<String>, // SCAN only
+    pub novalues: bool,             // HSCAN only
+}
+
+impl ScanOptions {
+    // Parses the arguments following the cursor, e.g. [MATCH pattern] [COUNT count] [TYPE type]
+    pub fn parse(
+        args: &[String],
+        allow_type: bool,
+        allow_novalues: bool,
+    ) -> Result<Self, RedisResponse> {
+        let mut opts = ScanOptions {
+            pattern: None,
+            count: 10,
+            value_type: None,
+            novalues: false,
+        };
+
+        let mut args_iter = args.iter();
+        while let Some(arg) = args_iter.next() {
+            match arg.to_lowercase().as_str() {
+                "match" => {
+                    let pattern = args_iter.next().ok_or_else(syntax_error)?;
+                    // A lone '*' matches everything, no need to run the matcher
+                    opts.pattern = (pattern != "*").then(|| pattern.clone());
+                }
+                "count" => {
+                    let count = args_iter.next().ok_or_else(syntax_error)?;
+                    opts.count = match count.parse::<i64>() {
+                        Ok(count) if count >= 1 => count as usize,
+                        Ok(_) => return Err(syntax_error()),
+                        Err(_) => {
+                            return Err(RedisResponse::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            ))
+                        }
+                    };
+                }
+                "type" if allow_type => {
+                    let value_type = args_iter.next().ok_or_else(syntax_error)?;
+                    opts.value_type = Some(value_type.clone());
+                }
+                "novalues" if allow_novalues => {
+                    opts.novalues = true;
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        Ok(opts)
+    }
+
+    pub fn matches(&self, item: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob::string_match(pattern, item, false),
+            None => true,
+        }
+    }
+}
+
+pub fn parse_cursor(arg: &str) -> Result<u64, RedisResponse> {
+    arg.parse::<u64>()
+        .map_err(|_| RedisResponse::Error("ERR invalid cursor".to_string()))
+}
+
+// *2 [cursor, [items...]]
+pub fn format_scan_response(cursor: u64, items: &[String]) -> String {
+    let cursor = cursor.to_string();
+    let formatted_item = items
+        .iter()
+        .map(|item| format!("${}\r\n{}\r\n", item.len(), item))
+        .collect::<String>();
+
+    format!(
+        "*2\r\n${}\r\n{}\r\n*{}\r\n{}",
+        cursor.len(),
+        cursor,
+        items.len(),
+        formatted_item
+    )
+}
+
+fn syntax_error() -> RedisResponse {
+    RedisResponse::Error("ERR syntax error".to_string())
+}
@@ -0,0 +1,1382 @@
+use crate::commands;
+use crate::rdb::RedisResponse;
+use crate::redis_config::RedisConfig;
+use crate::replication;
+use crate::RedisCommand;
+use bytes::{Buf, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+
+// Sentinels announce themselves and the master they monitor on this channel of every
+// instance, which is how they find each other
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+const PING_PERIOD: Duration = Duration::from_secs(1);
+// INFO refreshes the list of replicas and their state, every second while the master is
+// down so the replica to promote is picked from fresh offsets
+const INFO_PERIOD: Duration = Duration::from_secs(10);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+// How often peers are asked whether they also see the master down
+const ASK_PERIOD: Duration = Duration::from_secs(1);
+// Replies older than this don't count towards ODOWN
+const ASK_VALIDITY: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+// Longest an election waits for votes, failover-timeout if that is shorter
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// Sentinels seeing ODOWN at once start their elections up to this much apart, so one of
+// them usually gets its votes first
+const MAX_DESYNC_MS: u64 = 1000;
+
+// Longest bulk string accepted in a reply, Redis' default proto-max-bulk-len
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Longest line without a CRLF, like Redis' limit on inline requests
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Deepest array nesting accepted in a reply, none of the replies we read nest past 2
+const MAX_REPLY_DEPTH: usize = 32;
+
+type Addr = (String, u16);
+
+// --sentinel-monitor "<name> <host> <port> <quorum>"
+pub struct Monitor {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    // Sentinels that have to see the master down before it is ODOWN
+    pub quorum: usize,
+}
+
+impl Monitor {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.split_whitespace().collect::<Vec<&str>>()[..] {
+            [name, host, port, quorum] => Some(Self {
+                name: name.to_string(),
+                host: host.to_string(),
+                port: port.parse().ok()?,
+                quorum: quorum.parse().ok().filter(|quorum| *quorum > 0)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// The monitored master or one of its replicas
+struct Instance {
+    addr: Addr,
+    last_ok: Instant,
+    // Oldest PING still without a valid reply, SDOWN once it is older than
+    // down-after-milliseconds
+    unanswered_ping: Option<Instant>,
+    last_ping: Option<Instant>,
+    ping_pending: bool,
+    last_info: Option<Instant>,
+    info_pending: bool,
+    // Last time we sent it REPLICAOF to fix its configuration
+    last_reconf: Option<Instant>,
+    // From its INFO replication
+    is_master: bool,
+    master_addr: Option<Addr>,
+    master_link_up: bool,
+    offset: u64,
+}
+
+impl Instance {
+    fn new(addr: Addr) -> Self {
+        Self {
+            addr,
+            last_ok: Instant::now(),
+            unanswered_ping: None,
+            last_ping: None,
+            ping_pending: false,
+            last_info: None,
+            info_pending: false,
+            last_reconf: None,
+            is_master: false,
+            master_addr: None,
+            master_link_up: false,
+            offset: 0,
+        }
+    }
+
+    fn is_down(&self, down_after: Duration) -> bool {
+        self.unanswered_ping
+            .is_some_and(|sent| sent.elapsed() > down_after)
+    }
+}
+
+// Another sentinel monitoring the same master, learned from its hello messages
+struct Peer {
+    addr: Addr,
+    last_hello: Instant,
+    ask_pending: bool,
+    // Its answer to our last SENTINEL IS-MASTER-DOWN-BY-ADDR
+    last_reply: Option<Instant>,
+    master_down: bool,
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+enum FailoverState {
+    // Asking the other sentinels to vote for us
+    Election,
+    // REPLICAOF NO ONE was sent, waiting for the replica to report itself master
+    Promotion(Addr),
+}
+
+struct Failover {
+    epoch: u64,
+    started: Instant,
+    state: FailoverState,
+}
+
+// Work the cron hands to tasks, so no request is made with the state locked
+enum Action {
+    Ping(Addr),
+    Info(Addr),
+    Subscribe(Addr),
+    Publish(Addr, String),
+    AskPeer(String, Addr, Vec<String>),
+    Replicaof(Addr, Option<Addr>),
+}
+
+struct Sentinel {
+    myid: String,
+    port: u16,
+    name: String,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    master: Instance,
+    replicas: HashMap<Addr, Instance>,
+    // By run id
+    peers: HashMap<String, Peer>,
+    // Highest election epoch seen, and the one of the configuration we follow
+    current_epoch: u64,
+    config_epoch: u64,
+    // Our vote in the latest epoch we were asked about, as (epoch, run id)
+    vote: Option<(u64, String)>,
+    failover: Option<Failover>,
+    // Failovers are tried again only after twice failover-timeout
+    next_failover: Instant,
+    desync: Duration,
+    sdown: bool,
+    odown: bool,
+    odown_since: Instant,
+    subscribed: HashSet<Addr>,
+    last_hello: Instant,
+    last_ask: Instant,
+}
+
+impl Sentinel {
+    fn new(config: &RedisConfig, monitor: Monitor, port: u16) -> Self {
+        let myid = replication::random_replid();
+        let desync = Duration::from_millis(RandomState::new().hash_one(&myid) % MAX_DESYNC_MS);
+        let now = Instant::now();
+        Self {
+            myid,
+            port,
+            name: monitor.name,
+            quorum: monitor.quorum,
+            down_after: config.get_sentinel_down_after(),
+            failover_timeout: config.get_sentinel_failover_timeout(),
+            master: Instance::new((monitor.host, monitor.port)),
+            replicas: HashMap::new(),
+            peers: HashMap::new(),
+            current_epoch: 0,
+            config_epoch: 0,
+            vote: None,
+            failover: None,
+            next_failover: now,
+            desync,
+            sdown: false,
+            odown: false,
+            odown_since: now,
+            subscribed: HashSet::new(),
+            last_hello: now - HELLO_PERIOD,
+            last_ask: now - ASK_PERIOD,
+        }
+    }
+
+    fn instance_mut(&mut self, addr: &Addr) -> Option<&mut Instance> {
+        if self.master.addr == *addr {
+            return Some(&mut self.master);
+        }
+        self.replicas.get_mut(addr)
+    }
+
+    fn event(&self, event: &str, detail: String) {
+        println!("{} {}", event, detail);
+    }
+
+    fn master_detail(&self) -> String {
+        format!(
+            "master {} {} {}",
+            self.name, self.master.addr.0, self.master.addr.1
+        )
+    }
+
+    // Everything that is due, run every 100ms
+    fn cron(&mut self) -> Vec<Action> {
+        let mut actions = vec![];
+        let failover_running = self.failover.is_some() || self.sdown;
+        let ping_period = PING_PERIOD.min(self.down_after);
+
+        let instances = std::iter::once(&mut self.master).chain(self.replicas.values_mut());
+        for instance in instances {
+            if !instance.ping_pending
+                && instance
+                    .last_ping
+                    .is_none_or(|last| last.elapsed() >= ping_period)
+            {
+                let now = Instant::now();
+                instance.ping_pending = true;
+                instance.last_ping = Some(now);
+                instance.unanswered_ping.get_or_insert(now);
+                actions.push(Action::Ping(instance.addr.clone()));
+            }
+            let info_period = if failover_running {
+                PING_PERIOD
+            } else {
+                INFO_PERIOD
+            };
+            if !instance.info_pending
+                && instance
+                    .last_info
+                    .is_none_or(|last| last.elapsed() >= info_period)
+            {
+                instance.info_pending = true;
+                actions.push(Action::Info(instance.addr.clone()));
+            }
+            if self.subscribed.insert(instance.addr.clone()) {
+                actions.push(Action::Subscribe(instance.addr.clone()));
+            }
+        }
+
+        if self.last_hello.elapsed() >= HELLO_PERIOD {
+            self.last_hello = Instant::now();
+            let hello = self.hello();
+            for addr in std::iter::once(&self.master.addr).chain(self.replicas.keys()) {
+                actions.push(Action::Publish(addr.clone(), hello.clone()));
+            }
+        }
+
+        self.check_down(&mut actions);
+        self.check_failover(&mut actions);
+        self.check_replicas(&mut actions);
+        actions
+    }
+
+    // <ip>,<port>,<runid>,<current_epoch>,<master_name>,<master_ip>,<master_port>,<config_epoch>
+    fn hello(&self) -> String {
+        format!(
+            "127.0.0.1,{},{},{},{},{},{},{}",
+            self.port,
+            self.myid,
+            self.current_epoch,
+            self.name,
+            self.master.addr.0,
+            self.master.addr.1,
+            self.config_epoch
+        )
+    }
+
+    // SDOWN is our own view of the master, ODOWN needs a quorum of sentinels agreeing
+    fn check_down(&mut self, actions: &mut Vec<Action>) {
+        let sdown = self.master.is_down(self.down_after);
+        if sdown != self.sdown {
+            self.sdown = sdown;
+            let event = if sdown { "+sdown" } else { "-sdown" };
+            self.event(event, self.master_detail());
+        }
+        if !sdown {
+            for peer in self.peers.values_mut() {
+                peer.master_down = false;
+            }
+        }
+
+        if sdown && self.last_ask.elapsed() >= ASK_PERIOD {
+            self.last_ask = Instant::now();
+            // While electing, the question also asks for their vote
+            let (epoch, runid) = match &self.failover {
+                Some(Failover {
+                    state: FailoverState::Election,
+                    epoch,
+                    ..
+                }) => (*epoch, self.myid.clone()),
+                _ => (self.current_epoch, "*".to_string()),
+            };
+            for (id, peer) in self.peers.iter_mut().filter(|(_, peer)| !peer.ask_pending) {
+                peer.ask_pending = true;
+                let args = vec![
+                    "SENTINEL".to_string(),
+                    "IS-MASTER-DOWN-BY-ADDR".to_string(),
+                    self.master.addr.0.clone(),
+                    self.master.addr.1.to_string(),
+                    epoch.to_string(),
+                    runid.clone(),
+                ];
+                actions.push(Action::AskPeer(id.clone(), peer.addr.clone(), args));
+            }
+        }
+
+        let agreeing = self
+            .peers
+            .values()
+            .filter(|peer| {
+                peer.master_down
+                    && peer
+                        .last_reply
+                        .is_some_and(|reply| reply.elapsed() < ASK_VALIDITY)
+            })
+            .count();
+        let odown = sdown && 1 + agreeing >= self.quorum;
+        if odown != self.odown {
+            self.odown = odown;
+            if odown {
+                self.odown_since = Instant::now();
+                self.event(
+                    "+odown",
+                    format!(
+                        "{} #quorum {}/{}",
+                        self.master_detail(),
+                        1 + agreeing,
+                        self.quorum
+                    ),
+                );
+            } else {
+                self.event("-odown", self.master_detail());
+            }
+        }
+    }
+
+    fn check_failover(&mut self, actions: &mut Vec<Action>) {
+        let Some(failover) = self.failover.as_ref() else {
+            if self.odown
+                && Instant::now() >= self.next_failover
+                && self.odown_since.elapsed() >= self.desync
+            {
+                self.current_epoch += 1;
+                let epoch = self.current_epoch;
+                self.vote = Some((epoch, self.myid.clone()));
+                self.failover = Some(Failover {
+                    epoch,
+                    started: Instant::now(),
+                    state: FailoverState::Election,
+                });
+                self.next_failover = Instant::now() + self.failover_timeout * 2;
+                // Ask for votes right away
+                self.last_ask = Instant::now() - ASK_PERIOD;
+                self.event("+new-epoch", epoch.to_string());
+                self.event("+try-failover", self.master_detail());
+            }
+            return;
+        };
+
+        match &failover.state {
+            FailoverState::Election => {
+                let epoch = failover.epoch;
+                let votes = 1 + self
+                    .peers
+                    .values()
+                    .filter(|peer| {
+                        peer.leader_epoch == epoch && peer.leader.as_ref() == Some(&self.myid)
+                    })
+                    .count();
+                let voters = self.peers.len() + 1;
+                if votes >= self.quorum.max(voters / 2 + 1) {
+                    self.event(
+                        "+elected-leader",
+                        format!(
+                            "{} #epoch {} votes {}/{}",
+                            self.master_detail(),
+                            epoch,
+                            votes,
+                            voters
+                        ),
+                    );
+                    match self.select_replica() {
+                        Some(addr) => {
+                            self.event(
+                                "+selected-slave",
+                                format!("slave {}:{} {}", addr.0, addr.1, self.master_detail()),
+                            );
+                            actions.push(Action::Replicaof(addr.clone(), None));
+                            self.failover = Some(Failover {
+                                epoch,
+                                started: Instant::now(),
+                                state: FailoverState::Promotion(addr),
+                            });
+                        }
+                        None => {
+                            self.event("-failover-abort-no-good-slave", self.master_detail());
+                            self.failover = None;
+                        }
+                    }
+                } else if failover.started.elapsed() >= ELECTION_TIMEOUT.min(self.failover_timeout)
+                {
+                    self.event("-failover-abort-not-elected", self.master_detail());
+                    self.failover = None;
+                }
+            }
+            FailoverState::Promotion(addr) => {
+                let promoted = self
+                    .replicas
+                    .get(addr)
+                    .is_some_and(|replica| replica.is_master);
+                if promoted {
+                    let (addr, epoch) = (addr.clone(), failover.epoch);
+                    self.event(
+                        "+promoted-slave",
+                        format!("slave {}:{} {}", addr.0, addr.1, self.master_detail()),
+                    );
+                    self.switch_master(addr.clone(), epoch);
+                    for replica in self.replicas.keys() {
+                        actions.push(Action::Replicaof(replica.clone(), Some(addr.clone())));
+                    }
+                    // Let the other sentinels know at once
+                    self.last_hello = Instant::now() - HELLO_PERIOD;
+                } else if failover.started.elapsed() >= self.failover_timeout {
+                    self.event("-failover-abort-slave-timeout", self.master_detail());
+                    self.failover = None;
+                }
+            }
+        }
+    }
+
+    // The replica with the most data among those that answer, ties going to the lowest
+    // address so every sentinel would pick the same one
+    fn select_replica(&self) -> Option<Addr> {
+        self.replicas
+            .values()
+            .filter(|replica| {
+                !replica.is_master
+                    && replica.last_ok.elapsed() < PING_PERIOD * 5
+                    && replica
+                        .last_info
+                        .is_some_and(|info| info.elapsed() < PING_PERIOD * 3)
+            })
+            .max_by(|a, b| a.offset.cmp(&b.offset).then(b.addr.cmp(&a.addr)))
+            .map(|replica| replica.addr.clone())
+    }
+
+    // The replica at addr is the master from now on, under the configuration epoch
+    fn switch_master(&mut self, addr: Addr, epoch: u64) {
+        self.event(
+            "+switch-master",
+            format!(
+                "{} {} {} {} {}",
+                self.name, self.master.addr.0, self.master.addr.1, addr.0, addr.1
+            ),
+        );
+        let master = self
+            .replicas
+            .remove(&addr)
+            .unwrap_or_else(|| Instance::new(addr));
+        let old = std::mem::replace(&mut self.master, master);
+        self.replicas.insert(old.addr.clone(), old);
+        // What the replicas reported was about the old master
+        for replica in self.replicas.values_mut() {
+            replica.last_info = None;
+        }
+        self.config_epoch = epoch;
+        self.failover = None;
+        self.sdown = false;
+        self.odown = false;
+        for peer in self.peers.values_mut() {
+            peer.master_down = false;
+        }
+    }
+
+    // Replicas following another master, and a former master that came back, are
+    // pointed at the current master once it is reachable and reports itself master
+    fn check_replicas(&mut self, actions: &mut Vec<Action>) {
+        if self.failover.is_some()
+            || self.sdown
+            || !self.master.is_master
+            || self.master.last_info.is_none()
+        {
+            return;
+        }
+        let master = self.master.addr.clone();
+        for replica in self.replicas.values_mut() {
+            let misconfigured = replica.is_master || replica.master_addr.as_ref() != Some(&master);
+            if misconfigured
+                && !replica.is_down(self.down_after)
+                && replica.last_info.is_some()
+                && replica
+                    .last_reconf
+                    .is_none_or(|last| last.elapsed() >= INFO_PERIOD)
+            {
+                replica.last_reconf = Some(Instant::now());
+                let event = if replica.is_master {
+                    "+convert-to-slave"
+                } else {
+                    "+fix-slave-config"
+                };
+                println!(
+                    "{} slave {}:{} master {} {} {}",
+                    event, replica.addr.0, replica.addr.1, self.name, master.0, master.1
+                );
+                actions.push(Action::Replicaof(
+                    replica.addr.clone(),
+                    Some(master.clone()),
+                ));
+            }
+        }
+    }
+
+    fn process_info(&mut self, addr: &Addr, info: &str) {
+        let fields = info
+            .lines()
+            .filter_map(|line| line.trim_end().split_once(':'))
+            .collect::<Vec<(&str, &str)>>();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| *value)
+        };
+
+        let is_master = field("role") == Some("master");
+        let master_addr = match (field("master_host"), field("master_port")) {
+            (Some(host), Some(port)) => port.parse().ok().map(|port| (host.to_string(), port)),
+            _ => None,
+        };
+        let offset = field("slave_repl_offset")
+            .or(field("master_repl_offset"))
+            .and_then(|offset| offset.parse().ok())
+            .unwrap_or(0);
+        let replicas = fields
+            .iter()
+            .filter(|(field, _)| field.starts_with("slave") && field[5..].parse::<u32>().is_ok())
+            .filter_map(|(_, value)| {
+                let mut ip = None;
+                let mut port = None;
+                for pair in value.split(',') {
+                    match pair.split_once('=') {
+                        Some(("ip", value)) => ip = Some(value.to_string()),
+                        Some(("port", value)) => port = value.parse::<u16>().ok(),
+                        _ => {}
+                    }
+                }
+                Some((ip?, port?))
+            })
+            .collect::<Vec<Addr>>();
+
+        let Some(instance) = self.instance_mut(addr) else {
+            return;
+        };
+        instance.info_pending = false;
+        instance.last_info = Some(Instant::now());
+        instance.is_master = is_master;
+        instance.master_addr = master_addr;
+        instance.master_link_up = field("master_link_status") == Some("up");
+        instance.offset = offset;
+
+        if *addr == self.master.addr && is_master {
+            for replica in replicas {
+                if !self.replicas.contains_key(&replica) && replica != self.master.addr {
+                    self.event(
+                        "+slave",
+                        format!("slave {}:{} {}", replica.0, replica.1, self.master_detail()),
+                    );
+                    self.replicas
+                        .insert(replica.clone(), Instance::new(replica));
+                }
+            }
+        }
+    }
+
+    fn process_hello(&mut self, hello: &str) {
+        let fields = hello.split(',').collect::<Vec<&str>>();
+        let [ip, port, runid, current_epoch, name, master_ip, master_port, config_epoch] =
+            fields[..]
+        else {
+            return;
+        };
+        let (Ok(port), Ok(current_epoch), Ok(master_port), Ok(config_epoch)) = (
+            port.parse::<u16>(),
+            current_epoch.parse::<u64>(),
+            master_port.parse::<u16>(),
+            config_epoch.parse::<u64>(),
+        ) else {
+            return;
+        };
+        if runid == self.myid || name != self.name {
+            return;
+        }
+
+        let addr = (ip.to_string(), port);
+        // A sentinel restarted with a new id on the same address replaces the old one
+        self.peers
+            .retain(|id, peer| id == runid || peer.addr != addr);
+        if !self.peers.contains_key(runid) {
+            self.event(
+                "+sentinel",
+                format!(
+                    "sentinel {} {} {} @ {}",
+                    runid,
+                    addr.0,
+                    addr.1,
+                    self.master_detail()
+                ),
+            );
+        }
+        let peer = self.peers.entry(runid.to_string()).or_insert_with(|| Peer {
+            addr: addr.clone(),
+            last_hello: Instant::now(),
+            ask_pending: false,
+            last_reply: None,
+            master_down: false,
+            leader: None,
+            leader_epoch: 0,
+        });
+        peer.addr = addr;
+        peer.last_hello = Instant::now();
+
+        if current_epoch > self.current_epoch {
+            self.current_epoch = current_epoch;
+            self.event("+new-epoch", current_epoch.to_string());
+        }
+        // A newer configuration, the result of a failover another sentinel led
+        let announced = (master_ip.to_string(), master_port);
+        if config_epoch > self.config_epoch && announced != self.master.addr {
+            self.switch_master(announced, config_epoch);
+        }
+    }
+
+    // SENTINEL IS-MASTER-DOWN-BY-ADDR <ip> <port> <current-epoch> <runid>: whether we
+    // see that master down and, unless runid is *, our vote for that epoch. The first
+    // sentinel asking in a new epoch gets it.
+    fn is_master_down_by_addr(&mut self, addr: Addr, epoch: u64, runid: &str) -> String {
+        let down = self.master.addr == addr && self.sdown;
+        let mut leader = ("*".to_string(), 0);
+        if runid != "*" {
+            if epoch > self.current_epoch {
+                self.current_epoch = epoch;
+                self.event("+new-epoch", epoch.to_string());
+            }
+            // Never in an epoch we already know is over, and only once per epoch
+            if epoch >= self.current_epoch
+                && self.vote.as_ref().is_none_or(|(voted, _)| *voted < epoch)
+            {
+                self.vote = Some((epoch, runid.to_string()));
+                self.event("+vote-for-leader", format!("{} {}", runid, epoch));
+                // Give the sentinel we voted for time to complete its failover
+                if runid != self.myid {
+                    self.next_failover = Instant::now() + self.failover_timeout * 2;
+                }
+            }
+            if let Some((voted, id)) = &self.vote {
+                leader = (id.clone(), *voted);
+            }
+        }
+        format!(
+            "*3\r\n:{}\r\n${}\r\n{}\r\n:{}\r\n",
+            down as u8,
+            leader.0.len(),
+            leader.0,
+            leader.1
+        )
+    }
+
+    fn process_peer_reply(&mut self, runid: &str, reply: Option<Reply>) {
+        let Some(peer) = self.peers.get_mut(runid) else {
+            return;
+        };
+        peer.ask_pending = false;
+        if let Some(Reply::Array(Some(items))) = reply {
+            if let [Reply::Integer(down), Reply::Bulk(Some(leader)), Reply::Integer(epoch)] =
+                &items[..]
+            {
+                peer.last_reply = Some(Instant::now());
+                peer.master_down = *down == 1;
+                if leader != "*" {
+                    peer.leader = Some(leader.clone());
+                    peer.leader_epoch = *epoch as u64;
+                }
+            }
+        }
+    }
+
+    fn master_flags(&self) -> String {
+        let mut flags = vec!["master"];
+        if self.sdown {
+            flags.push("s_down");
+        }
+        if self.odown {
+            flags.push("o_down");
+        }
+        if self.failover.is_some() {
+            flags.push("failover_in_progress");
+        }
+        flags.join(",")
+    }
+
+    fn command(&mut self, command: &RedisCommand) -> String {
+        let args = &command.str_cmd;
+        match args[0].to_lowercase().as_str() {
+            "ping" => RedisResponse::OK("PONG".to_string()).to_string(),
+            "info" => {
+                let status = if self.odown {
+                    "odown"
+                } else if self.sdown {
+                    "sdown"
+                } else {
+                    "ok"
+                };
+                let info = format!(
+                    "# Sentinel\r\nsentinel_masters:1\r\nmaster0:name={},status={},address={}:{},slaves={},sentinels={}\r\n",
+                    self.name,
+                    status,
+                    self.master.addr.0,
+                    self.master.addr.1,
+                    self.replicas.len(),
+                    self.peers.len() + 1
+                );
+                format!("${}\r\n{}\r\n", info.len(), info)
+            }
+            "sentinel" if args.len() >= 2 => self.sentinel_command(&args[1..]),
+            "sentinel" => commands::wrong_arity("sentinel").to_string(),
+            _ => {
+                let rest = args[1..]
+                    .iter()
+                    .map(|arg| format!("'{}' ", arg))
+                    .collect::<String>();
+                RedisResponse::Error(format!(
+                    "ERR unknown command '{}', with args beginning with: {}",
+                    args[0], rest
+                ))
+                .to_string()
+            }
+        }
+    }
+
+    fn sentinel_command(&mut self, args: &[String]) -> String {
+        let unknown_master =
+            || RedisResponse::Error("ERR No such master with that name".to_string());
+        match (args[0].to_lowercase().as_str(), args.len()) {
+            ("get-master-addr-by-name", 2) => {
+                if args[1] != self.name {
+                    return "*-1\r\n".to_string();
+                }
+                RedisCommand::encode(&[self.master.addr.0.clone(), self.master.addr.1.to_string()])
+            }
+            ("is-master-down-by-addr", 5) => {
+                match (args[2].parse::<u16>(), args[3].parse::<u64>()) {
+                    (Ok(port), Ok(epoch)) => {
+                        self.is_master_down_by_addr((args[1].clone(), port), epoch, &args[4])
+                    }
+                    _ => RedisResponse::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    )
+                    .to_string(),
+                }
+            }
+            ("myid", 1) => format!("${}\r\n{}\r\n", self.myid.len(), self.myid),
+            ("master", 2) if args[1] == self.name => RedisCommand::encode(&[
+                "name".to_string(),
+                self.name.clone(),
+                "ip".to_string(),
+                self.master.addr.0.clone(),
+                "port".to_string(),
+                self.master.addr.1.to_string(),
+                "flags".to_string(),
+                self.master_flags(),
+                "num-slaves".to_string(),
+                self.replicas.len().to_string(),
+                "num-other-sentinels".to_string(),
+                self.peers.len().to_string(),
+                "quorum".to_string(),
+                self.quorum.to_string(),
+                "config-epoch".to_string(),
+                self.config_epoch.to_string(),
+                "down-after-milliseconds".to_string(),
+                self.down_after.as_millis().to_string(),
+                "failover-timeout".to_string(),
+                self.failover_timeout.as_millis().to_string(),
+            ]),
+            ("replicas" | "slaves", 2) if args[1] == self.name => {
+                let mut replicas = self.replicas.values().collect::<Vec<&Instance>>();
+                replicas.sort_by(|a, b| a.addr.cmp(&b.addr));
+                let items = replicas
+                    .iter()
+                    .map(|replica| {
+                        let mut flags = vec![if replica.is_master { "master" } else { "slave" }];
+                        if replica.is_down(self.down_after) {
+                            flags.push("s_down");
+                        }
+                        RedisCommand::encode(&[
+                            "name".to_string(),
+                            format!("{}:{}", replica.addr.0, replica.addr.1),
+                            "ip".to_string(),
+                            replica.addr.0.clone(),
+                            "port".to_string(),
+                            replica.addr.1.to_string(),
+                            "flags".to_string(),
+                            flags.join(","),
+                            "master-link-status".to_string(),
+                            if replica.master_link_up { "ok" } else { "err" }.to_string(),
+                            "slave-repl-offset".to_string(),
+                            replica.offset.to_string(),
+                        ])
+                    })
+                    .collect::<String>();
+                format!("*{}\r\n{}", replicas.len(), items)
+            }
+            ("sentinels", 2) if args[1] == self.name => {
+                let items = self
+                    .peers
+                    .iter()
+                    .map(|(id, peer)| {
+                        RedisCommand::encode(&[
+                            "name".to_string(),
+                            id.clone(),
+                            "ip".to_string(),
+                            peer.addr.0.clone(),
+                            "port".to_string(),
+                            peer.addr.1.to_string(),
+                            "runid".to_string(),
+                            id.clone(),
+                            "flags".to_string(),
+                            "sentinel".to_string(),
+                            "last-hello-message".to_string(),
+                            peer.last_hello.elapsed().as_millis().to_string(),
+                        ])
+                    })
+                    .collect::<String>();
+                format!("*{}\r\n{}", self.peers.len(), items)
+            }
+            ("master" | "replicas" | "slaves" | "sentinels", 2) => unknown_master().to_string(),
+            (subcommand, _) => RedisResponse::Error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try SENTINEL HELP.",
+                subcommand
+            ))
+            .to_string(),
+        }
+    }
+}
+
+// Sentinel mode: instead of serving a dataset the process watches the master of
+// --sentinel-monitor and its replicas, and with the other sentinels promotes a replica
+// when the master is down
+pub async fn run(config: RedisConfig, monitor: Monitor) {
+    let port = config
+        .get_port()
+        .and_then(|port| port.parse::<u16>().ok())
+        .unwrap_or(26379);
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .expect("Failed to bind port");
+
+    println!(
+        "Sentinel monitoring master {} {}:{} quorum {}",
+        monitor.name, monitor.host, monitor.port, monitor.quorum
+    );
+    let sentinel = Arc::new(Mutex::new(Sentinel::new(&config, monitor, port)));
+    println!("Sentinel ID is {}", sentinel.lock().unwrap().myid);
+
+    let cron_sentinel = Arc::clone(&sentinel);
+    task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let actions = cron_sentinel.lock().unwrap().cron();
+            for action in actions {
+                task::spawn(perform(Arc::clone(&cron_sentinel), action));
+            }
+        }
+    });
+
+    loop {
+        let (mut socket, _) = listener
+            .accept()
+            .await
+            .expect("Failed to accept connection");
+        let sentinel = Arc::clone(&sentinel);
+        task::spawn(async move {
+            if let Err(e) = serve_client(&mut socket, &sentinel).await {
+                eprintln!("Failed to handle connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn serve_client(stream: &mut TcpStream, sentinel: &Arc<Mutex<Sentinel>>) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+        loop {
+            let (command, used) = match RedisCommand::parser_receive(&buf) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => break,
+                Err(e) => {
+                    let response = RedisResponse::Error(format!("ERR Protocol error: {}", e));
+                    stream.write_all(response.to_string().as_bytes()).await?;
+                    return Ok(());
+                }
+            };
+            buf.advance(used);
+            if command.str_cmd.is_empty() {
+                continue;
+            }
+            if command.str_cmd[0].eq_ignore_ascii_case("quit") {
+                stream.write_all(b"+OK\r\n").await?;
+                return Ok(());
+            }
+            let response = sentinel.lock().unwrap().command(&command);
+            stream.write_all(response.as_bytes()).await?;
+        }
+    }
+}
+
+// Runs one action of the cron and records its outcome
+async fn perform(sentinel: Arc<Mutex<Sentinel>>, action: Action) {
+    match action {
+        Action::Ping(addr) => {
+            let reply = request(&addr, &["PING"]).await;
+            // Still loading or cut off from its own master, but alive
+            let valid = match &reply {
+                Ok(Reply::Simple(pong)) => pong == "PONG",
+                Ok(Reply::Error(e)) => e.starts_with("LOADING") || e.starts_with("MASTERDOWN"),
+                _ => false,
+            };
+            let mut sentinel = sentinel.lock().unwrap();
+            if let Some(instance) = sentinel.instance_mut(&addr) {
+                instance.ping_pending = false;
+                if valid {
+                    instance.last_ok = Instant::now();
+                    instance.unanswered_ping = None;
+                }
+            }
+        }
+        Action::Info(addr) => {
+            let reply = request(&addr, &["INFO", "replication"]).await;
+            let mut sentinel = sentinel.lock().unwrap();
+            match reply {
+                Ok(Reply::Bulk(Some(info))) => sentinel.process_info(&addr, &info),
+                _ => {
+                    if let Some(instance) = sentinel.instance_mut(&addr) {
+                        instance.info_pending = false;
+                    }
+                }
+            }
+        }
+        Action::Subscribe(addr) => {
+            if let Err(e) = subscribe_hello(&sentinel, &addr).await {
+                if e.kind() != io::ErrorKind::ConnectionRefused {
+                    eprintln!("Hello subscription to {}:{} lost: {}", addr.0, addr.1, e);
+                }
+            }
+            // Retried by the cron after a short wait
+            tokio::time::sleep(PING_PERIOD).await;
+            sentinel.lock().unwrap().subscribed.remove(&addr);
+        }
+        Action::Publish(addr, hello) => {
+            let _ = request(&addr, &["PUBLISH", HELLO_CHANNEL, &hello]).await;
+        }
+        Action::AskPeer(runid, addr, args) => {
+            let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+            let reply = request(&addr, &args).await.ok();
+            sentinel.lock().unwrap().process_peer_reply(&runid, reply);
+        }
+        Action::Replicaof(addr, master) => {
+            let args = match &master {
+                Some((host, port)) => vec!["REPLICAOF".to_string(), host.clone(), port.to_string()],
+                None => vec!["REPLICAOF".to_string(), "NO".to_string(), "ONE".to_string()],
+            };
+            let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+            if let Err(e) = request(&addr, &args).await {
+                eprintln!("REPLICAOF to {}:{} failed: {}", addr.0, addr.1, e);
+            }
+        }
+    }
+}
+
+// Listens to the hello channel of one instance until the connection drops
+async fn subscribe_hello(sentinel: &Arc<Mutex<Sentinel>>, addr: &Addr) -> io::Result<()> {
+    let mut stream = tokio::time::timeout(REQUEST_TIMEOUT, TcpStream::connect(addr.clone()))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let mut buf = BytesMut::with_capacity(1024);
+    send(&mut stream, &["SUBSCRIBE", HELLO_CHANNEL]).await?;
+    loop {
+        if let Reply::Array(Some(items)) = read_reply(&mut stream, &mut buf).await? {
+            if let [Reply::Bulk(Some(kind)), _, Reply::Bulk(Some(hello))] = &items[..] {
+                if kind == "message" {
+                    sentinel.lock().unwrap().process_hello(hello);
+                }
+            }
+        }
+    }
+}
+
+// A reply from one of the instances or sentinels we talk to
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Option<Vec<Reply>>),
+}
+
+// Sends one command on a new connection and waits for its reply
+async fn request(addr: &Addr, args: &[&str]) -> io::Result<Reply> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr.clone()).await?;
+        let mut buf = BytesMut::with_capacity(1024);
+        send(&mut stream, args).await?;
+        read_reply(&mut stream, &mut buf).await
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+async fn send(stream: &mut TcpStream, args: &[&str]) -> io::Result<()> {
+    let args = args
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<String>>();
+    stream
+        .write_all(RedisCommand::encode(&args).as_bytes())
+        .await
+}
+
+async fn read_reply(stream: &mut TcpStream, buf: &mut BytesMut) -> io::Result<Reply> {
+    let mut parser = ReplyParser::default();
+    loop {
+        match parser.parse(buf) {
+            Ok(Some(reply)) => return Ok(reply),
+            Ok(None) => {}
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+}
+
+// One parsed line of a reply: a whole value, or the header of an array whose elements
+// follow
+enum Element {
+    Value(Reply),
+    Array(usize),
+}
+
+// Parses a reply as it arrives. Complete elements are taken off the buffer right away and
+// nested arrays are kept on a stack instead of the call stack, so every byte is parsed
+// once and deep nesting cannot overflow.
+#[derive(Default)]
+struct ReplyParser {
+    // Arrays still missing elements, innermost last
+    pending: Vec<(usize, Vec<Reply>)>,
+}
+
+impl ReplyParser {
+    // None while the buffer holds only part of the reply
+    fn parse(&mut self, buf: &mut BytesMut) -> Result<Option<Reply>, String> {
+        loop {
+            let Some((element, used)) = parse_element(buf)? else {
+                return Ok(None);
+            };
+            buf.advance(used);
+            let mut reply = match element {
+                Element::Value(reply) => reply,
+                Element::Array(0) => Reply::Array(Some(vec![])),
+                Element::Array(_) if self.pending.len() >= MAX_REPLY_DEPTH => {
+                    return Err("reply nested too deeply".to_string());
+                }
+                Element::Array(len) => {
+                    self.pending.push((len, Vec::new()));
+                    continue;
+                }
+            };
+
+            // Hand the value to its array, which may complete the arrays around it too
+            loop {
+                let Some((missing, items)) = self.pending.last_mut() else {
+                    return Ok(Some(reply));
+                };
+                items.push(reply);
+                *missing -= 1;
+                if *missing > 0 {
+                    break;
+                }
+                let (_, items) = self.pending.pop().unwrap();
+                reply = Reply::Array(Some(items));
+            }
+        }
+    }
+}
+
+// The element at the front of buf and its length in bytes, None if it is incomplete
+fn parse_element(buf: &[u8]) -> Result<Option<(Element, usize)>, String> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() > MAX_LINE_LEN {
+            return Err("reply line too long".to_string());
+        }
+        return Ok(None);
+    };
+    let line = String::from_utf8_lossy(&buf[..end]).to_string();
+    let cursor = end + 2;
+    let (kind, rest) = line.split_at(line.len().min(1));
+    let number = || {
+        rest.parse::<i64>()
+            .map_err(|_| format!("invalid reply: {}", line))
+    };
+
+    let value = match kind {
+        "+" => Reply::Simple(rest.to_string()),
+        "-" => Reply::Error(rest.to_string()),
+        ":" => Reply::Integer(number()?),
+        "$" => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Some((Element::Value(Reply::Bulk(None)), cursor)));
+            }
+            let end = usize::try_from(len)
+                .ok()
+                .filter(|len| *len <= MAX_BULK_LEN)
+                .map(|len| cursor + len)
+                .ok_or_else(|| format!("invalid bulk length: {}", line))?;
+            if buf.len() < end + 2 {
+                return Ok(None);
+            }
+            let value = String::from_utf8_lossy(&buf[cursor..end]).to_string();
+            return Ok(Some((Element::Value(Reply::Bulk(Some(value))), end + 2)));
+        }
+        "*" => match number()? {
+            len if len < 0 => Reply::Array(None),
+            len => {
+                let len = usize::try_from(len)
+                    .map_err(|_| format!("invalid multibulk length: {}", line))?;
+                return Ok(Some((Element::Array(len), cursor)));
+            }
+        },
+        _ => return Err(format!("invalid reply: {}", line)),
+    };
+    Ok(Some((Element::Value(value), cursor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<Option<Reply>, String> {
+        ReplyParser::default().parse(&mut BytesMut::from(input))
+    }
+
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(value.to_string()))
+    }
+
+    fn sentinel() -> Sentinel {
+        let config = RedisConfig::parse_argument(vec!["redis-server".to_string()]);
+        let monitor = Monitor::parse("mymaster 127.0.0.1 6379 2").unwrap();
+        Sentinel::new(&config, monitor, 26379)
+    }
+
+    // master is "<ip>,<port>"
+    fn hello(runid: &str, current_epoch: u64, master: &str, config_epoch: u64) -> String {
+        format!(
+            "127.0.0.1,26380,{},{},mymaster,{},{}",
+            runid, current_epoch, master, config_epoch
+        )
+    }
+
+    #[test]
+    fn parses_every_reply_type() {
+        assert_eq!(parse(b"+OK\r\n"), Ok(Some(Reply::Simple("OK".to_string()))));
+        assert_eq!(
+            parse(b"-ERR nope\r\n"),
+            Ok(Some(Reply::Error("ERR nope".to_string())))
+        );
+        assert_eq!(parse(b":-42\r\n"), Ok(Some(Reply::Integer(-42))));
+        assert_eq!(parse(b"$5\r\nhello\r\n"), Ok(Some(bulk("hello"))));
+        assert_eq!(parse(b"$0\r\n\r\n"), Ok(Some(bulk(""))));
+        assert_eq!(parse(b"$-1\r\n"), Ok(Some(Reply::Bulk(None))));
+        assert_eq!(parse(b"*-1\r\n"), Ok(Some(Reply::Array(None))));
+        assert_eq!(parse(b"*0\r\n"), Ok(Some(Reply::Array(Some(vec![])))));
+        assert_eq!(
+            parse(b"*3\r\n:1\r\n*2\r\n$1\r\na\r\n*0\r\n$1\r\nb\r\n"),
+            Ok(Some(Reply::Array(Some(vec![
+                Reply::Integer(1),
+                Reply::Array(Some(vec![bulk("a"), Reply::Array(Some(vec![]))])),
+                bulk("b"),
+            ]))))
+        );
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_reply_and_leaves_what_follows() {
+        let input = b"*2\r\n$5\r\nhello\r\n*1\r\n:7\r\n+NEXT\r\n";
+        let mut parser = ReplyParser::default();
+        let mut buf = BytesMut::new();
+        let mut parsed = None;
+        // One byte per read, what was parsed is never parsed again
+        for (i, byte) in input.iter().enumerate() {
+            buf.extend_from_slice(&[*byte]);
+            if let Some(reply) = parser.parse(&mut buf).unwrap() {
+                parsed = Some((reply, i));
+                break;
+            }
+        }
+        let (reply, last) = parsed.unwrap();
+        assert_eq!(
+            reply,
+            Reply::Array(Some(vec![
+                bulk("hello"),
+                Reply::Array(Some(vec![Reply::Integer(7)])),
+            ]))
+        );
+        assert_eq!(last, input.len() - b"+NEXT\r\n".len() - 1);
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&input[..]);
+        parser.parse(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"+NEXT\r\n");
+        assert_eq!(parse(b"$5\r\nhel"), Ok(None));
+        assert_eq!(parse(b"*2\r\n:1\r\n"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_replies() {
+        assert!(parse(b"?what\r\n").is_err());
+        assert!(parse(b":12x\r\n").is_err());
+        assert!(parse(b"$abc\r\n").is_err());
+        assert!(parse(b"*abc\r\n").is_err());
+        // Longer than proto-max-bulk-len, or past what fits in memory at all
+        assert!(parse(b"$536870913\r\n").is_err());
+        assert!(parse(b"$9223372036854775807\r\n").is_err());
+        assert!(parse(b"$99999999999999999999\r\n").is_err());
+        assert!(parse(&vec![b'+'; MAX_LINE_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn caps_the_nesting_depth() {
+        let nested = |depth: usize| {
+            let mut input = b"*1\r\n".repeat(depth);
+            input.extend_from_slice(b":1\r\n");
+            input
+        };
+        assert!(parse(&nested(MAX_REPLY_DEPTH)).unwrap().is_some());
+        assert!(parse(&nested(MAX_REPLY_DEPTH + 1)).is_err());
+        assert!(parse(&nested(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn parses_the_monitor_option() {
+        let monitor = Monitor::parse("mymaster 10.0.0.1 6380 2").unwrap();
+        assert_eq!(monitor.name, "mymaster");
+        assert_eq!(monitor.host, "10.0.0.1");
+        assert_eq!(monitor.port, 6380);
+        assert_eq!(monitor.quorum, 2);
+
+        assert!(Monitor::parse("mymaster 10.0.0.1 6380").is_none());
+        assert!(Monitor::parse("mymaster 10.0.0.1 6380 2 extra").is_none());
+        assert!(Monitor::parse("mymaster 10.0.0.1 port 2").is_none());
+        assert!(Monitor::parse("mymaster 10.0.0.1 70000 2").is_none());
+        assert!(Monitor::parse("mymaster 10.0.0.1 6380 0").is_none());
+        assert!(Monitor::parse("mymaster 10.0.0.1 6380 -1").is_none());
+    }
+
+    #[test]
+    fn learns_peers_and_epochs_from_hello_messages() {
+        let mut sentinel = sentinel();
+        sentinel.process_hello(&hello("peer1", 3, "127.0.0.1,6379", 0));
+        assert_eq!(
+            sentinel.peers["peer1"].addr,
+            ("127.0.0.1".to_string(), 26380)
+        );
+        assert_eq!(sentinel.current_epoch, 3);
+
+        // An older epoch does not go back
+        sentinel.process_hello(&hello("peer1", 1, "127.0.0.1,6379", 0));
+        assert_eq!(sentinel.current_epoch, 3);
+
+        // A new run id on the same address replaces the old peer
+        sentinel.process_hello(&hello("peer2", 3, "127.0.0.1,6379", 0));
+        assert_eq!(sentinel.peers.len(), 1);
+        assert!(sentinel.peers.contains_key("peer2"));
+
+        // Our own hello, another master's and malformed ones are ignored
+        let myid = sentinel.myid.clone();
+        sentinel.process_hello(&hello(&myid, 9, "127.0.0.1,6379", 0));
+        sentinel.process_hello("127.0.0.1,26381,peer3,9,other,127.0.0.1,6379,0");
+        sentinel.process_hello("127.0.0.1,26381,peer3,9,mymaster,127.0.0.1");
+        sentinel.process_hello("127.0.0.1,port,peer3,9,mymaster,127.0.0.1,6379,0");
+        assert_eq!(sentinel.peers.len(), 1);
+        assert_eq!(sentinel.current_epoch, 3);
+    }
+
+    #[test]
+    fn follows_a_newer_configuration_from_a_hello() {
+        let mut sentinel = sentinel();
+        sentinel.process_hello(&hello("peer1", 4, "127.0.0.1,6380", 4));
+        assert_eq!(sentinel.master.addr, ("127.0.0.1".to_string(), 6380));
+        assert_eq!(sentinel.config_epoch, 4);
+        assert!(sentinel
+            .replicas
+            .contains_key(&("127.0.0.1".to_string(), 6379)));
+
+        // Not one from an older configuration epoch
+        sentinel.process_hello(&hello("peer1", 4, "127.0.0.1,6379", 3));
+        assert_eq!(sentinel.master.addr, ("127.0.0.1".to_string(), 6380));
+    }
+
+    #[test]
+    fn votes_once_per_epoch_and_never_in_an_older_one() {
+        let mut sentinel = sentinel();
+        let master = ("127.0.0.1".to_string(), 6379);
+        let reply = |leader: &str, epoch: u64| {
+            format!(
+                "*3\r\n:0\r\n${}\r\n{}\r\n:{}\r\n",
+                leader.len(),
+                leader,
+                epoch
+            )
+        };
+
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master.clone(), 0, "*"),
+            reply("*", 0)
+        );
+        assert_eq!(sentinel.vote, None);
+
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master.clone(), 1, "peer1"),
+            reply("peer1", 1)
+        );
+        assert_eq!(sentinel.current_epoch, 1);
+        // A second candidate in the same epoch learns who got the vote
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master.clone(), 1, "peer2"),
+            reply("peer1", 1)
+        );
+
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master.clone(), 2, "peer2"),
+            reply("peer2", 2)
+        );
+        // An epoch that is already over gets no vote
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master.clone(), 1, "peer3"),
+            reply("peer2", 2)
+        );
+        sentinel.current_epoch = 5;
+        assert_eq!(
+            sentinel.is_master_down_by_addr(master, 3, "peer3"),
+            reply("peer2", 2)
+        );
+        assert_eq!(sentinel.vote, Some((2, "peer2".to_string())));
+    }
+}
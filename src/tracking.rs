@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+// Options given to CLIENT TRACKING ON
+#[derive(Clone, Default)]
+pub struct TrackingOptions {
+    // Client that receives the invalidation messages instead of the tracking one
+    pub redirect: Option<u64>,
+    // Broadcast mode: every change to a key matching one of the prefixes is reported,
+    // whether or not the client read it
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    // Track only the reads right after CLIENT CACHING YES
+    pub optin: bool,
+    // Track every read except the ones right after CLIENT CACHING NO
+    pub optout: bool,
+    // Skip invalidations for keys the client modified itself
+    pub noloop: bool,
+}
+
+// Server side of client-side caching: remembers which clients may hold a key in their local
+// cache, and works out who has to be told when that key changes
+pub struct Tracking {
+    clients: HashMap<u64, TrackingOptions>,
+    // Default mode: key -> clients that read it since its last invalidation
+    table: HashMap<String, HashSet<u64>>,
+    // Broadcast mode: prefix -> clients
+    prefixes: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    pub fn new() -> Self {
+        Self {
+            clients: HashMap::new(),
+            table: HashMap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+
+    pub fn options(&self, client_id: u64) -> Option<&TrackingOptions> {
+        self.clients.get(&client_id)
+    }
+
+    pub fn enable(&mut self, client_id: u64, options: TrackingOptions) -> Result<(), String> {
+        // Prefixes of one client must not overlap, otherwise a key would be reported twice
+        let mut prefixes = options.prefixes.clone();
+        if let Some(current) = self.clients.get(&client_id) {
+            prefixes.extend(current.prefixes.iter().cloned());
+        }
+        for (i, prefix) in prefixes.iter().enumerate() {
+            for other in &prefixes[i + 1..] {
+                if prefix != other && (prefix.starts_with(other) || other.starts_with(prefix)) {
+                    return Err(format!(
+                        "ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.",
+                        prefix, other
+                    ));
+                }
+            }
+        }
+
+        if options.bcast {
+            // BCAST without PREFIX tracks every key
+            let prefixes = if options.prefixes.is_empty() {
+                vec![String::new()]
+            } else {
+                options.prefixes.clone()
+            };
+            for prefix in prefixes {
+                self.prefixes.entry(prefix).or_default().insert(client_id);
+            }
+        }
+
+        let mut options = options;
+        if let Some(current) = self.clients.remove(&client_id) {
+            options.prefixes.extend(current.prefixes);
+        }
+        options.prefixes.sort();
+        options.prefixes.dedup();
+        self.clients.insert(client_id, options);
+        Ok(())
+    }
+
+    pub fn disable(&mut self, client_id: u64) {
+        if self.clients.remove(&client_id).is_none() {
+            return;
+        }
+        // Entries in the key table are dropped lazily when the key is invalidated
+        self.prefixes.retain(|_, clients| {
+            clients.remove(&client_id);
+            !clients.is_empty()
+        });
+    }
+
+    // Called after a read-only command ran for a tracking client in default mode
+    pub fn remember_keys(&mut self, client_id: u64, keys: &[String]) {
+        for key in keys {
+            self.table.entry(key.clone()).or_default().insert(client_id);
+        }
+    }
+
+    // Clients to notify now that the key changed, the caller excluded when it uses NOLOOP.
+    // Default mode entries are one-shot: the client has to read the key again to keep
+    // being told about it.
+    pub fn invalidate_key(&mut self, key: &str, caller: Option<u64>) -> Vec<u64> {
+        let mut targets = vec![];
+
+        if let Some(clients) = self.table.remove(key) {
+            targets.extend(
+                clients
+                    .into_iter()
+                    .filter(|id| self.clients.contains_key(id)),
+            );
+        }
+        for (prefix, clients) in &self.prefixes {
+            if key.starts_with(prefix.as_str()) {
+                targets.extend(clients.iter().copied());
+            }
+        }
+
+        targets.retain(|id| {
+            Some(*id) != caller || !self.clients.get(id).is_some_and(|options| options.noloop)
+        });
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    // FLUSHDB / FLUSHALL: every tracking client drops its whole cache
+    pub fn invalidate_all(&mut self) -> Vec<u64> {
+        self.table.clear();
+        self.clients.keys().copied().collect()
+    }
+
+    // Where invalidations meant for this client are delivered
+    pub fn recipient(&self, client_id: u64) -> u64 {
+        self.clients
+            .get(&client_id)
+            .and_then(|options| options.redirect)
+            .unwrap_or(client_id)
+    }
+}